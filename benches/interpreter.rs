@@ -0,0 +1,137 @@
+#![allow(clippy::unusual_byte_groupings)]
+
+/*!
+Measures interpreter throughput in instructions per second: `cargo bench`.
+
+Each program runs several times on a fresh machine with each engine and the fastest run is reported, with its
+speedup over the interpreter. The engines take turns, so a machine that gets slower or faster during the bench
+affects them alike. The bundled games get a scripted key sequence and stop when it runs out or after a fixed number
+of instructions.
+*/
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use rust_vm::hardware::console::{Display, Keyboard};
+use rust_vm::hardware::vm::engine::Engine;
+use rust_vm::hardware::vm::limits::Limits;
+use rust_vm::hardware::vm::VirtualMachine;
+
+const RUNS: usize = 10;
+const GAME_INSTRUCTIONS: u64 = 50_000_000;
+
+/**
+A nested counting loop: 200 × 32767 iterations of ADD, STR, LDR, ADD and BRp.
+
+```text
+        LD   R4, OUTER
+        LEA  R3, CELL
+AGAIN   AND  R0, R0, #0
+        LD   R1, INNER
+LOOP    ADD  R0, R0, R1
+        STR  R0, R3, #0
+        LDR  R2, R3, #0
+        ADD  R1, R1, #-1
+        BRp  LOOP
+        ADD  R4, R4, #-1
+        BRp  AGAIN
+        HALT
+INNER   .FILL x7FFF
+OUTER   .FILL #200
+CELL    .BLKW 1
+```
+*/
+const LOOP: &[u16] = &[
+    0b0010_100_000001100,
+    0b1110_011_000001100,
+    0b0101_000_000_1_00000,
+    0b0010_001_000001000,
+    0b0001_000_000_0_00_001,
+    0b0111_000_011_000000,
+    0b0110_010_011_000000,
+    0b0001_001_001_1_11111,
+    0b0000_001_111111011,
+    0b0001_100_100_1_11111,
+    0b0000_001_111110111,
+    0xF025,
+    0x7FFF,
+    200,
+    0,
+];
+
+fn machine() -> VirtualMachine {
+    let mut vm = VirtualMachine::create();
+    vm.memory.display = Display::to_writer(Box::new(io::sink()));
+    vm
+}
+
+fn counting_loop() -> VirtualMachine {
+    let mut vm = machine();
+    for (address, &word) in (0x3000..).zip(LOOP) {
+        vm.memory.write(address, word);
+    }
+    vm
+}
+
+/**
+A bundled game, fed `answer` to its first prompt and then `keys` over and over.
+*/
+fn game(
+    path: &'static str,
+    answer: &'static [u8],
+    keys: &'static [u8],
+) -> impl Fn() -> VirtualMachine {
+    move || {
+        let mut vm = machine();
+        vm.load_program(path).expect("bundled program loads");
+        vm.memory.keyboard = Keyboard::from_bytes(&[answer, &keys.repeat(500)].concat());
+        vm.set_limits(Limits {
+            instructions: Some(GAME_INSTRUCTIONS),
+            ..Limits::unlimited()
+        });
+        vm
+    }
+}
+
+/**
+Runs the program `RUNS` times with every engine in `Engine::ALL`, in turns, and returns the executed instruction
+count and the fastest time of each engine.
+*/
+fn measure(setup: &impl Fn() -> VirtualMachine) -> (u64, [Duration; Engine::ALL.len()]) {
+    let mut fastest = [Duration::MAX; Engine::ALL.len()];
+    let mut steps = 0;
+    for _ in 0..RUNS {
+        for (engine, fastest) in Engine::ALL.into_iter().zip(&mut fastest) {
+            let mut vm = setup();
+            vm.set_engine(engine);
+            let started = Instant::now();
+            vm.execute_program();
+            *fastest = (*fastest).min(started.elapsed());
+            steps = vm.steps;
+        }
+    }
+    (steps, fastest)
+}
+
+fn report(name: &str, setup: impl Fn() -> VirtualMachine) {
+    let (steps, times) = measure(&setup);
+    // Engine::ALL starts with the interpreter
+    let interpreter = times[0];
+    for (engine, time) in Engine::ALL.into_iter().zip(times) {
+        println!(
+            "{:<14} {:<12} {:>11} instructions in {:>8.2?}  {:>8.1} MIPS  {:>5.2}x",
+            name,
+            engine,
+            steps,
+            time,
+            steps as f64 / time.as_secs_f64() / 1e6,
+            interpreter.as_secs_f64() / time.as_secs_f64()
+        );
+    }
+}
+
+fn main() {
+    report("counting loop", counting_loop);
+    report("2048.obj", game("2048.obj", b"n", b"wasd"));
+    report("rogue.obj", game("rogue.obj", b" ", b"wasd"));
+}
@@ -0,0 +1,72 @@
+/*!
+Decodes every word of an arbitrary raw image at its address and executes it, next to a second machine that executes
+the same word through the opcode handlers. Both machines must end up with the same registers and memory writes and
+stop for the same reasons.
+*/
+#![no_main]
+
+use std::io;
+
+use libfuzzer_sys::fuzz_target;
+use rust_vm::hardware::console::{Display, Keyboard};
+use rust_vm::hardware::instructions::decoded::Decoded;
+use rust_vm::hardware::instructions::execute_instruction;
+use rust_vm::hardware::memory::Memory;
+use rust_vm::hardware::registers::Registers;
+
+/**
+A machine with the image in memory, wrapping around at the end of memory, for the instructions to work on.
+*/
+fn machine(origin: u16, words: &[u16]) -> (Registers, Memory) {
+    let mut memory = Memory::empty();
+    memory.keyboard = Keyboard::from_bytes(b"y");
+    memory.display = Display::to_writer(Box::new(io::sink()));
+    for (offset, &word) in words.iter().enumerate() {
+        memory.write(origin.wrapping_add(offset as u16), word);
+    }
+    (Registers::initial(), memory)
+}
+
+fuzz_target!(|bytes: &[u8]| {
+    let words: Vec<u16> = bytes
+        .chunks_exact(2)
+        .take(1 << 16)
+        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+        .collect();
+    let Some((&origin, words)) = words.split_first() else {
+        return;
+    };
+    let (mut decoded_registers, mut decoded_memory) = machine(origin, words);
+    let (mut registers, mut memory) = machine(origin, words);
+
+    for (offset, &instruction) in words.iter().enumerate().take(4096) {
+        let address = origin.wrapping_add(offset as u16);
+        let next = address.wrapping_add(1);
+        decoded_registers.update_program_counter(next);
+        registers.update_program_counter(next);
+
+        decoded_memory.start_journal();
+        let decoded_stop = Decoded::decode(instruction, address)
+            .execute(&mut decoded_registers, &mut decoded_memory);
+        let decoded_accesses = decoded_memory.take_journal();
+        memory.start_journal();
+        let stop = execute_instruction(instruction, &mut registers, &mut memory);
+        let accesses = memory.take_journal();
+
+        assert_eq!(
+            decoded_stop, stop,
+            "x{:04X} at x{:04X}",
+            instruction, address
+        );
+        assert_eq!(
+            decoded_registers, registers,
+            "x{:04X} at x{:04X}",
+            instruction, address
+        );
+        assert_eq!(
+            decoded_accesses, accesses,
+            "x{:04X} at x{:04X}",
+            instruction, address
+        );
+    }
+});
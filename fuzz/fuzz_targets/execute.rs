@@ -0,0 +1,59 @@
+/*!
+Runs arbitrary raw images under an instruction and output budget, in lockstep on the interpreter and each other
+engine. Every run must end in a stop reason the budget allows, with both engines agreeing after every instruction.
+*/
+#![no_main]
+
+use std::io;
+
+use libfuzzer_sys::fuzz_target;
+use rust_vm::hardware::console::{Display, Keyboard};
+use rust_vm::hardware::vm::engine::Engine;
+use rust_vm::hardware::vm::limits::Limits;
+use rust_vm::hardware::vm::lockstep::{Lockstep, LockstepOutcome};
+use rust_vm::hardware::vm::{StopReason, VirtualMachine};
+
+const INSTRUCTIONS: u64 = 20_000;
+const OUTPUT_BYTES: u64 = 4096;
+
+fn machine(engine: Engine, bytes: &[u8]) -> Option<VirtualMachine> {
+    let mut vm = VirtualMachine::create();
+    vm.set_engine(engine);
+    vm.memory.keyboard = Keyboard::from_bytes(b"nwasd\nwasd\n");
+    vm.memory.display = Display::to_writer(Box::new(io::sink()));
+    vm.load_bytes("image.obj", bytes).ok()?;
+    Some(vm)
+}
+
+fuzz_target!(|bytes: &[u8]| {
+    let limits = Limits {
+        instructions: Some(INSTRUCTIONS),
+        output_bytes: Some(OUTPUT_BYTES),
+        ..Limits::unlimited()
+    };
+    for engine in [Engine::Cached, Engine::Compiled] {
+        let (Some(left), Some(right)) =
+            (machine(Engine::Interpreter, bytes), machine(engine, bytes))
+        else {
+            return;
+        };
+        let mut lockstep = Lockstep::new(left, right);
+
+        let stop = match lockstep.run(limits) {
+            LockstepOutcome::Agreed(stop) => stop,
+            LockstepOutcome::Diverged(divergence) => panic!("{}", divergence),
+        };
+        match stop {
+            StopReason::Halted | StopReason::EndOfInput | StopReason::Fault(_) => {}
+            StopReason::InstructionLimit(limit) => assert_eq!(limit, INSTRUCTIONS),
+            StopReason::OutputLimit(limit) => assert_eq!(limit, OUTPUT_BYTES),
+            StopReason::TimeLimit(_)
+            | StopReason::InputLimit(_)
+            | StopReason::ReplayDiverged { .. } => {
+                panic!("unexpected stop: {}", stop)
+            }
+        }
+        assert!(lockstep.left.steps <= INSTRUCTIONS);
+        assert!(lockstep.left.memory.display.written <= OUTPUT_BYTES);
+    }
+});
@@ -0,0 +1,46 @@
+/*!
+Loads arbitrary bytes as an image in every format. The loader either rejects them and leaves memory untouched, or
+places every section below the device page and nothing else.
+*/
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_vm::hardware::vm::loader::{LoadedImage, DEVICE_PAGE};
+use rust_vm::hardware::vm::VirtualMachine;
+
+fuzz_target!(|bytes: &[u8]| {
+    // the extension picks the format unless the bytes carry the lc3tools header
+    for name in ["image.obj", "image.hex", "image.bin", "image"] {
+        let mut vm = VirtualMachine::create();
+        match vm.load_bytes(name, bytes) {
+            Ok(images) => {
+                let mut loaded = vec![false; 1 << 16];
+                for image in &images {
+                    // an empty section may sit anywhere, it loads nothing
+                    assert!(
+                        image.length == 0
+                            || image.origin as usize + image.length <= DEVICE_PAGE as usize
+                    );
+                    for address in image.origin as usize..image.origin as usize + image.length {
+                        assert!(!loaded[address], "sections overlap at x{:04X}", address);
+                        loaded[address] = true;
+                    }
+                }
+                for (address, &word) in vm.memory.contents().iter().enumerate() {
+                    assert!(
+                        word == 0 || loaded[address],
+                        "stray write to x{:04X}",
+                        address
+                    );
+                }
+                let loaded: Vec<LoadedImage> =
+                    vm.programs.iter().map(|&(_, image)| image).collect();
+                assert_eq!(loaded, images);
+            }
+            Err(_) => {
+                assert!(vm.memory.contents().iter().all(|&word| word == 0));
+                assert!(vm.programs.is_empty());
+            }
+        }
+    }
+});
@@ -0,0 +1,540 @@
+use std::collections::BTreeSet;
+use std::io::{self, Write};
+
+use crate::hardware::memory::MemoryAccess;
+use crate::hardware::vm::callstack::CallStack;
+use crate::hardware::vm::dump::{CrashDump, DumpInfo};
+use crate::hardware::vm::symbols::SymbolTable;
+use crate::hardware::vm::{StopReason, VirtualMachine};
+
+const HELP: &str = "\
+commands:
+  s, step [n]              execute n instructions (default 1)
+  rs, reverse-step [n]     undo n instructions (default 1)
+  c, continue              run until a breakpoint or watchpoint is hit
+  rc, reverse-continue     run backwards to the previous breakpoint or watchpoint hit
+  goto <step>              move to a recorded step number, backwards or forwards
+  b, break <addr>          set a breakpoint
+  w, watch <addr>          stop whenever <addr> is written
+  d, delete <addr>         remove a breakpoint or watchpoint
+  r, regs                  print the registers
+  x <addr> [count]         print memory words without polling devices
+  bt [fp]                  print the call stack, with 'fp' also the frames found by following R5
+  profile                  start profiling, or print the profile so far
+  save <file>              write a snapshot of the machine
+  restore <file>           load a snapshot written by 'save'
+  q, quit                  exit
+<addr> is x3000, 0x3000, decimal or a label from the program's .sym file
+crash dumps (read-only, nothing can be executed):
+  info                     print why the program stopped and the PSR
+  trace [n]                print the last n executed instructions (default all)";
+
+/// Commands that change the machine state, refused when inspecting a crash dump.
+const EXECUTING_COMMANDS: &[&str] = &[
+    "s",
+    "step",
+    "rs",
+    "reverse-step",
+    "c",
+    "continue",
+    "rc",
+    "reverse-continue",
+    "goto",
+    "profile",
+    "restore",
+];
+
+/**
+Why the debugger handed control back to the user.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    Stepped,
+    Breakpoint(u16),
+    Watchpoint(u16),
+    HistoryExhausted,
+    Stopped(StopReason),
+}
+
+/**
+An interactive debugger with breakpoints, watchpoints and reverse execution.
+*/
+pub struct Debugger {
+    pub vm: VirtualMachine,
+    breakpoints: BTreeSet<u16>,
+    watchpoints: BTreeSet<u16>,
+    dump: Option<DumpInfo>,
+}
+
+impl Debugger {
+    pub fn new(vm: VirtualMachine) -> Debugger {
+        Debugger {
+            vm,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeSet::new(),
+            dump: None,
+        }
+    }
+
+    /**
+    Opens a crash dump read-only, with the symbols of the programs that crashed when their `.sym` files are still around.
+    */
+    pub fn inspect(dump: CrashDump) -> Debugger {
+        let mut vm = VirtualMachine::create();
+        vm.restore_snapshot(&dump.snapshot);
+        vm.call_stack = CallStack::from_frames(dump.info.call_stack.clone());
+        for program in &dump.info.programs {
+            if let Ok(Some(symbols)) = SymbolTable::for_program(program) {
+                vm.symbols.extend(symbols);
+            }
+        }
+
+        let mut debugger = Debugger::new(vm);
+        debugger.dump = Some(dump.info);
+        debugger
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn add_watchpoint(&mut self, address: u16) {
+        self.watchpoints.insert(address);
+    }
+
+    pub fn remove(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address) | self.watchpoints.remove(&address)
+    }
+
+    /**
+    Executes one instruction, reporting a watchpoint if it wrote to a watched address.
+    */
+    pub fn step(&mut self) -> Stop {
+        let step = self.vm.step_recorded();
+        if let Some(reason) = step.stop {
+            return Stop::Stopped(reason);
+        }
+
+        for access in &step.accesses {
+            if let MemoryAccess::Write { address, .. } = *access {
+                if self.watchpoints.contains(&address) {
+                    return Stop::Watchpoint(address);
+                }
+            }
+        }
+        Stop::Stepped
+    }
+
+    pub fn continue_forward(&mut self) -> Stop {
+        loop {
+            let stop = self.step();
+            if stop != Stop::Stepped {
+                return stop;
+            }
+            let pc = self.vm.registers.read_program_counter();
+            if self.breakpoints.contains(&pc) {
+                return Stop::Breakpoint(pc);
+            }
+        }
+    }
+
+    /**
+    Undoes one instruction. A watchpoint is reported when the undone instruction wrote to a watched
+    address, which leaves the machine just before that write.
+    */
+    pub fn reverse_step(&mut self) -> Stop {
+        match self.vm.step_back() {
+            Some(entry) => {
+                match self
+                    .watchpoints
+                    .iter()
+                    .find(|&&address| entry.writes(address))
+                {
+                    Some(&address) => Stop::Watchpoint(address),
+                    None => Stop::Stepped,
+                }
+            }
+            None => Stop::HistoryExhausted,
+        }
+    }
+
+    pub fn reverse_continue(&mut self) -> Stop {
+        loop {
+            let stop = self.reverse_step();
+            if stop != Stop::Stepped {
+                return stop;
+            }
+            let pc = self.vm.registers.read_program_counter();
+            if self.breakpoints.contains(&pc) {
+                return Stop::Breakpoint(pc);
+            }
+        }
+    }
+
+    /**
+    Moves to the given step number: backwards through the recorded history, or forwards by executing.
+    */
+    pub fn goto(&mut self, step: u64) -> Result<(), String> {
+        if step < self.vm.steps {
+            let earliest = self.vm.history().and_then(|history| history.earliest());
+            match earliest {
+                Some(earliest) if earliest <= step => {}
+                _ => return Err(format!("step {} is no longer recorded", step)),
+            }
+            while self.vm.steps > step {
+                self.vm.step_back();
+            }
+        }
+        while self.vm.steps < step {
+            if let Some(reason) = self.vm.step_recorded().stop {
+                return Err(format!("program {} at step {}", reason, self.vm.steps));
+            }
+        }
+        Ok(())
+    }
+
+    /**
+    Reads commands from `input` until it is exhausted or the user quits.
+    */
+    pub fn run<I, W>(&mut self, input: I, output: &mut W) -> io::Result<()>
+    where
+        I: IntoIterator<Item = io::Result<String>>,
+        W: Write,
+    {
+        if let Some(dump) = &self.dump {
+            writeln!(output, "post-mortem: program {}", dump.reason)?;
+            write!(output, "{}", self.vm.backtrace(dump.address, false))?;
+        }
+        self.print_location(output)?;
+        write!(output, "(lc3) ")?;
+        output.flush()?;
+
+        for line in input {
+            let running = self.execute_command(&line?, output)?;
+            // show what the program printed before the prompt
+            self.vm.memory.display.flush();
+            if !running {
+                break;
+            }
+            write!(output, "(lc3) ")?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    /**
+    Runs a single debugger command. Returns `false` when the debugger should exit.
+    */
+    pub fn execute_command<W: Write>(&mut self, line: &str, output: &mut W) -> io::Result<bool> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, arguments)) = words.split_first() else {
+            return Ok(true);
+        };
+
+        if self.dump.is_some() && EXECUTING_COMMANDS.contains(&command) {
+            writeln!(
+                output,
+                "a crash dump is read-only, '{}' is not available",
+                command
+            )?;
+            return Ok(true);
+        }
+
+        match command {
+            "s" | "step" => {
+                let Some(count) = parse_count(arguments.first()) else {
+                    writeln!(output, "expected a count")?;
+                    return Ok(true);
+                };
+                let mut stop = Stop::Stepped;
+                for _ in 0..count {
+                    stop = self.step();
+                    if stop != Stop::Stepped {
+                        break;
+                    }
+                }
+                self.report(stop, output)?;
+            }
+            "rs" | "reverse-step" => {
+                let Some(count) = parse_count(arguments.first()) else {
+                    writeln!(output, "expected a count")?;
+                    return Ok(true);
+                };
+                let mut stop = Stop::Stepped;
+                for _ in 0..count {
+                    stop = self.reverse_step();
+                    if stop != Stop::Stepped {
+                        break;
+                    }
+                }
+                self.report(stop, output)?;
+            }
+            "c" | "continue" => {
+                let stop = self.continue_forward();
+                self.report(stop, output)?;
+            }
+            "rc" | "reverse-continue" => {
+                let stop = self.reverse_continue();
+                self.report(stop, output)?;
+            }
+            "goto" => match arguments.first().and_then(|step| step.parse().ok()) {
+                Some(step) => match self.goto(step) {
+                    Ok(()) => self.print_location(output)?,
+                    Err(message) => writeln!(output, "{}", message)?,
+                },
+                None => writeln!(output, "usage: goto <step>")?,
+            },
+            "b" | "break" => match arguments.first().and_then(|a| self.vm.symbols.resolve(a)) {
+                Some(address) => {
+                    self.add_breakpoint(address);
+                    writeln!(output, "breakpoint at x{:04X}", address)?;
+                }
+                None => writeln!(output, "usage: break <addr>")?,
+            },
+            "w" | "watch" => match arguments.first().and_then(|a| self.vm.symbols.resolve(a)) {
+                Some(address) => {
+                    self.add_watchpoint(address);
+                    writeln!(output, "watchpoint at x{:04X}", address)?;
+                }
+                None => writeln!(output, "usage: watch <addr>")?,
+            },
+            "d" | "delete" => match arguments.first().and_then(|a| self.vm.symbols.resolve(a)) {
+                Some(address) if self.remove(address) => {
+                    writeln!(output, "removed x{:04X}", address)?
+                }
+                Some(address) => writeln!(output, "nothing set at x{:04X}", address)?,
+                None => writeln!(output, "usage: delete <addr>")?,
+            },
+            "r" | "regs" => self.print_registers(output)?,
+            "x" => match arguments.first().and_then(|a| self.vm.symbols.resolve(a)) {
+                Some(address) => {
+                    let Some(count) = parse_count(arguments.get(1)) else {
+                        writeln!(output, "expected a count")?;
+                        return Ok(true);
+                    };
+                    for offset in 0..count {
+                        let address = address.wrapping_add(offset as u16);
+                        let value = self.vm.peek_memory(address);
+                        writeln!(output, "x{:04X}: x{:04X}", address, value)?;
+                    }
+                }
+                None => writeln!(output, "usage: x <addr> [count]")?,
+            },
+            "bt" | "backtrace" => {
+                let frame_pointers = arguments.first() == Some(&"fp");
+                let pc = match &self.dump {
+                    Some(dump) => dump.address,
+                    None => self.vm.registers.read_program_counter(),
+                };
+                write!(output, "{}", self.vm.backtrace(pc, frame_pointers))?;
+            }
+            "profile" => match self.vm.profile_report() {
+                Some(report) => write!(output, "{}", report)?,
+                None => {
+                    self.vm.enable_profiler();
+                    writeln!(output, "profiling from step {}", self.vm.steps)?;
+                }
+            },
+            "save" => match arguments.first() {
+                Some(path) => match self.vm.save_snapshot(path) {
+                    Ok(()) => writeln!(output, "saved {}", path)?,
+                    Err(error) => writeln!(output, "failed to save {}: {}", path, error)?,
+                },
+                None => writeln!(output, "usage: save <file>")?,
+            },
+            "restore" => match arguments.first() {
+                Some(path) => match self.vm.load_snapshot(path) {
+                    Ok(()) => self.print_location(output)?,
+                    Err(error) => writeln!(output, "failed to restore {}: {}", path, error)?,
+                },
+                None => writeln!(output, "usage: restore <file>")?,
+            },
+            "info" => match &self.dump {
+                Some(dump) => writeln!(output, "program {}\nPSR: x{:04X}", dump.reason, dump.psr)?,
+                None => writeln!(output, "not inspecting a crash dump")?,
+            },
+            "trace" => match &self.dump {
+                Some(dump) => {
+                    let count = match arguments.first().map(|count| count.parse()) {
+                        Some(Ok(count)) => count,
+                        Some(Err(_)) => {
+                            writeln!(output, "usage: trace [n]")?;
+                            return Ok(true);
+                        }
+                        None => dump.trace.len(),
+                    };
+                    let skip = dump.trace.len().saturating_sub(count);
+                    for record in &dump.trace[skip..] {
+                        let operands: Vec<String> = record
+                            .operands
+                            .iter()
+                            .map(|(name, value)| format!("{}={}", name, value))
+                            .collect();
+                        writeln!(
+                            output,
+                            "[step {}] x{:04X} {:<5} {}",
+                            record.step,
+                            record.pc,
+                            record.opcode,
+                            operands.join(" ")
+                        )?;
+                    }
+                }
+                None => writeln!(output, "not inspecting a crash dump")?,
+            },
+            "h" | "help" => writeln!(output, "{}", HELP)?,
+            "q" | "quit" => return Ok(false),
+            _ => writeln!(output, "unknown command '{}', try 'help'", command)?,
+        }
+        Ok(true)
+    }
+
+    fn report<W: Write>(&self, stop: Stop, output: &mut W) -> io::Result<()> {
+        match stop {
+            Stop::Stepped => {}
+            Stop::Breakpoint(address) => writeln!(output, "breakpoint x{:04X}", address)?,
+            Stop::Watchpoint(address) => writeln!(output, "watchpoint x{:04X}", address)?,
+            Stop::HistoryExhausted => writeln!(output, "no more recorded history")?,
+            Stop::Stopped(StopReason::Fault(fault)) => {
+                writeln!(output, "program {}", StopReason::Fault(fault))?;
+                write!(output, "{}", self.vm.backtrace(fault.address(), false))?;
+            }
+            Stop::Stopped(reason) => writeln!(output, "program {}", reason)?,
+        }
+        self.print_location(output)
+    }
+
+    fn print_location<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        let pc = self.vm.registers.read_program_counter();
+        writeln!(output, "[step {}] pc x{:04X}", self.vm.steps, pc)
+    }
+
+    fn print_registers<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        let registers = &self.vm.registers;
+        for index in 0..8 {
+            write!(output, "R{}: x{:04X}  ", index, registers.read(index))?;
+        }
+        writeln!(output)?;
+        writeln!(
+            output,
+            "PC: x{:04X}  COND: x{:04X}",
+            registers.read_program_counter(),
+            registers.read_cond()
+        )
+    }
+}
+
+/**
+Lines typed on stdin. Unlike `Stdin::lines` this only locks stdin while a line is being read,
+so the program being debugged can still read its own input from the keyboard.
+*/
+pub fn stdin_lines() -> impl Iterator<Item = io::Result<String>> {
+    std::iter::from_fn(|| {
+        let mut line = String::new();
+        match io::stdin().read_line(&mut line) {
+            Ok(0) => None,
+            Ok(_) => Some(Ok(line)),
+            Err(error) => Some(Err(error)),
+        }
+    })
+}
+
+fn parse_count(argument: Option<&&str>) -> Option<u64> {
+    match argument {
+        Some(text) => text.parse().ok(),
+        None => Some(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn debugger() -> Debugger {
+        let mut vm = VirtualMachine::create();
+        vm.enable_history(64);
+
+        // loop: LEA R1, #2 ; STR R1, R1, #0 ; BRnzp loop
+        vm.memory.write(0x3000, 0b1110_001_000000010);
+        vm.memory.write(0x3001, 0b0111_001_001_000000);
+        vm.memory.write(0x3002, 0b0000_111_111111101);
+
+        Debugger::new(vm)
+    }
+
+    #[test]
+    fn test_inspect_is_read_only() {
+        let mut vm = VirtualMachine::create();
+        vm.keep_recent_steps(8);
+        vm.memory.write(0x3000, 0b1110_001_000000010);
+        vm.memory.write(0x3001, 0xF0FF);
+        let reason = vm.execute_program();
+
+        let mut debugger = Debugger::inspect(vm.crash_dump(reason));
+        let mut output = Vec::new();
+        debugger.execute_command("step", &mut output).unwrap();
+        debugger.execute_command("info", &mut output).unwrap();
+        debugger.execute_command("trace 1", &mut output).unwrap();
+        debugger.execute_command("trace l", &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert_eq!(debugger.vm.steps, 2);
+        assert!(output.contains("'step' is not available"));
+        assert!(output.contains("unknown trap vector xFF at x3001"));
+        assert!(output.contains("PSR: x8001"));
+        assert!(output.contains("[step 1] x3001 TRAP  trapvect8=255\nusage: trace [n]\n"));
+        assert!(!output.contains("LEA"));
+    }
+
+    #[test]
+    fn test_reverse_continue_stops_at_previous_breakpoint() {
+        let mut debugger = debugger();
+        debugger.add_breakpoint(0x3002);
+
+        assert_eq!(debugger.continue_forward(), Stop::Breakpoint(0x3002));
+        assert_eq!(debugger.continue_forward(), Stop::Breakpoint(0x3002));
+        assert_eq!(debugger.vm.steps, 5);
+
+        assert_eq!(debugger.reverse_continue(), Stop::Breakpoint(0x3002));
+        assert_eq!(debugger.vm.steps, 2);
+
+        assert_eq!(debugger.reverse_continue(), Stop::HistoryExhausted);
+        assert_eq!(debugger.vm.steps, 0);
+    }
+
+    #[test]
+    fn test_watchpoints_and_goto() {
+        let mut debugger = debugger();
+        debugger.add_watchpoint(0x3003);
+
+        assert_eq!(debugger.continue_forward(), Stop::Watchpoint(0x3003));
+        assert_eq!(debugger.vm.steps, 2);
+
+        debugger.goto(6).unwrap();
+        assert_eq!(debugger.reverse_continue(), Stop::Watchpoint(0x3003));
+        assert_eq!(debugger.vm.steps, 4);
+        assert_eq!(debugger.vm.registers.read_program_counter(), 0x3001);
+
+        debugger.goto(1).unwrap();
+        assert_eq!(debugger.vm.registers.read_program_counter(), 0x3001);
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut debugger = debugger();
+        let mut output = Vec::new();
+
+        debugger
+            .run(
+                ["s 2", "rs", "x x3003", "q"].map(|line| Ok(String::from(line))),
+                &mut output,
+            )
+            .unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("[step 2] pc x3002"));
+        assert!(output.contains("[step 1] pc x3001"));
+        assert!(output.contains("x3003: x0000"));
+    }
+}
@@ -0,0 +1,319 @@
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufWriter, Read, Write};
+
+/**
+One byte of keyboard input and the instruction count at which the program consumed it.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    pub step: u64,
+    pub byte: u8,
+}
+
+/**
+Why the keyboard could not deliver a byte the program asked for.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFailure {
+    Exhausted,
+    LimitReached,
+    /// The program asked for input at `step`, but the replay log has the next byte at `recorded`.
+    Diverged {
+        step: u64,
+        recorded: u64,
+    },
+}
+
+/**
+The keyboard the program reads from, both through the memory mapped KBSR/KBDR registers and the GETC/IN traps.
+
+Bytes come from the replay log when one is set, otherwise from the `pending` queue and then from the source
+(stdin by default). Every consumed byte can be logged together with the current instruction count, so a run
+can later be replayed exactly.
+
+When no byte can be delivered, `failure` says why and the VM stops after the current instruction.
+*/
+pub struct Keyboard {
+    source: Box<dyn Read>,
+    pub pending: VecDeque<u8>,
+    pub step: u64,
+    pub reads: u64,
+    pub limit: Option<u64>,
+    pub failure: Option<InputFailure>,
+    recorder: Option<Box<dyn Write>>,
+    replay: Option<VecDeque<InputEvent>>,
+}
+
+impl Keyboard {
+    pub fn stdin() -> Keyboard {
+        Keyboard::from_reader(Box::new(io::stdin()))
+    }
+
+    pub fn from_reader(source: Box<dyn Read>) -> Keyboard {
+        Keyboard {
+            source,
+            pending: VecDeque::new(),
+            step: 0,
+            reads: 0,
+            limit: None,
+            failure: None,
+            recorder: None,
+            replay: None,
+        }
+    }
+
+    /**
+    A keyboard that only ever delivers `bytes`.
+    */
+    pub fn from_bytes(bytes: &[u8]) -> Keyboard {
+        let mut keyboard = Keyboard::from_reader(Box::new(io::empty()));
+        keyboard.pending.extend(bytes);
+        keyboard
+    }
+
+    /**
+    Logs every consumed byte to `log`, one `<step> <byte>` line each.
+    */
+    pub fn record(&mut self, log: Box<dyn Write>) {
+        self.recorder = Some(log);
+    }
+
+    /**
+    Delivers exactly the recorded bytes instead of reading the source.
+    Fails with `InputFailure::Diverged` if the program asks for a byte at a different instruction count than it
+    did in the recorded run.
+    */
+    pub fn replay(&mut self, events: Vec<InputEvent>) {
+        self.replay = Some(events.into());
+    }
+
+    /**
+    Returns the next input byte, blocking on the source if needed.
+    `None` once the input is exhausted, the read limit is reached or the replay diverges, with `failure` set
+    accordingly.
+    */
+    pub fn read_byte(&mut self) -> Option<u8> {
+        if self.limit.is_some_and(|limit| self.reads >= limit) {
+            self.failure = Some(InputFailure::LimitReached);
+            return None;
+        }
+
+        let byte = self.next_byte();
+        if byte.is_none() {
+            self.failure.get_or_insert(InputFailure::Exhausted);
+        }
+        byte
+    }
+
+    fn next_byte(&mut self) -> Option<u8> {
+        let byte = match &mut self.replay {
+            Some(replay) => {
+                let event = *replay.front()?;
+                if event.step != self.step {
+                    self.failure = Some(InputFailure::Diverged {
+                        step: self.step,
+                        recorded: event.step,
+                    });
+                    return None;
+                }
+                replay.pop_front();
+                event.byte
+            }
+            None => match self.pending.pop_front() {
+                Some(byte) => byte,
+                None => {
+                    let mut buffer = [0; 1];
+                    self.source.read_exact(&mut buffer).ok()?;
+                    buffer[0]
+                }
+            },
+        };
+
+        self.reads += 1;
+        if let Some(recorder) = &mut self.recorder {
+            writeln!(recorder, "{} {}", self.step, byte).expect("Error writing input log");
+        }
+        Some(byte)
+    }
+}
+
+/**
+The display the program writes to through the OUT, PUTS and PUTSP traps. Once `limit` bytes have been written,
+further output is dropped, `limit_reached` is set and the VM stops after the current instruction.
+
+Output is buffered. It is flushed when the program reads the keyboard, by PUTS, PUTSP and HALT, and when the VM
+stops.
+*/
+pub struct Display {
+    sink: BufWriter<Box<dyn Write>>,
+    unflushed: bool,
+    pub written: u64,
+    pub limit: Option<u64>,
+    pub limit_reached: bool,
+}
+
+impl Display {
+    pub fn stdout() -> Display {
+        Display::to_writer(Box::new(io::stdout()))
+    }
+
+    pub fn to_writer(sink: Box<dyn Write>) -> Display {
+        Display {
+            sink: BufWriter::new(sink),
+            unflushed: false,
+            written: 0,
+            limit: None,
+            limit_reached: false,
+        }
+    }
+
+    #[inline]
+    pub fn write_byte(&mut self, byte: u8) {
+        if self.limit.is_some_and(|limit| self.written >= limit) {
+            self.limit_reached = true;
+            return;
+        }
+        self.sink.write_all(&[byte]).expect("Error writing output");
+        self.unflushed = true;
+        self.written += 1;
+    }
+
+    /**
+    Writes out buffered output. Cheap when there is none, so it can be called before every keyboard read.
+    */
+    pub fn flush(&mut self) {
+        if self.unflushed {
+            self.sink.flush().expect("Error flushing output");
+            self.unflushed = false;
+        }
+    }
+}
+
+/**
+Parses an input log written by `Keyboard::record`.
+*/
+pub fn read_input_log<R: BufRead>(reader: R) -> io::Result<Vec<InputEvent>> {
+    let mut events = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let step = fields.next().and_then(|step| step.parse().ok());
+        let byte = fields.next().and_then(|byte| byte.parse().ok());
+        match (step, byte, fields.next()) {
+            (Some(step), Some(byte), None) => events.push(InputEvent { step, byte }),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid input log entry on line {}: {}", index + 1, line),
+                ))
+            }
+        }
+    }
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    struct SharedLog(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedLog {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+
+        let mut keyboard = Keyboard::from_bytes(b"ab");
+        keyboard.record(Box::new(SharedLog(log.clone())));
+        keyboard.step = 3;
+        assert_eq!(keyboard.read_byte(), Some(b'a'));
+        keyboard.step = 10;
+        assert_eq!(keyboard.read_byte(), Some(b'b'));
+        assert_eq!(keyboard.read_byte(), None);
+
+        let events = read_input_log(log.borrow().as_slice()).unwrap();
+        assert_eq!(
+            events,
+            vec![
+                InputEvent {
+                    step: 3,
+                    byte: b'a'
+                },
+                InputEvent {
+                    step: 10,
+                    byte: b'b'
+                }
+            ]
+        );
+
+        let mut replayed = Keyboard::from_bytes(b"ignored");
+        replayed.replay(events);
+        replayed.step = 3;
+        assert_eq!(replayed.read_byte(), Some(b'a'));
+        replayed.step = 10;
+        assert_eq!(replayed.read_byte(), Some(b'b'));
+        assert_eq!(replayed.read_byte(), None);
+        assert_eq!(replayed.failure, Some(InputFailure::Exhausted));
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut keyboard = Keyboard::from_bytes(b"abc");
+        keyboard.limit = Some(2);
+        assert_eq!(keyboard.read_byte(), Some(b'a'));
+        assert_eq!(keyboard.read_byte(), Some(b'b'));
+        assert_eq!(keyboard.read_byte(), None);
+        assert_eq!(keyboard.failure, Some(InputFailure::LimitReached));
+
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let mut display = Display::to_writer(Box::new(SharedLog(output.clone())));
+        display.limit = Some(2);
+        for &byte in b"abc" {
+            display.write_byte(byte);
+        }
+        assert!(display.limit_reached);
+        assert!(output.borrow().is_empty());
+        display.flush();
+        assert_eq!(output.borrow().as_slice(), b"ab");
+    }
+
+    #[test]
+    fn test_replay_detects_divergence() {
+        let mut keyboard = Keyboard::from_bytes(b"");
+        keyboard.replay(vec![InputEvent {
+            step: 5,
+            byte: b'a',
+        }]);
+        keyboard.step = 4;
+        assert_eq!(keyboard.read_byte(), None);
+        assert_eq!(
+            keyboard.failure,
+            Some(InputFailure::Diverged {
+                step: 4,
+                recorded: 5
+            })
+        );
+    }
+
+    #[test]
+    fn test_read_input_log_rejects_garbage() {
+        assert!(read_input_log("1 2 3".as_bytes()).is_err());
+        assert!(read_input_log("1 300".as_bytes()).is_err());
+    }
+}
@@ -1,5 +1,3 @@
-use std::process;
-
 use crate::hardware::registers::Registers;
 
 use super::{sign_extended, update_flags};
@@ -16,17 +14,17 @@ pub fn and(instruction: u16, registers: &mut Registers) {
     let source_1_value = registers.read(source_1);
 
     if immediate_flag == 1 {
-        let source_2: u16 = instruction & 0x7;
-
-        let source_2_value = registers.read(source_2);
-
-        registers.update(destination_register, source_1_value & source_2_value)
-    } else {
         let immediate_value_extended = sign_extended(instruction & 0x1F, 5);
         registers.update(
             destination_register,
             source_1_value & immediate_value_extended,
         )
+    } else {
+        let source_2: u16 = instruction & 0x7;
+
+        let source_2_value = registers.read(source_2);
+
+        registers.update(destination_register, source_1_value & source_2_value)
     }
 
     update_flags(destination_register, registers)
@@ -1,5 +1,3 @@
-use std::os::unix::process;
-
 use crate::hardware::registers::Registers;
 
 use super::sign_extended;
@@ -20,9 +18,9 @@ pub fn br(instruction: u16, registers: &mut Registers) {
     let pc_offset = sign_extended(instruction & 0x1FF, 9);
     let cond_flag = (instruction >> 9) & 0x7;
 
-    if cond_flag & registers.read_cond_flag() as u16 != 0 {
-        let value = (registers.read_program_counter() as u32) + (pc_offset as u32);
-        registers.update_program_counter(value as u16);
+    if cond_flag & registers.read_cond() != 0 {
+        let value = registers.read_program_counter().wrapping_add(pc_offset);
+        registers.update_program_counter(value);
     }
     //std::process::exit(0);
 }
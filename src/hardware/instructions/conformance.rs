@@ -0,0 +1,318 @@
+/*!
+Runs every opcode through `VirtualMachine::step` and checks the registers, condition codes and memory against the
+LC-3 ISA. Unlike the unit tests next to each handler, these also cover the dispatch from opcode to handler.
+Every case runs on each engine, which must agree with the interpreter.
+
+The VM follows the second edition of the ISA, where LEA sets the condition codes.
+*/
+
+use std::io;
+
+use crate::hardware::console::{Display, Keyboard};
+use crate::hardware::registers::{Registers, COND, PC};
+use crate::hardware::vm::engine::Engine;
+use crate::hardware::vm::{Fault, StopReason, VirtualMachine};
+
+const P: u16 = 1;
+const Z: u16 = 2;
+const N: u16 = 4;
+
+struct Outcome {
+    vm: VirtualMachine,
+    before: Registers,
+    memory: Vec<u16>,
+    stop: Option<StopReason>,
+}
+
+/**
+Executes `instruction` at x3000 on a machine prepared by `setup`, with every engine. Returns the interpreter's
+outcome after checking that the other engines ended up in the same state.
+*/
+fn execute(instruction: u16, setup: impl Fn(&mut VirtualMachine)) -> Outcome {
+    let mut outcomes = Engine::ALL.into_iter().map(|engine| {
+        let mut vm = VirtualMachine::create();
+        vm.set_engine(engine);
+        vm.memory.keyboard = Keyboard::from_bytes(b"k");
+        vm.memory.display = Display::to_writer(Box::new(io::sink()));
+        vm.memory.write(0x3000, instruction);
+        setup(&mut vm);
+
+        let before = vm.registers;
+        let memory = vm.memory.contents().to_vec();
+        let stop = vm.step();
+        Outcome {
+            vm,
+            before,
+            memory,
+            stop,
+        }
+    });
+
+    let interpreter = outcomes.next().expect("the interpreter is an engine");
+    for outcome in outcomes {
+        let engine = outcome.vm.engine();
+        assert_eq!(outcome.vm.registers, interpreter.vm.registers, "{}", engine);
+        assert_eq!(outcome.stop, interpreter.stop, "{}", engine);
+        assert!(
+            outcome.vm.memory.contents() == interpreter.vm.memory.contents(),
+            "{} wrote different memory",
+            engine
+        );
+    }
+    interpreter
+}
+
+impl Outcome {
+    /**
+    Every register, the PC and COND included, must be as before except for `changes`.
+    */
+    fn registers(&self, changes: &[(u16, u16)]) -> &Self {
+        let mut expected = self.before;
+        for &(register, value) in changes {
+            expected.update(register, value);
+        }
+        assert_eq!(self.vm.registers, expected);
+        self
+    }
+
+    /**
+    Every memory word must be as before except for `writes`.
+    */
+    fn memory(&self, writes: &[(u16, u16)]) -> &Self {
+        let mut expected = self.memory.clone();
+        for &(address, value) in writes {
+            expected[address as usize] = value;
+        }
+        let changed: Vec<(usize, u16)> = (0..expected.len())
+            .filter(|&address| self.vm.memory.contents()[address] != expected[address])
+            .map(|address| (address, self.vm.memory.contents()[address]))
+            .collect();
+        assert!(
+            changed.is_empty(),
+            "unexpected memory contents {:X?}",
+            changed
+        );
+        self
+    }
+
+    fn stop(&self, stop: Option<StopReason>) -> &Self {
+        assert_eq!(self.stop, stop);
+        self
+    }
+}
+
+#[test]
+fn test_br() {
+    // BRz #5 with Z set
+    execute(0b0000_010_000000101, |_| {})
+        .registers(&[(PC, 0x3006)])
+        .memory(&[]);
+    // BRnp #5 with Z set
+    execute(0b0000_101_000000101, |_| {}).registers(&[(PC, 0x3001)]);
+    // BRn #-1 with N set
+    execute(0b0000_100_111111111, |vm| vm.registers.update(COND, N)).registers(&[(PC, 0x3000)]);
+    // BR with no condition never branches
+    execute(0b0000_000_000000101, |_| {}).registers(&[(PC, 0x3001)]);
+}
+
+#[test]
+fn test_add() {
+    // ADD R0, R1, R2
+    execute(0b0001_000_001_0_00_010, |vm| {
+        vm.registers.update(1, 5);
+        vm.registers.update(2, (-7i16) as u16);
+    })
+    .registers(&[(0, (-2i16) as u16), (PC, 0x3001), (COND, N)])
+    .memory(&[]);
+    // ADD R3, R3, #-1
+    execute(0b0001_011_011_1_11111, |vm| vm.registers.update(3, 1)).registers(&[
+        (3, 0),
+        (PC, 0x3001),
+        (COND, Z),
+    ]);
+    // ADD R4, R4, #15 wraps around
+    execute(0b0001_100_100_1_01111, |vm| vm.registers.update(4, 0xFFFF)).registers(&[
+        (4, 14),
+        (PC, 0x3001),
+        (COND, P),
+    ]);
+}
+
+#[test]
+fn test_ld() {
+    // LD R2, #3
+    execute(0b0010_010_000000011, |vm| vm.memory.write(0x3004, 0x8000))
+        .registers(&[(2, 0x8000), (PC, 0x3001), (COND, N)])
+        .memory(&[]);
+}
+
+#[test]
+fn test_st() {
+    // ST R4, #-2
+    execute(0b0011_100_111111110, |vm| vm.registers.update(4, 0x1234))
+        .registers(&[(PC, 0x3001)])
+        .memory(&[(0x2FFF, 0x1234)]);
+}
+
+#[test]
+fn test_jsr() {
+    // JSR #16
+    execute(0b0100_1_00000010000, |_| {})
+        .registers(&[(7, 0x3001), (PC, 0x3011)])
+        .memory(&[]);
+    // JSRR R2
+    execute(0b0100_0_00_010_000000, |vm| vm.registers.update(2, 0x4000))
+        .registers(&[(7, 0x3001), (PC, 0x4000)]);
+    // JSRR R7 jumps to the old R7
+    execute(0b0100_0_00_111_000000, |vm| vm.registers.update(7, 0x4000))
+        .registers(&[(7, 0x3001), (PC, 0x4000)]);
+}
+
+#[test]
+fn test_and() {
+    // AND R0, R1, R2
+    execute(0b0101_000_001_0_00_010, |vm| {
+        vm.registers.update(1, 0b1100);
+        vm.registers.update(2, 0b1010);
+    })
+    .registers(&[(0, 0b1000), (PC, 0x3001), (COND, P)])
+    .memory(&[]);
+    // AND R1, R1, #-16
+    execute(0b0101_001_001_1_10000, |vm| vm.registers.update(1, 0x800F)).registers(&[
+        (1, 0x8000),
+        (PC, 0x3001),
+        (COND, N),
+    ]);
+    // AND R1, R1, #0
+    execute(0b0101_001_001_1_00000, |vm| vm.registers.update(1, 0x1234)).registers(&[
+        (1, 0),
+        (PC, 0x3001),
+        (COND, Z),
+    ]);
+}
+
+#[test]
+fn test_ldr() {
+    // LDR R3, R1, #-1
+    execute(0b0110_011_001_111111, |vm| {
+        vm.registers.update(1, 0x4000);
+        vm.memory.write(0x3FFF, 7);
+    })
+    .registers(&[(3, 7), (PC, 0x3001), (COND, P)])
+    .memory(&[]);
+}
+
+#[test]
+fn test_str() {
+    // STR R3, R1, #31
+    execute(0b0111_011_001_011111, |vm| {
+        vm.registers.update(1, 0x4000);
+        vm.registers.update(3, 0xABCD);
+    })
+    .registers(&[(PC, 0x3001)])
+    .memory(&[(0x401F, 0xABCD)]);
+}
+
+#[test]
+fn test_rti() {
+    execute(0x8000, |_| {})
+        .registers(&[(PC, 0x3001)])
+        .memory(&[])
+        .stop(Some(StopReason::Fault(Fault::IllegalOpcode {
+            address: 0x3000,
+            instruction: 0x8000,
+        })));
+}
+
+#[test]
+fn test_not() {
+    // NOT R0, R1
+    execute(0b1001_000_001_111111, |vm| vm.registers.update(1, 0x00FF))
+        .registers(&[(0, 0xFF00), (PC, 0x3001), (COND, N)])
+        .memory(&[]);
+    execute(0b1001_000_001_111111, |vm| vm.registers.update(1, 0xFFFF)).registers(&[
+        (0, 0),
+        (PC, 0x3001),
+        (COND, Z),
+    ]);
+}
+
+#[test]
+fn test_ldi() {
+    // LDI R6, #2
+    execute(0b1010_110_000000010, |vm| {
+        vm.registers.update(6, 0x1111);
+        vm.memory.write(0x3003, 0x5000);
+        vm.memory.write(0x5000, 0);
+    })
+    .registers(&[(6, 0), (PC, 0x3001), (COND, Z)])
+    .memory(&[]);
+}
+
+#[test]
+fn test_sti() {
+    // STI R0, #2
+    execute(0b1011_000_000000010, |vm| {
+        vm.registers.update(0, 0x0042);
+        vm.memory.write(0x3003, 0x5000);
+    })
+    .registers(&[(PC, 0x3001)])
+    .memory(&[(0x5000, 0x0042)]);
+}
+
+#[test]
+fn test_jmp() {
+    // JMP R3
+    execute(0b1100_000_011_000000, |vm| vm.registers.update(3, 0x1234))
+        .registers(&[(PC, 0x1234)])
+        .memory(&[]);
+    // RET
+    execute(0b1100_000_111_000000, |vm| vm.registers.update(7, 0x3050)).registers(&[(PC, 0x3050)]);
+}
+
+#[test]
+fn test_res() {
+    execute(0xD000, |_| {})
+        .registers(&[(PC, 0x3001)])
+        .memory(&[])
+        .stop(Some(StopReason::Fault(Fault::IllegalOpcode {
+            address: 0x3000,
+            instruction: 0xD000,
+        })));
+}
+
+#[test]
+fn test_lea() {
+    // LEA R5, #-16
+    execute(0b1110_101_111110000, |_| {})
+        .registers(&[(5, 0x2FF1), (PC, 0x3001), (COND, P)])
+        .memory(&[]);
+}
+
+#[test]
+fn test_trap() {
+    // GETC
+    execute(0xF020, |_| {})
+        .registers(&[(0, b'k' as u16), (7, 0x3001), (PC, 0x3001)])
+        .memory(&[])
+        .stop(None);
+
+    // OUT
+    let outcome = execute(0xF021, |vm| vm.registers.update(0, b'A' as u16));
+    outcome.registers(&[(7, 0x3001), (PC, 0x3001)]).stop(None);
+    assert_eq!(outcome.vm.memory.display.written, 1);
+
+    // HALT
+    execute(0xF025, |_| {})
+        .registers(&[(7, 0x3001), (PC, 0x3001)])
+        .memory(&[])
+        .stop(Some(StopReason::Halted));
+
+    // an unknown vector
+    execute(0xF0FF, |_| {})
+        .registers(&[(7, 0x3001), (PC, 0x3001)])
+        .stop(Some(StopReason::Fault(Fault::UnknownTrap {
+            address: 0x3000,
+            vector: 0xFF,
+        })));
+}
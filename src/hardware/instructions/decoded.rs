@@ -0,0 +1,339 @@
+use crate::hardware::memory::Memory;
+use crate::hardware::registers::Registers;
+use crate::hardware::vm::loader::DEVICE_PAGE;
+use crate::hardware::vm::StopReason;
+
+use super::{sign_extended, update_flags, Instructions, OPCODES};
+
+/**
+An instruction with its fields extracted and sign extended. PC-relative operands are resolved to absolute
+addresses, so a decoded instruction is only valid at the address it was decoded for.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decoded {
+    Br {
+        nzp: u16,
+        target: u16,
+    },
+    Add {
+        dr: u16,
+        sr1: u16,
+        sr2: u16,
+    },
+    AddImmediate {
+        dr: u16,
+        sr1: u16,
+        immediate: u16,
+    },
+    And {
+        dr: u16,
+        sr1: u16,
+        sr2: u16,
+    },
+    AndImmediate {
+        dr: u16,
+        sr1: u16,
+        immediate: u16,
+    },
+    Not {
+        dr: u16,
+        sr: u16,
+    },
+    Ld {
+        dr: u16,
+        address: u16,
+    },
+    Ldi {
+        dr: u16,
+        address: u16,
+    },
+    Ldr {
+        dr: u16,
+        base: u16,
+        offset: u16,
+    },
+    Lea {
+        dr: u16,
+        address: u16,
+    },
+    St {
+        sr: u16,
+        address: u16,
+    },
+    Sti {
+        sr: u16,
+        address: u16,
+    },
+    Str {
+        sr: u16,
+        base: u16,
+        offset: u16,
+    },
+    Jmp {
+        base: u16,
+    },
+    Jsr {
+        target: u16,
+    },
+    Jsrr {
+        base: u16,
+    },
+    /// TRAP, RTI and RES, which run their usual handler on the original word.
+    Handler {
+        instruction: u16,
+    },
+}
+
+impl Decoded {
+    /**
+    Decodes the instruction stored at `address`.
+    */
+    pub fn decode(instruction: u16, address: u16) -> Decoded {
+        let next = address.wrapping_add(1);
+        let dr = (instruction >> 9) & 0x7;
+        let sr1 = (instruction >> 6) & 0x7;
+        let pc_offset9 = next.wrapping_add(sign_extended(instruction & 0x1FF, 9));
+        let offset6 = sign_extended(instruction & 0x3F, 6);
+        let immediate = (instruction >> 5) & 0x1 == 1;
+        let imm5 = sign_extended(instruction & 0x1F, 5);
+
+        let opcode = instruction >> 12;
+        match OPCODES[opcode as usize].kind {
+            Instructions::BR => Decoded::Br {
+                nzp: dr,
+                target: pc_offset9,
+            },
+            Instructions::ADD if immediate => Decoded::AddImmediate {
+                dr,
+                sr1,
+                immediate: imm5,
+            },
+            Instructions::ADD => Decoded::Add {
+                dr,
+                sr1,
+                sr2: instruction & 0x7,
+            },
+            Instructions::AND if immediate => Decoded::AndImmediate {
+                dr,
+                sr1,
+                immediate: imm5,
+            },
+            Instructions::AND => Decoded::And {
+                dr,
+                sr1,
+                sr2: instruction & 0x7,
+            },
+            Instructions::NOT => Decoded::Not { dr, sr: sr1 },
+            Instructions::LD => Decoded::Ld {
+                dr,
+                address: pc_offset9,
+            },
+            Instructions::LDI => Decoded::Ldi {
+                dr,
+                address: pc_offset9,
+            },
+            Instructions::LDR => Decoded::Ldr {
+                dr,
+                base: sr1,
+                offset: offset6,
+            },
+            Instructions::LEA => Decoded::Lea {
+                dr,
+                address: pc_offset9,
+            },
+            Instructions::ST => Decoded::St {
+                sr: dr,
+                address: pc_offset9,
+            },
+            Instructions::STI => Decoded::Sti {
+                sr: dr,
+                address: pc_offset9,
+            },
+            Instructions::STR => Decoded::Str {
+                sr: dr,
+                base: sr1,
+                offset: offset6,
+            },
+            Instructions::JMP => Decoded::Jmp { base: sr1 },
+            Instructions::JSR if (instruction >> 11) & 0x1 == 1 => Decoded::Jsr {
+                target: next.wrapping_add(sign_extended(instruction & 0x7FF, 11)),
+            },
+            Instructions::JSR => Decoded::Jsrr { base: sr1 },
+            Instructions::TRAP | Instructions::RTI | Instructions::RES => {
+                Decoded::Handler { instruction }
+            }
+        }
+    }
+
+    /**
+    Executes the instruction `self` was decoded from, with the PC already incremented past it. Behaves exactly
+    like `execute_instruction` on the original word.
+    */
+    #[inline(always)]
+    pub fn execute(self, registers: &mut Registers, memory: &mut Memory) -> Option<StopReason> {
+        match self {
+            Decoded::Br { nzp, target } => {
+                if nzp & registers.read_cond() != 0 {
+                    registers.update_program_counter(target);
+                }
+            }
+            Decoded::Add { dr, sr1, sr2 } => {
+                let value = registers.read(sr1).wrapping_add(registers.read(sr2));
+                set(registers, dr, value);
+            }
+            Decoded::AddImmediate { dr, sr1, immediate } => {
+                set(registers, dr, registers.read(sr1).wrapping_add(immediate));
+            }
+            Decoded::And { dr, sr1, sr2 } => {
+                set(registers, dr, registers.read(sr1) & registers.read(sr2));
+            }
+            Decoded::AndImmediate { dr, sr1, immediate } => {
+                set(registers, dr, registers.read(sr1) & immediate);
+            }
+            Decoded::Not { dr, sr } => set(registers, dr, !registers.read(sr)),
+            Decoded::Ld { dr, address } => set(registers, dr, memory.read(address)),
+            Decoded::Ldi { dr, address } => {
+                let pointer = memory.read(address);
+                set(registers, dr, memory.read(pointer));
+            }
+            Decoded::Ldr { dr, base, offset } => {
+                let address = registers.read(base).wrapping_add(offset);
+                set(registers, dr, memory.read(address));
+            }
+            Decoded::Lea { dr, address } => set(registers, dr, address),
+            Decoded::St { sr, address } => memory.write(address, registers.read(sr)),
+            Decoded::Sti { sr, address } => {
+                let pointer = memory.read(address);
+                memory.write(pointer, registers.read(sr));
+            }
+            Decoded::Str { sr, base, offset } => {
+                let address = registers.read(base).wrapping_add(offset);
+                memory.write(address, registers.read(sr));
+            }
+            Decoded::Jmp { base } => registers.update_program_counter(registers.read(base)),
+            Decoded::Jsr { target } => {
+                registers.update(7, registers.read_program_counter());
+                registers.update_program_counter(target);
+            }
+            Decoded::Jsrr { base } => {
+                let target = registers.read(base);
+                registers.update(7, registers.read_program_counter());
+                registers.update_program_counter(target);
+            }
+            Decoded::Handler { instruction } => {
+                return (OPCODES[instruction as usize >> 12].execute)(
+                    instruction,
+                    registers,
+                    memory,
+                )
+            }
+        }
+        None
+    }
+}
+
+/**
+Writes `value` to `register` and sets the condition codes from it.
+*/
+#[inline]
+pub(crate) fn set(registers: &mut Registers, register: u16, value: u16) {
+    registers.update(register, value);
+    update_flags(register, registers);
+}
+
+/**
+Decoded instructions per address. An entry is dropped whenever its address is written, so self-modifying programs
+see their changes. The device registers are never cached.
+*/
+pub struct DecodeCache {
+    entries: Vec<Option<Decoded>>,
+}
+
+impl Default for DecodeCache {
+    fn default() -> Self {
+        DecodeCache {
+            entries: vec![None; 1 << 16],
+        }
+    }
+}
+
+impl DecodeCache {
+    pub fn new() -> DecodeCache {
+        DecodeCache::default()
+    }
+
+    #[inline]
+    pub fn get(&self, address: u16) -> Option<Decoded> {
+        self.entries[address as usize]
+    }
+
+    #[inline]
+    pub fn insert(&mut self, address: u16, instruction: u16) -> Decoded {
+        let decoded = Decoded::decode(instruction, address);
+        if address < DEVICE_PAGE {
+            self.entries[address as usize] = Some(decoded);
+        }
+        decoded
+    }
+
+    #[inline]
+    pub fn invalidate(&mut self, address: u16) {
+        self.entries[address as usize] = None;
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.fill(None);
+    }
+
+    /**
+    The number of addresses with a decoded instruction.
+    */
+    pub fn len(&self) -> usize {
+        self.entries.iter().filter(|entry| entry.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_decode_resolves_pc_relative_operands() {
+        // LD R2, #-1 at x3000 reads x3000
+        assert_eq!(
+            Decoded::decode(0b0010_010_111111111, 0x3000),
+            Decoded::Ld {
+                dr: 2,
+                address: 0x3000
+            }
+        );
+        // JSR #1 at xFFFF wraps to x0001
+        assert_eq!(
+            Decoded::decode(0b0100_1_00000000001, 0xFFFF),
+            Decoded::Jsr { target: 0x0001 }
+        );
+        assert_eq!(
+            Decoded::decode(0xF025, 0x3000),
+            Decoded::Handler {
+                instruction: 0xF025
+            }
+        );
+    }
+
+    #[test]
+    fn test_cache_entries_are_invalidated() {
+        let mut cache = DecodeCache::new();
+        cache.insert(0x3000, 0xF025);
+        cache.insert(DEVICE_PAGE, 0xF025);
+        assert_eq!(cache.len(), 1);
+
+        cache.invalidate(0x3000);
+        assert_eq!(cache.get(0x3000), None);
+        assert!(cache.is_empty());
+    }
+}
@@ -1,6 +1,9 @@
 use crate::hardware::registers::Registers;
 
-use super::sign_extended;
+/**
+`JMP R7`, returning from a subroutine.
+*/
+pub const RET: u16 = 0b1100_000_111_000000;
 
 /**
 Jump to a location in memory.
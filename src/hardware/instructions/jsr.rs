@@ -7,19 +7,19 @@ Jump to a location in memory based on the value of a register.
 */
 pub fn jsr(instruction: u16, registers: &mut Registers) {
     let long_flag = (instruction >> 11) & 0x1;
-    registers.update(7, registers.read_program_counter());
+    let pc = registers.read_program_counter();
 
-    if long_flag == 1 {
+    let target = if long_flag == 1 {
         // jsr
         let offset = sign_extended(instruction & 0x7FF, 11);
-        registers.update_program_counter(
-            (registers.read_program_counter() as u32 + offset as u32) as u16,
-        );
+        pc.wrapping_add(offset)
     } else {
-        // jsrr
+        // jsrr, the base is read before R7 is overwritten so `JSRR R7` works
         let base_register = (instruction >> 6) & 0x7;
-        registers.update_program_counter(registers.read(base_register));
-    }
+        registers.read(base_register)
+    };
+    registers.update(7, pc);
+    registers.update_program_counter(target);
 }
 
 #[cfg(test)]
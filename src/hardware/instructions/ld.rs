@@ -1,6 +1,6 @@
 use crate::hardware::{memory::Memory, registers::Registers};
 
-use super::{add, sign_extended, update_flags};
+use super::{sign_extended, update_flags};
 
 /**
 Load from memory into a register
@@ -10,9 +10,9 @@ pub fn ld(instruction: u16, registers: &mut Registers, memory: &mut Memory) {
     let offset = sign_extended(instruction & 0x1FF, 9);
     let pc = registers.read_program_counter();
 
-    let address = (pc as u32) + (offset as u32);
+    let address = pc.wrapping_add(offset);
 
-    registers.update(destination_register, memory.read(address as u16));
+    registers.update(destination_register, memory.read(address));
 
     update_flags(destination_register, registers)
 }
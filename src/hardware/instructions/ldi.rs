@@ -9,7 +9,7 @@ pub fn ldi(instruction: u16, registers: &mut Registers, memory: &mut Memory) {
     let destination_register = (instruction >> 9) & 0x7;
     let offset = sign_extended(instruction & 0x1FF, 9);
 
-    let address = memory.read(offset + registers.read_program_counter());
+    let address = memory.read(registers.read_program_counter().wrapping_add(offset));
 
     registers.update(destination_register, memory.read(address));
     update_flags(destination_register, registers)
@@ -1,6 +1,6 @@
 use crate::hardware::{memory::Memory, registers::Registers};
 
-use super::{add, sign_extended, update_flags};
+use super::{sign_extended, update_flags};
 
 /**
 Load from memory into a register from address in BaseR + offset
@@ -10,9 +10,9 @@ pub fn ldr(instruction: u16, registers: &mut Registers, memory: &mut Memory) {
     let base_register = (instruction >> 6) & 0x7;
     let offset = sign_extended(instruction & 0x3F, 6);
 
-    let address = registers.read(base_register) as u32 + offset as u32;
+    let address = registers.read(base_register).wrapping_add(offset);
 
-    registers.update(destination_register, memory.read(address as u16));
+    registers.update(destination_register, memory.read(address));
     update_flags(destination_register, registers)
 }
 
@@ -1,4 +1,4 @@
-use crate::hardware::{memory::Memory, registers::Registers};
+use crate::hardware::registers::Registers;
 
 use super::{sign_extended, update_flags};
 
@@ -9,9 +9,9 @@ pub fn lea(instruction: u16, registers: &mut Registers) {
     let destination_register = (instruction >> 9) & 0x7;
     let offset = sign_extended(instruction & 0x1FF, 9);
 
-    let address = (offset as u32) + (registers.read_program_counter() as u32);
+    let address = registers.read_program_counter().wrapping_add(offset);
 
-    registers.update(destination_register, address as u16);
+    registers.update(destination_register, address);
     update_flags(destination_register, registers)
 }
 
@@ -1,8 +1,9 @@
-use super::{memory::Memory, registers::Registers};
+use super::{memory::Memory, registers::Registers, vm::StopReason};
 
 pub mod add;
 pub mod and;
 pub mod br;
+pub mod decoded;
 pub mod jmp;
 pub mod jsr;
 pub mod ld;
@@ -17,31 +18,144 @@ pub mod sti;
 pub mod str;
 pub mod trap;
 
-pub fn execute_instruction(instruction: u16, registers: &mut Registers, memory: &mut Memory) {
-    let opcode = instruction >> 12;
-    let instruction_kind = Instructions::try_from(opcode);
-    println!("Executing instruction: {:?}", instruction_kind);
-    match instruction_kind {
-        Ok(Instructions::ADD) => br::br(instruction, registers),
-        Ok(Instructions::AND) => and::and(instruction, registers),
-        Ok(Instructions::NOT) => not::not(instruction, registers),
-        Ok(Instructions::BR) => br::br(instruction, registers),
-        Ok(Instructions::JMP) => jmp::jmp(instruction, registers),
-        Ok(Instructions::JSR) => jsr::jsr(instruction, registers),
-        Ok(Instructions::LD) => ld::ld(instruction, registers, memory),
-        Ok(Instructions::LDI) => ldi::ldi(instruction, registers, memory),
-        Ok(Instructions::LDR) => ldr::ldr(instruction, registers, memory),
-        Ok(Instructions::LEA) => lea::lea(instruction, registers),
-        Ok(Instructions::ST) => st::st(instruction, registers, memory),
-        Ok(Instructions::STI) => sti::sti(instruction, registers, memory),
-        Ok(Instructions::STR) => str::str(instruction, registers, memory),
-        Ok(Instructions::TRAP) => trap::trap(instruction, registers, memory),
-        Ok(Instructions::RTI) => rti::rti(instruction, registers),
-        _ => panic!("Unknown opcode: {}", opcode),
-    }
+#[cfg(test)]
+mod conformance;
+#[cfg(test)]
+mod spec;
+
+/**
+Executes one instruction with the PC already incremented past it. Returns `Some` when it stops the machine.
+*/
+pub type Handler = fn(u16, &mut Registers, &mut Memory) -> Option<StopReason>;
+
+pub struct Opcode {
+    pub kind: Instructions,
+    pub execute: Handler,
+}
+
+/**
+Every opcode and its handler, indexed by the top four bits of the instruction. Dispatch and decoding both go
+through this table, so an opcode cannot be wired to the wrong handler in one place only.
+*/
+pub const OPCODES: [Opcode; 16] = [
+    Opcode {
+        kind: Instructions::BR,
+        execute: |instruction, registers, _| {
+            br::br(instruction, registers);
+            None
+        },
+    },
+    Opcode {
+        kind: Instructions::ADD,
+        execute: |instruction, registers, _| {
+            add::add(instruction, registers);
+            None
+        },
+    },
+    Opcode {
+        kind: Instructions::LD,
+        execute: |instruction, registers, memory| {
+            ld::ld(instruction, registers, memory);
+            None
+        },
+    },
+    Opcode {
+        kind: Instructions::ST,
+        execute: |instruction, registers, memory| {
+            st::st(instruction, registers, memory);
+            None
+        },
+    },
+    Opcode {
+        kind: Instructions::JSR,
+        execute: |instruction, registers, _| {
+            jsr::jsr(instruction, registers);
+            None
+        },
+    },
+    Opcode {
+        kind: Instructions::AND,
+        execute: |instruction, registers, _| {
+            and::and(instruction, registers);
+            None
+        },
+    },
+    Opcode {
+        kind: Instructions::LDR,
+        execute: |instruction, registers, memory| {
+            ldr::ldr(instruction, registers, memory);
+            None
+        },
+    },
+    Opcode {
+        kind: Instructions::STR,
+        execute: |instruction, registers, memory| {
+            str::str(instruction, registers, memory);
+            None
+        },
+    },
+    Opcode {
+        kind: Instructions::RTI,
+        execute: |instruction, registers, _| rti::rti(instruction, registers),
+    },
+    Opcode {
+        kind: Instructions::NOT,
+        execute: |instruction, registers, _| {
+            not::not(instruction, registers);
+            None
+        },
+    },
+    Opcode {
+        kind: Instructions::LDI,
+        execute: |instruction, registers, memory| {
+            ldi::ldi(instruction, registers, memory);
+            None
+        },
+    },
+    Opcode {
+        kind: Instructions::STI,
+        execute: |instruction, registers, memory| {
+            sti::sti(instruction, registers, memory);
+            None
+        },
+    },
+    Opcode {
+        kind: Instructions::JMP,
+        execute: |instruction, registers, _| {
+            jmp::jmp(instruction, registers);
+            None
+        },
+    },
+    Opcode {
+        kind: Instructions::RES,
+        execute: |instruction, registers, _| res::res(instruction, registers),
+    },
+    Opcode {
+        kind: Instructions::LEA,
+        execute: |instruction, registers, _| {
+            lea::lea(instruction, registers);
+            None
+        },
+    },
+    Opcode {
+        kind: Instructions::TRAP,
+        execute: trap::trap,
+    },
+];
+
+/**
+Executes a single instruction. Returns `Some` when the instruction stops the machine.
+*/
+#[inline]
+pub fn execute_instruction(
+    instruction: u16,
+    registers: &mut Registers,
+    memory: &mut Memory,
+) -> Option<StopReason> {
+    (OPCODES[(instruction >> 12) as usize].execute)(instruction, registers, memory)
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Instructions {
     BR = 0,
     ADD,
@@ -65,25 +179,10 @@ impl TryFrom<u16> for Instructions {
     type Error = ();
 
     fn try_from(value: u16) -> Result<Self, Self::Error> {
-        match value {
-            0 => Ok(Instructions::BR),
-            1 => Ok(Instructions::ADD),
-            2 => Ok(Instructions::LD),
-            3 => Ok(Instructions::ST),
-            4 => Ok(Instructions::JSR),
-            5 => Ok(Instructions::AND),
-            6 => Ok(Instructions::LDR),
-            7 => Ok(Instructions::STR),
-            8 => Ok(Instructions::RTI),
-            9 => Ok(Instructions::NOT),
-            10 => Ok(Instructions::LDI),
-            11 => Ok(Instructions::STI),
-            12 => Ok(Instructions::JMP),
-            13 => Ok(Instructions::RES),
-            14 => Ok(Instructions::LEA),
-            15 => Ok(Instructions::TRAP),
-            _ => Err(()),
-        }
+        OPCODES
+            .get(value as usize)
+            .map(|opcode| opcode.kind)
+            .ok_or(())
     }
 }
 
@@ -110,3 +209,98 @@ pub enum ConditionalFlags {
     Zero = 1 << 1,     // Z
     Negative = 1 << 2, // N
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::hardware::console::Keyboard;
+
+    const EDGES: [u16; 6] = [0x0000, 0x0001, 0x00FF, 0xFFF0, 0xFFFE, 0xFFFF];
+
+    /// Every offset of a `bits` wide field, with the address it reaches from `base` modulo 2^16.
+    fn offsets(base: u16, bits: u16) -> impl Iterator<Item = (u16, u16)> {
+        let half = 1i32 << (bits - 1);
+        (-half..half).map(move |offset| {
+            let field = (offset as u16) & ((1 << bits) - 1);
+            let target = (base as i32 + offset).rem_euclid(1 << 16) as u16;
+            (field, target)
+        })
+    }
+
+    #[test]
+    fn test_opcode_table_is_in_opcode_order() {
+        for (opcode, entry) in OPCODES.iter().enumerate() {
+            assert_eq!(entry.kind as usize, opcode);
+        }
+    }
+
+    fn machine(pc: u16) -> (Registers, Memory) {
+        let mut registers = Registers::initial();
+        registers.update_program_counter(pc);
+        let mut memory = Memory::empty();
+        memory.keyboard = Keyboard::from_bytes(b"");
+        (registers, memory)
+    }
+
+    #[test]
+    fn test_pc_relative_addresses_wrap() {
+        for pc in EDGES {
+            for (field, target) in offsets(pc, 9) {
+                let (mut registers, mut memory) = machine(pc);
+
+                lea::lea(0b1110_000_000000000 | field, &mut registers);
+                assert_eq!(registers.read(0), target, "LEA at x{:04X}", pc);
+
+                br::br(0b0000_111_000000000 | field, &mut registers);
+                assert_eq!(
+                    registers.read_program_counter(),
+                    target,
+                    "BR at x{:04X}",
+                    pc
+                );
+
+                registers.update_program_counter(pc);
+                registers.update(1, 0xBEEF);
+                st::st(0b0011_001_000000000 | field, &mut registers, &mut memory);
+                assert_eq!(memory.peek(target), 0xBEEF, "ST at x{:04X}", pc);
+                ld::ld(0b0010_010_000000000 | field, &mut registers, &mut memory);
+                assert_eq!(registers.read(2), 0xBEEF, "LD at x{:04X}", pc);
+
+                memory.write(target, 0x8000);
+                sti::sti(0b1011_001_000000000 | field, &mut registers, &mut memory);
+                assert_eq!(memory.peek(0x8000), 0xBEEF, "STI at x{:04X}", pc);
+                ldi::ldi(0b1010_011_000000000 | field, &mut registers, &mut memory);
+                assert_eq!(registers.read(3), 0xBEEF, "LDI at x{:04X}", pc);
+            }
+
+            for (field, target) in offsets(pc, 11) {
+                let (mut registers, _) = machine(pc);
+                jsr::jsr(0b0100_1_00000000000 | field, &mut registers);
+                assert_eq!(
+                    registers.read_program_counter(),
+                    target,
+                    "JSR at x{:04X}",
+                    pc
+                );
+                assert_eq!(registers.read(7), pc);
+            }
+        }
+    }
+
+    #[test]
+    fn test_base_relative_addresses_wrap() {
+        for base in EDGES {
+            for (field, target) in offsets(base, 6) {
+                let (mut registers, mut memory) = machine(0x3000);
+                registers.update(1, base);
+                registers.update(2, 0x1234);
+
+                str::str(0b0111_010_001_000000 | field, &mut registers, &mut memory);
+                assert_eq!(memory.peek(target), 0x1234, "STR from x{:04X}", base);
+                ldr::ldr(0b0110_011_001_000000 | field, &mut registers, &mut memory);
+                assert_eq!(registers.read(3), 0x1234, "LDR from x{:04X}", base);
+            }
+        }
+    }
+}
@@ -1,5 +1,3 @@
-use std::process;
-
 use crate::hardware::registers::Registers;
 
 use super::update_flags;
@@ -1,10 +1,12 @@
-use std::process;
-
 use crate::hardware::registers::Registers;
+use crate::hardware::vm::{Fault, StopReason};
 
 /**
-Unused, abort.
+Return from interrupt. Only legal in supervisor mode, which this VM does not model, so it faults.
 */
-pub fn rti(_instruction: u16, _registers: &mut Registers) {
-    process::abort()
+pub fn rti(instruction: u16, registers: &mut Registers) -> Option<StopReason> {
+    Some(StopReason::Fault(Fault::IllegalOpcode {
+        address: registers.read_program_counter().wrapping_sub(1),
+        instruction,
+    }))
 }
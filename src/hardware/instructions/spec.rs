@@ -0,0 +1,427 @@
+/*!
+A reference model of every LC-3 instruction, written from the ISA description without the handlers or their
+helpers, and tests that check `execute_instruction` against it: every one of the 65536 instruction words from
+random register and memory states, random states with shrinking through proptest, and `sign_extended` and
+`update_flags` over all their inputs.
+
+Where the ISA leaves the behaviour to the implementation, the model follows the VM: unused instruction bits are
+ignored, LEA sets the condition codes (second edition), RTI and the reserved opcode fault, the trap routines are
+built in and GETC and IN leave R0 alone when there is no input.
+*/
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use proptest::prelude::*;
+
+use super::{execute_instruction, sign_extended, update_flags};
+use crate::hardware::console::{Display, Keyboard};
+use crate::hardware::memory::{Memory, MemoryAccess};
+use crate::hardware::registers::{Registers, COND, PC};
+use crate::hardware::vm::{Fault, StopReason};
+
+const P: u16 = 1;
+const Z: u16 = 2;
+const N: u16 = 4;
+
+const KBSR: u16 = 0xFE00;
+const KBDR: u16 = 0xFE02;
+const IN_PROMPT: &[u8] = b"Enter a  character : ";
+
+/// Random states per instruction word in the exhaustive test.
+const ROUNDS: usize = 3;
+
+fn sext(value: u16, bits: u32) -> u16 {
+    let unused = 16 - bits;
+    (((value << unused) as i16) >> unused) as u16
+}
+
+fn condition(value: u16) -> u16 {
+    match (value as i16).signum() {
+        0 => Z,
+        -1 => N,
+        _ => P,
+    }
+}
+
+/**
+The machine state before an instruction, apart from memory.
+*/
+#[derive(Debug, Clone)]
+struct State {
+    registers: [u16; 8],
+    /// Where the instruction is.
+    pc: u16,
+    cond: u16,
+    input: Vec<u8>,
+}
+
+/**
+Everything an instruction can change.
+*/
+#[derive(Debug, PartialEq, Eq)]
+struct Outcome {
+    registers: [u16; 8],
+    pc: u16,
+    cond: u16,
+    /// Address and value of every memory write, device registers included, in order.
+    writes: Vec<(u16, u16)>,
+    output: Vec<u8>,
+    stop: Option<StopReason>,
+}
+
+/**
+The model machine. Memory is `base` with the writes made so far on top.
+*/
+struct Model<'a> {
+    registers: [u16; 8],
+    pc: u16,
+    cond: u16,
+    base: &'a [u16],
+    memory: BTreeMap<u16, u16>,
+    writes: Vec<(u16, u16)>,
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+}
+
+impl Model<'_> {
+    /**
+    Reading the keyboard status register polls the keyboard: with a key available the status gets its ready bit
+    and the data register the key, otherwise the status is cleared.
+    */
+    fn read(&mut self, address: u16) -> u16 {
+        if address == KBSR {
+            match self.input.pop_front() {
+                // a NUL byte is consumed but does not count as a key
+                Some(key) if key != 0 => {
+                    self.write(KBSR, 0x8000);
+                    self.write(KBDR, key as u16);
+                }
+                _ => self.write(KBSR, 0),
+            }
+        }
+        match self.memory.get(&address) {
+            Some(&value) => value,
+            None => self.base[address as usize],
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u16) {
+        self.memory.insert(address, value);
+        self.writes.push((address, value));
+    }
+
+    fn set(&mut self, register: usize, value: u16) {
+        self.registers[register] = value;
+        self.cond = condition(value);
+    }
+
+    fn execute(&mut self, instruction: u16) -> Option<StopReason> {
+        let address = self.pc;
+        self.pc = self.pc.wrapping_add(1);
+
+        let field = |lowest: u16| ((instruction >> lowest) & 0b111) as usize;
+        let (dr, sr1, sr2) = (field(9), field(6), field(0));
+        let pc_offset9 = self.pc.wrapping_add(sext(instruction, 9));
+        let offset6 = sext(instruction, 6);
+        let operand = match instruction & 0x20 {
+            0 => self.registers[sr2],
+            _ => sext(instruction, 5),
+        };
+
+        match instruction >> 12 {
+            0b0000 => {
+                let nzp = (instruction >> 9) & 0b111;
+                if nzp & self.cond != 0 {
+                    self.pc = pc_offset9;
+                }
+            }
+            0b0001 => self.set(dr, self.registers[sr1].wrapping_add(operand)),
+            0b0101 => self.set(dr, self.registers[sr1] & operand),
+            0b1001 => self.set(dr, !self.registers[sr1]),
+            0b0010 => {
+                let value = self.read(pc_offset9);
+                self.set(dr, value);
+            }
+            0b1010 => {
+                let pointer = self.read(pc_offset9);
+                let value = self.read(pointer);
+                self.set(dr, value);
+            }
+            0b0110 => {
+                let value = self.read(self.registers[sr1].wrapping_add(offset6));
+                self.set(dr, value);
+            }
+            0b1110 => self.set(dr, pc_offset9),
+            0b0011 => self.write(pc_offset9, self.registers[dr]),
+            0b1011 => {
+                let pointer = self.read(pc_offset9);
+                self.write(pointer, self.registers[dr]);
+            }
+            0b0111 => self.write(
+                self.registers[sr1].wrapping_add(offset6),
+                self.registers[dr],
+            ),
+            0b1100 => self.pc = self.registers[sr1],
+            0b0100 => {
+                let target = match instruction & 0x800 {
+                    0 => self.registers[sr1],
+                    _ => self.pc.wrapping_add(sext(instruction, 11)),
+                };
+                self.registers[7] = self.pc;
+                self.pc = target;
+            }
+            0b1111 => {
+                self.registers[7] = self.pc;
+                return self.trap(address, instruction as u8);
+            }
+            _ => {
+                return Some(StopReason::Fault(Fault::IllegalOpcode {
+                    address,
+                    instruction,
+                }))
+            }
+        }
+        None
+    }
+
+    fn trap(&mut self, address: u16, vector: u8) -> Option<StopReason> {
+        match vector {
+            0x20 => self.getc(),
+            0x21 => self.output.push(self.registers[0] as u8),
+            0x22 | 0x24 => {
+                let mut address = self.registers[0];
+                loop {
+                    let word = self.read(address);
+                    if word == 0 {
+                        break;
+                    }
+                    self.output.push(word as u8);
+                    // PUTSP packs a second character in the high byte, a zero high byte ends the string
+                    if vector == 0x24 && word >> 8 != 0 {
+                        self.output.push((word >> 8) as u8);
+                    }
+                    address = address.wrapping_add(1);
+                }
+            }
+            0x23 => {
+                self.output.extend_from_slice(IN_PROMPT);
+                self.getc();
+            }
+            0x25 => return Some(StopReason::Halted),
+            _ => return Some(StopReason::Fault(Fault::UnknownTrap { address, vector })),
+        }
+        None
+    }
+
+    fn getc(&mut self) {
+        if let Some(key) = self.input.pop_front() {
+            self.registers[0] = key as u16;
+        }
+    }
+}
+
+/**
+What the model says `instruction` at `state.pc` does, with memory as in `memory`.
+*/
+fn model(memory: &Memory, state: &State, instruction: u16) -> Outcome {
+    let mut model = Model {
+        registers: state.registers,
+        pc: state.pc,
+        cond: state.cond,
+        base: memory.contents(),
+        memory: BTreeMap::new(),
+        writes: Vec::new(),
+        input: state.input.iter().copied().collect(),
+        output: Vec::new(),
+    };
+    let stop = model.execute(instruction);
+    Outcome {
+        registers: model.registers,
+        pc: model.pc,
+        cond: model.cond,
+        writes: model.writes,
+        output: model.output,
+        stop,
+    }
+}
+
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/**
+What `execute_instruction` does with `instruction` at `state.pc`. Memory is left as it was.
+*/
+fn implementation(memory: &mut Memory, state: &State, instruction: u16) -> Outcome {
+    let mut registers = Registers::initial();
+    for (register, &value) in (0..).zip(&state.registers) {
+        registers.update(register, value);
+    }
+    registers.update(COND, state.cond);
+    registers.update(PC, state.pc.wrapping_add(1));
+    let output = Rc::new(RefCell::new(Vec::new()));
+    memory.display = Display::to_writer(Box::new(Output(Rc::clone(&output))));
+    memory.keyboard = Keyboard::from_bytes(&state.input);
+
+    memory.start_journal();
+    let stop = execute_instruction(instruction, &mut registers, memory);
+    let accesses = memory.take_journal();
+    memory.display.flush();
+
+    let mut writes = Vec::new();
+    for access in &accesses {
+        if let &MemoryAccess::Write { address, value, .. } = access {
+            writes.push((address, value));
+        }
+    }
+    for access in accesses.iter().rev() {
+        if let &MemoryAccess::Write {
+            address, previous, ..
+        } = access
+        {
+            memory.write(address, previous);
+        }
+    }
+
+    Outcome {
+        registers: [0, 1, 2, 3, 4, 5, 6, 7].map(|register| registers.read(register)),
+        pc: registers.read_program_counter(),
+        cond: registers.read_cond(),
+        writes,
+        output: output.take(),
+        stop,
+    }
+}
+
+/**
+Checks `instruction`, stored at `state.pc`, against the model.
+*/
+fn check(memory: &mut Memory, state: &State, instruction: u16) -> Result<(), String> {
+    let previous = memory.peek(state.pc);
+    memory.write(state.pc, instruction);
+    let expected = model(memory, state, instruction);
+    let actual = implementation(memory, state, instruction);
+    memory.write(state.pc, previous);
+
+    if actual == expected {
+        return Ok(());
+    }
+    Err(format!(
+        "x{:04X} from {:?}:\n  expected {:?}\n  got      {:?}",
+        instruction, state, expected, actual
+    ))
+}
+
+/**
+A small deterministic generator, biased towards the values where sign extension, wrapping and the condition codes
+have their edges.
+*/
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn word(&mut self) -> u16 {
+        let random = self.next();
+        match random % 8 {
+            0 => 0,
+            1 => 0x7FFF,
+            2 => 0x8000,
+            3 => 0xFFFF,
+            // small positive and negative numbers, e.g. characters and offsets
+            4 => sext((random >> 8) as u16, 8),
+            _ => (random >> 16) as u16,
+        }
+    }
+
+    fn state(&mut self) -> State {
+        State {
+            registers: [(); 8].map(|_| self.word()),
+            pc: self.word(),
+            cond: [N, Z, P][(self.next() % 3) as usize],
+            input: if self.next().is_multiple_of(2) {
+                Vec::new()
+            } else {
+                vec![b'k']
+            },
+        }
+    }
+}
+
+#[test]
+fn test_every_instruction_word_matches_the_model() {
+    let mut random = Random(0x9E37_79B9_7F4A_7C15);
+    let mut memory = Memory::empty();
+    for _ in 0..ROUNDS {
+        for address in 0..=u16::MAX {
+            memory.write(address, random.word());
+        }
+        for instruction in 0..=u16::MAX {
+            let state = random.state();
+            if let Err(mismatch) = check(&mut memory, &state, instruction) {
+                panic!("{}", mismatch);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_sign_extension_matches_the_model() {
+    for bits in [5, 6, 9, 11] {
+        for value in 0..1 << bits {
+            assert_eq!(sign_extended(value, bits), sext(value, bits as u32));
+        }
+    }
+    assert_eq!(sign_extended(0b10000, 5), 0xFFF0);
+    assert_eq!(sign_extended(0b01111, 5), 0x000F);
+    assert_eq!(sign_extended(0x100, 9), 0xFF00);
+    assert_eq!(sign_extended(0x7FF, 11), 0xFFFF);
+}
+
+#[test]
+fn test_condition_codes_match_the_model() {
+    let mut registers = Registers::initial();
+    for value in 0..=u16::MAX {
+        registers.update(3, value);
+        update_flags(3, &mut registers);
+        assert_eq!(registers.read_cond(), condition(value), "x{:04X}", value);
+    }
+    assert_eq!(condition(0x8000), N);
+    assert_eq!(condition(0x7FFF), P);
+}
+
+proptest! {
+    #[test]
+    fn prop_instruction_matches_the_model(
+        instruction in any::<u16>(),
+        registers in any::<[u16; 8]>(),
+        pc in any::<u16>(),
+        cond in prop::sample::select(vec![N, Z, P]),
+        cells in prop::collection::vec((any::<u16>(), any::<u16>()), 0..16),
+        input in prop::collection::vec(prop::sample::select(vec![0, b'k']), 0..2),
+    ) {
+        let mut memory = Memory::empty();
+        for (address, value) in cells {
+            memory.write(address, value);
+        }
+        let state = State { registers, pc, cond, input };
+        check(&mut memory, &state, instruction).map_err(TestCaseError::fail)?;
+    }
+}
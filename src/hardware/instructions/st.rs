@@ -10,10 +10,10 @@ pub fn st(instruction: u16, registers: &mut Registers, memory: &mut Memory) {
     let pc = registers.read_program_counter();
     let offset = sign_extended(instruction & 0x1FF, 9);
 
-    let address = (pc as u32) + (offset as u32);
+    let address = pc.wrapping_add(offset);
     let value = registers.read(source_register);
 
-    memory.write(address as u16, value);
+    memory.write(address, value);
 }
 
 #[cfg(test)]
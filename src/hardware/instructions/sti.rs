@@ -1,6 +1,6 @@
 use crate::hardware::{memory::Memory, registers::Registers};
 
-use super::{add, sign_extended, update_flags};
+use super::sign_extended;
 
 /**
 Store content of register into an address in memory specified by the address thats in memory at the offset and program counter.
@@ -10,7 +10,7 @@ pub fn sti(instruction: u16, registers: &mut Registers, memory: &mut Memory) {
     let pc = registers.read_program_counter();
     let offset = sign_extended(instruction & 0x1FF, 9);
 
-    let address: u16 = memory.read((pc as u32 + offset as u32) as u16);
+    let address = memory.read(pc.wrapping_add(offset));
     let value = registers.read(source_register);
 
     memory.write(address, value);
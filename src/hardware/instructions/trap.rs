@@ -1,87 +1,102 @@
-use std::io::{self, stdin, Read, Write};
-
 use crate::hardware::{
-    memory::{self, Memory},
+    memory::Memory,
     registers::Registers,
+    vm::{Fault, StopReason},
 };
 
-pub fn trap(instruction: u16, registers: &mut Registers, memory: &mut Memory) {
+/**
+Runs a trap routine. Returns `Some` when the routine stops the machine: on HALT, or when there is no routine for the vector.
+The routines are built into the VM rather than loaded from an OS image, but R7 still receives the return address.
+*/
+pub fn trap(
+    instruction: u16,
+    registers: &mut Registers,
+    memory: &mut Memory,
+) -> Option<StopReason> {
     let trap_code = instruction & 0xFF;
+    registers.update(7, registers.read_program_counter());
 
     match trap_code {
-        0x20 => trap_getc(registers),
-        0x21 => trap_out(registers),
+        0x20 => trap_getc(registers, memory),
+        0x21 => trap_out(registers, memory),
         0x22 => trap_puts(registers, memory),
-        0x23 => trap_in(registers),
+        0x23 => trap_in(registers, memory),
         0x24 => trap_putsp(registers, memory),
-        0x25 => trap_halt(),
-        _ => panic!("Unknown trap code: {}", trap_code),
+        0x25 => return Some(trap_halt(memory)),
+        _ => {
+            return Some(StopReason::Fault(Fault::UnknownTrap {
+                address: registers.read_program_counter().wrapping_sub(1),
+                vector: trap_code as u8,
+            }))
+        }
     }
+    None
 }
 
-fn trap_getc(registers: &mut Registers) {
-    let mut buffer = [0; 1];
-    io::stdin().read_exact(&mut buffer).unwrap();
-
-    registers.update(0, buffer[0] as u16);
+fn trap_getc(registers: &mut Registers, memory: &mut Memory) {
+    // without input R0 is left alone, the VM stops with the keyboard's failure
+    memory.display.flush();
+    if let Some(byte) = memory.keyboard.read_byte() {
+        registers.update(0, byte as u16);
+    }
 }
 
-fn trap_out(registers: &mut Registers) {
+fn trap_out(registers: &mut Registers, memory: &mut Memory) {
     let c = registers.read(0);
-    print!("{}", c as u8 as char);
+    memory.display.write_byte(c as u8);
 }
 
 fn trap_puts(registers: &mut Registers, memory: &mut Memory) {
     let mut index = registers.read(0);
     let mut c = memory.read(index);
 
-    while c != 0x0000 {
-        print!("{}", c as u8 as char);
-        index += 1;
+    // past the output limit, scanning on for the NUL would only poll KBSR and wrap around memory
+    while c != 0x0000 && !memory.display.limit_reached {
+        memory.display.write_byte(c as u8);
+        index = index.wrapping_add(1);
         c = memory.read(index);
     }
-    io::stdout().flush().expect("Error flushing output");
+    memory.display.flush();
 }
 
-fn trap_in(registers: &mut Registers) {
-    print!("Enter a  character : ");
-    io::stdout().flush().expect("failed to flush");
-    let char = std::io::stdin()
-        .bytes()
-        .next()
-        .and_then(|result| result.ok())
-        .map(|byte| byte as u16)
-        .unwrap();
-    registers.update(0, char);
+fn trap_in(registers: &mut Registers, memory: &mut Memory) {
+    for &byte in b"Enter a  character : " {
+        memory.display.write_byte(byte);
+    }
+    memory.display.flush();
+    if let Some(byte) = memory.keyboard.read_byte() {
+        registers.update(0, byte as u16);
+    }
 }
 
 fn trap_putsp(registers: &mut Registers, memory: &mut Memory) {
     // Putsp
     let mut index = registers.read(0);
     let mut c = memory.read(index);
-    while c != 0x0000 {
-        let c1 = ((c & 0xFF) as u8) as char;
-        print!("{}", c1);
-        let c2 = ((c >> 8) as u8) as char;
-        if c2 != '\0' {
-            print!("{}", c2);
+    while c != 0x0000 && !memory.display.limit_reached {
+        memory.display.write_byte((c & 0xFF) as u8);
+        let c2 = (c >> 8) as u8;
+        if c2 != 0 {
+            memory.display.write_byte(c2);
         }
-        index += 1;
+        index = index.wrapping_add(1);
         c = memory.read(index);
     }
-    io::stdout().flush().expect("failed to flush");
+    memory.display.flush();
 }
 
-fn trap_halt() {
-    println!("HALT detected");
-    io::stdout().flush().expect("failed to flush");
-    std::process::exit(1);
+fn trap_halt(memory: &mut Memory) -> StopReason {
+    memory.display.flush();
+    StopReason::Halted
 }
 
 #[cfg(test)]
 mod tests {
 
+    use std::io;
+
     use super::*;
+    use crate::hardware::console::{Display, Keyboard};
     use crate::hardware::memory;
 
     #[test]
@@ -102,4 +117,26 @@ mod tests {
 
         registers.pretty_print()
     }
+
+    #[test]
+    fn test_puts_stops_at_the_output_limit() {
+        let mut registers = Registers::initial();
+        let mut memory = memory::Memory::empty();
+        memory.display = Display::to_writer(Box::new(io::sink()));
+        memory.display.limit = Some(2);
+        memory.keyboard = Keyboard::from_bytes(b"k");
+
+        // a string without a terminator runs all the way around memory, past KBSR
+        for address in 0x3000..=0xFFFF {
+            memory.write(address, 'x' as u16);
+        }
+        registers.update(0, 0x3000);
+        trap_puts(&mut registers, &mut memory);
+        registers.update(0, 0x3000);
+        trap_putsp(&mut registers, &mut memory);
+
+        assert!(memory.display.limit_reached);
+        assert_eq!(memory.display.written, 2);
+        assert_eq!(memory.keyboard.pending, b"k");
+    }
 }
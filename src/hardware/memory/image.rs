@@ -0,0 +1,265 @@
+use std::fmt;
+use std::fmt::Write;
+
+use super::Memory;
+
+/// Words per Intel HEX data record.
+const RECORD_WORDS: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImageError {
+    /// The range starts after it ends or reaches past the end of memory.
+    InvalidRange {
+        start: u16,
+        end: u16,
+    },
+    /// The image would not fit in memory at the given address.
+    OutOfRange {
+        address: usize,
+    },
+    /// Raw images hold whole 16 bit words.
+    OddLength {
+        bytes: usize,
+    },
+    InvalidRecord {
+        line: usize,
+        reason: &'static str,
+    },
+    ChecksumMismatch {
+        line: usize,
+    },
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::InvalidRange { start, end } => {
+                write!(f, "invalid memory range x{:04X}-x{:04X}", start, end)
+            }
+            ImageError::OutOfRange { address } => {
+                write!(
+                    f,
+                    "image reaches past the end of memory at x{:04X}",
+                    address
+                )
+            }
+            ImageError::OddLength { bytes } => write!(
+                f,
+                "raw image has {} bytes, which is not a whole number of words",
+                bytes
+            ),
+            ImageError::InvalidRecord { line, reason } => {
+                write!(f, "invalid Intel HEX record on line {}: {}", line, reason)
+            }
+            ImageError::ChecksumMismatch { line } => {
+                write!(f, "Intel HEX checksum mismatch on line {}", line)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+/**
+Import and export of memory ranges for other LC-3 implementations.
+
+Intel HEX images are word addressed: a record's address is a word address and every word is two data bytes,
+high byte first. Raw images are the words of the range, big-endian, without any header.
+*/
+impl Memory {
+    pub fn export_intel_hex(&self, start: u16, end: u16) -> Result<String, ImageError> {
+        let words = self.range(start, end)?;
+
+        let mut text = String::new();
+        for (index, chunk) in words.chunks(RECORD_WORDS).enumerate() {
+            let address = start as usize + index * RECORD_WORDS;
+            let mut record = vec![
+                (chunk.len() * 2) as u8,
+                (address >> 8) as u8,
+                address as u8,
+                0x00,
+            ];
+            for word in chunk {
+                record.extend(word.to_be_bytes());
+            }
+            write_record(&mut text, &record);
+        }
+        write_record(&mut text, &[0x00, 0x00, 0x00, 0x01]);
+        Ok(text)
+    }
+
+    /**
+    Writes the data records of an Intel HEX image into memory and returns the number of words written.
+    Nothing is written if any record is invalid.
+    */
+    pub fn import_intel_hex(&mut self, text: &str) -> Result<usize, ImageError> {
+        let mut words = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let invalid = |reason| ImageError::InvalidRecord {
+                line: line_number,
+                reason,
+            };
+
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let digits = line
+                .strip_prefix(':')
+                .ok_or_else(|| invalid("missing ':'"))?;
+            if !digits.is_ascii() || !digits.len().is_multiple_of(2) || digits.len() < 10 {
+                return Err(invalid("wrong length"));
+            }
+            let bytes = (0..digits.len())
+                .step_by(2)
+                .map(|offset| u8::from_str_radix(&digits[offset..offset + 2], 16))
+                .collect::<Result<Vec<u8>, _>>()
+                .map_err(|_| invalid("not hexadecimal"))?;
+
+            let count = bytes[0] as usize;
+            if bytes.len() != count + 5 {
+                return Err(invalid("byte count does not match the record"));
+            }
+            if bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0 {
+                return Err(ImageError::ChecksumMismatch { line: line_number });
+            }
+
+            let address = u16::from_be_bytes([bytes[1], bytes[2]]) as usize;
+            let data = &bytes[4..4 + count];
+            match bytes[3] {
+                0x00 => {
+                    if !count.is_multiple_of(2) {
+                        return Err(invalid("odd number of data bytes"));
+                    }
+                    for (offset, pair) in data.chunks_exact(2).enumerate() {
+                        words.push((address + offset, u16::from_be_bytes([pair[0], pair[1]])));
+                    }
+                }
+                0x01 => break,
+                // extended segment or linear address, only zero fits in the 16 bit address space
+                0x02 | 0x04 if data.iter().all(|&byte| byte == 0) => {}
+                0x02 | 0x04 => return Err(ImageError::OutOfRange { address: 1 << 16 }),
+                // start addresses mean nothing to the LC-3
+                0x03 | 0x05 => {}
+                _ => return Err(invalid("unknown record type")),
+            }
+        }
+
+        if let Some(&(address, _)) = words
+            .iter()
+            .find(|&&(address, _)| address >= self.memory_max)
+        {
+            return Err(ImageError::OutOfRange { address });
+        }
+        for &(address, word) in &words {
+            self.memory[address] = word;
+            self.invalidate(address as u16);
+        }
+        Ok(words.len())
+    }
+
+    pub fn export_raw(&self, start: u16, end: u16) -> Result<Vec<u8>, ImageError> {
+        Ok(self
+            .range(start, end)?
+            .iter()
+            .flat_map(|word| word.to_be_bytes())
+            .collect())
+    }
+
+    /**
+    Writes a raw image to memory starting at `base` and returns the number of words written.
+    */
+    pub fn import_raw(&mut self, base: u16, bytes: &[u8]) -> Result<usize, ImageError> {
+        if !bytes.len().is_multiple_of(2) {
+            return Err(ImageError::OddLength { bytes: bytes.len() });
+        }
+        let length = bytes.len() / 2;
+        let base = base as usize;
+        if base + length > self.memory_max {
+            return Err(ImageError::OutOfRange {
+                address: self.memory_max,
+            });
+        }
+
+        for (offset, pair) in bytes.chunks_exact(2).enumerate() {
+            self.memory[base + offset] = u16::from_be_bytes([pair[0], pair[1]]);
+            self.invalidate((base + offset) as u16);
+        }
+        Ok(length)
+    }
+
+    fn range(&self, start: u16, end: u16) -> Result<&[u16], ImageError> {
+        if start > end || end as usize >= self.memory_max {
+            return Err(ImageError::InvalidRange { start, end });
+        }
+        Ok(&self.memory[start as usize..=end as usize])
+    }
+}
+
+fn write_record(text: &mut String, bytes: &[u8]) {
+    let checksum = bytes
+        .iter()
+        .fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+        .wrapping_neg();
+    text.push(':');
+    for byte in bytes.iter().chain(std::iter::once(&checksum)) {
+        write!(text, "{:02X}", byte).unwrap();
+    }
+    text.push('\n');
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_intel_hex_round_trip() {
+        let mut memory = Memory::empty();
+        for offset in 0..20 {
+            memory.write(0x3000 + offset, 0x1100 + offset);
+        }
+
+        let text = memory.export_intel_hex(0x3000, 0x3013).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with(":083010001110111111121113"));
+        assert_eq!(lines[2], ":00000001FF");
+
+        let mut copy = Memory::empty();
+        assert_eq!(copy.import_intel_hex(&text).unwrap(), 20);
+        assert_eq!(
+            copy.contents()[0x3000..0x3014],
+            memory.contents()[0x3000..0x3014]
+        );
+
+        let corrupt = text.replacen(":20", ":21", 1);
+        assert!(copy.import_intel_hex(&corrupt).is_err());
+        let corrupt = text.replacen("1100", "1101", 1);
+        assert_eq!(
+            copy.import_intel_hex(&corrupt),
+            Err(ImageError::ChecksumMismatch { line: 1 })
+        );
+    }
+
+    #[test]
+    fn test_raw_round_trip() {
+        let mut memory = Memory::empty();
+        memory.write(0x4000, 0xABCD);
+        memory.write(0x4001, 0x0102);
+
+        let bytes = memory.export_raw(0x4000, 0x4001).unwrap();
+        assert_eq!(bytes, vec![0xAB, 0xCD, 0x01, 0x02]);
+
+        let mut copy = Memory::empty();
+        assert_eq!(copy.import_raw(0x5000, &bytes).unwrap(), 2);
+        assert_eq!(copy.read(0x5001), 0x0102);
+        assert_eq!(
+            copy.import_raw(0x5000, &bytes[..3]),
+            Err(ImageError::OddLength { bytes: 3 })
+        );
+        assert!(copy.import_raw(0xFFFF, &bytes).is_err());
+        assert!(memory.export_raw(0x4001, 0x4000).is_err());
+    }
+}
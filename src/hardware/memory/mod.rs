@@ -1,43 +1,313 @@
-use std::io::Read;
+use super::console::{Display, Keyboard};
+use super::instructions::decoded::{DecodeCache, Decoded};
 
-const MEMORY_MAX: usize = u16::MAX as usize;
+pub mod image;
+
+pub const MEMORY_MAX: usize = 1 << 16;
 
 pub struct Memory {
     memory: [u16; MEMORY_MAX],
     pub memory_max: usize,
+    journal: Option<Vec<MemoryAccess>>,
+    decode_cache: Option<Box<DecodeCache>>,
+    watch: Option<Box<WriteWatch>>,
+    pub keyboard: Keyboard,
+    pub display: Display,
+}
+/**
+Addresses whose writes are collected, for anything that derives state from memory and is not rebuilt on every
+access.
+*/
+struct WriteWatch {
+    watched: Vec<bool>,
+    written: Vec<u16>,
 }
+
+#[allow(non_camel_case_types)]
 pub enum MemoryMappedRegister {
     MR_KBSR = 0xFE00, /* keyboard status */
     MR_KBDR = 0xFE02, /* keyboard data */
 }
 
+/**
+A single read or write performed through `Memory::read` / `Memory::write` while the journal is enabled.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryAccess {
+    Read {
+        address: u16,
+        value: u16,
+    },
+    Write {
+        address: u16,
+        previous: u16,
+        value: u16,
+    },
+}
+
 impl Memory {
     pub fn empty() -> Memory {
         Memory {
             memory: [0; MEMORY_MAX],
             memory_max: MEMORY_MAX,
+            journal: None,
+            decode_cache: None,
+            watch: None,
+            keyboard: Keyboard::stdin(),
+            display: Display::stdout(),
         }
     }
 
     fn handle_keyboard(&mut self) {
-        let mut buffer = [0; 1];
-        std::io::stdin().read_exact(&mut buffer).unwrap();
-        if buffer[0] != 0 {
-            self.write(MemoryMappedRegister::MR_KBSR as u16, 1 << 15);
-            self.write(MemoryMappedRegister::MR_KBDR as u16, buffer[0] as u16);
-        } else {
-            self.write(MemoryMappedRegister::MR_KBSR as u16, 0)
+        // the program may be waiting for a key in response to what it printed
+        self.display.flush();
+        match self.keyboard.read_byte() {
+            Some(byte) if byte != 0 => {
+                self.write(MemoryMappedRegister::MR_KBSR as u16, 1 << 15);
+                self.write(MemoryMappedRegister::MR_KBDR as u16, byte as u16);
+            }
+            _ => self.write(MemoryMappedRegister::MR_KBSR as u16, 0),
         }
     }
 
+    #[inline]
     pub fn read(&mut self, index: u16) -> u16 {
         if index == MemoryMappedRegister::MR_KBSR as u16 {
             self.handle_keyboard();
         }
-        self.memory[index as usize]
+        let value = self.memory[index as usize];
+        if let Some(journal) = &mut self.journal {
+            journal.push(MemoryAccess::Read {
+                address: index,
+                value,
+            });
+        }
+        value
     }
 
+    #[inline]
     pub fn write(&mut self, index: u16, value: u16) {
+        if let Some(journal) = &mut self.journal {
+            journal.push(MemoryAccess::Write {
+                address: index,
+                previous: self.memory[index as usize],
+                value,
+            });
+        }
         self.memory[index as usize] = value;
+        self.invalidate(index);
+    }
+
+    /**
+    Fetches and decodes the instruction at `pc`, from the decode cache when it is enabled and has an entry.
+    */
+    #[inline]
+    pub fn fetch_decoded(&mut self, pc: u16) -> Decoded {
+        match self.decode_cache.as_ref().and_then(|cache| cache.get(pc)) {
+            Some(entry) => entry,
+            None => self.decode(pc),
+        }
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn decode(&mut self, pc: u16) -> Decoded {
+        let instruction = self.read(pc);
+        match &mut self.decode_cache {
+            Some(cache) => cache.insert(pc, instruction),
+            None => Decoded::decode(instruction, pc),
+        }
+    }
+
+    /**
+    Starts caching decoded instructions, see `fetch_decoded`. Writes through `write` and the image importers
+    invalidate the affected entries.
+    */
+    pub fn enable_decode_cache(&mut self) {
+        self.decode_cache = Some(Box::new(DecodeCache::new()));
+    }
+
+    pub fn disable_decode_cache(&mut self) {
+        self.decode_cache = None;
+    }
+
+    pub fn decode_cache(&self) -> Option<&DecodeCache> {
+        self.decode_cache.as_deref()
+    }
+
+    /**
+    Reports writes to `index` through `take_watched_writes` from now on, including writes by the image
+    importers.
+    */
+    pub fn watch_writes(&mut self, index: u16) {
+        let watch = self.watch.get_or_insert_with(|| {
+            Box::new(WriteWatch {
+                watched: vec![false; MEMORY_MAX],
+                written: Vec::new(),
+            })
+        });
+        watch.watched[index as usize] = true;
+    }
+
+    pub fn unwatch_writes(&mut self) {
+        self.watch = None;
+    }
+
+    /**
+    Whether a watched address was written since the last `take_watched_writes`.
+    */
+    #[inline]
+    pub fn watched_written(&self) -> bool {
+        self.watch
+            .as_ref()
+            .is_some_and(|watch| !watch.written.is_empty())
+    }
+
+    /**
+    The watched addresses written since the last call, in the order they were written.
+    */
+    pub fn take_watched_writes(&mut self) -> Vec<u16> {
+        self.watch
+            .as_mut()
+            .map_or_else(Vec::new, |watch| std::mem::take(&mut watch.written))
+    }
+
+    #[inline]
+    fn invalidate(&mut self, index: u16) {
+        if let Some(cache) = &mut self.decode_cache {
+            cache.invalidate(index);
+        }
+        if let Some(watch) = &mut self.watch {
+            if watch.watched[index as usize] {
+                watch.written.push(index);
+            }
+        }
+    }
+
+    /**
+    The word at `index` without any side effects: reading the keyboard status does not poll the keyboard, and
+    nothing is journaled. Device registers report what the device last stored in them.
+    */
+    #[inline]
+    pub fn peek(&self, index: u16) -> u16 {
+        self.memory[index as usize]
+    }
+
+    /**
+    The words from `start` to `end` inclusive, see `peek`. Empty if `start` is after `end`.
+    */
+    pub fn peek_range(&self, start: u16, end: u16) -> Vec<u16> {
+        (start..=end).map(|index| self.peek(index)).collect()
+    }
+
+    /**
+    Every stored word, without triggering any device reads.
+    */
+    pub fn contents(&self) -> &[u16] {
+        &self.memory
+    }
+
+    /**
+    Replaces the whole memory, e.g. when restoring a snapshot. `words` must hold `MEMORY_MAX` words.
+    */
+    pub fn restore(&mut self, words: &[u16]) {
+        self.memory.copy_from_slice(words);
+        if let Some(cache) = &mut self.decode_cache {
+            cache.clear();
+        }
+        if let Some(watch) = &mut self.watch {
+            let watched = (0..=u16::MAX).filter(|&index| watch.watched[index as usize]);
+            watch.written.extend(watched);
+        }
+    }
+
+    /**
+    Starts recording every read and write into a fresh journal, discarding any previous one.
+    */
+    pub fn start_journal(&mut self) {
+        self.journal = Some(Vec::new());
+    }
+
+    /**
+    Stops recording and returns the accesses made since `start_journal`, in order.
+    */
+    pub fn take_journal(&mut self) -> Vec<MemoryAccess> {
+        self.journal.take().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_journal_records_accesses_in_order() {
+        let mut memory = Memory::empty();
+
+        memory.write(0x3000, 0x1);
+        memory.start_journal();
+        memory.write(0x3000, 0x2);
+        memory.read(0x3000);
+
+        assert_eq!(
+            memory.take_journal(),
+            vec![
+                MemoryAccess::Write {
+                    address: 0x3000,
+                    previous: 0x1,
+                    value: 0x2
+                },
+                MemoryAccess::Read {
+                    address: 0x3000,
+                    value: 0x2
+                },
+            ]
+        );
+
+        memory.read(0x3000);
+        assert!(memory.take_journal().is_empty());
+    }
+
+    #[test]
+    fn test_peek_has_no_side_effects() {
+        let mut memory = Memory::empty();
+        memory.keyboard = Keyboard::from_bytes(b"a");
+        memory.write(0x3000, 0x1234);
+        memory.start_journal();
+
+        assert_eq!(memory.peek(MemoryMappedRegister::MR_KBSR as u16), 0);
+        assert_eq!(memory.keyboard.pending.len(), 1);
+        assert_eq!(memory.peek_range(0x2FFF, 0x3001), vec![0, 0x1234, 0]);
+        assert_eq!(memory.peek(0xFFFF), 0);
+        assert!(memory.peek_range(0xFFFF, 0x0000).is_empty());
+        assert!(memory.take_journal().is_empty());
+
+        memory.read(MemoryMappedRegister::MR_KBSR as u16);
+        assert_eq!(memory.peek(MemoryMappedRegister::MR_KBSR as u16), 1 << 15);
+        assert_eq!(
+            memory.peek(MemoryMappedRegister::MR_KBDR as u16),
+            b'a' as u16
+        );
+    }
+
+    #[test]
+    fn test_watched_writes_are_reported() {
+        let mut memory = Memory::empty();
+        memory.write(0x3000, 1);
+        assert!(!memory.watched_written());
+
+        memory.watch_writes(0x3000);
+        memory.watch_writes(0x3002);
+        memory.write(0x3001, 2);
+        assert!(!memory.watched_written());
+        memory.write(0x3002, 3);
+        memory.import_raw(0x3000, &[0, 4]).unwrap();
+        assert!(memory.watched_written());
+        assert_eq!(memory.take_watched_writes(), vec![0x3002, 0x3000]);
+        assert!(!memory.watched_written());
+
+        memory.restore(&vec![0; MEMORY_MAX]);
+        assert_eq!(memory.take_watched_writes(), vec![0x3000, 0x3002]);
     }
 }
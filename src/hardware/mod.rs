@@ -1,3 +1,4 @@
+pub mod console;
 pub mod instructions;
 pub mod memory;
 pub mod registers;
@@ -0,0 +1,282 @@
+use std::fmt::Write;
+
+use serde::{Deserialize, Serialize};
+
+use crate::hardware::instructions::jmp::RET;
+use crate::hardware::instructions::Instructions;
+
+use super::symbols::SymbolTable;
+
+const MAX_DEPTH: usize = 1 << 12;
+const JSR: u16 = Instructions::JSR as u16;
+const JMP: u16 = Instructions::JMP as u16;
+
+/**
+A subroutine call: where the JSR/JSRR was and where it went.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CallFrame {
+    pub call_site: u16,
+    pub entry: u16,
+}
+
+impl CallFrame {
+    pub fn return_address(&self) -> u16 {
+        self.call_site.wrapping_add(1)
+    }
+}
+
+/**
+How one instruction changed the call stack, so stepping back over it can restore the stack.
+*/
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum CallChange {
+    #[default]
+    None,
+    Entered,
+    /// The frames that were popped, outermost first.
+    Returned(Vec<CallFrame>),
+}
+
+/**
+A shadow call stack maintained from JSR/JSRR and RET.
+
+A RET pops back to the innermost frame whose return address it jumps to, so frames of routines that were left
+some other way are dropped then. A RET to anywhere else (e.g. a computed return) leaves the stack alone. Runaway
+recursion only keeps the innermost frames.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct CallStack {
+    frames: Vec<CallFrame>,
+}
+
+impl CallStack {
+    pub fn new() -> CallStack {
+        CallStack::default()
+    }
+
+    pub fn from_frames(frames: Vec<CallFrame>) -> CallStack {
+        CallStack { frames }
+    }
+
+    /**
+    Accounts one executed instruction. `next_pc` is the program counter after it ran.
+    */
+    pub fn record(&mut self, pc: u16, instruction: u16, next_pc: u16) -> CallChange {
+        match instruction >> 12 {
+            JSR => {
+                self.enter(pc, next_pc);
+                CallChange::Entered
+            }
+            JMP if instruction == RET => match self.returning_frame(next_pc) {
+                Some(index) => CallChange::Returned(self.frames.split_off(index)),
+                None => CallChange::None,
+            },
+            _ => CallChange::None,
+        }
+    }
+
+    /**
+    Like `record`, for when the change never needs to be undone. This runs for every executed instruction.
+    */
+    #[inline]
+    pub fn follow(&mut self, pc: u16, instruction: u16, next_pc: u16) {
+        match instruction >> 12 {
+            JSR => self.enter(pc, next_pc),
+            JMP if instruction == RET => {
+                if let Some(index) = self.returning_frame(next_pc) {
+                    self.frames.truncate(index);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn enter(&mut self, call_site: u16, entry: u16) {
+        if self.frames.len() == MAX_DEPTH {
+            self.frames.remove(0);
+        }
+        self.frames.push(CallFrame { call_site, entry });
+    }
+
+    /**
+    The innermost frame a RET to `next_pc` leaves, if any.
+    */
+    fn returning_frame(&self, next_pc: u16) -> Option<usize> {
+        self.frames
+            .iter()
+            .rposition(|frame| frame.return_address() == next_pc)
+    }
+
+    pub fn undo(&mut self, change: CallChange) {
+        match change {
+            CallChange::None => {}
+            CallChange::Entered => {
+                self.frames.pop();
+            }
+            CallChange::Returned(frames) => self.frames.extend(frames),
+        }
+    }
+
+    /**
+    The active calls, outermost first.
+    */
+    pub fn frames(&self) -> &[CallFrame] {
+        &self.frames
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    /**
+    Formats the stack innermost first, starting at `pc` and continuing with the call sites:
+
+    ```text
+    #0   x3012 PRINT+2
+    #1   x3004 MAIN+3
+    ```
+    */
+    pub fn backtrace(&self, pc: u16, symbols: &SymbolTable) -> String {
+        let addresses = std::iter::once(pc).chain(self.frames.iter().rev().map(|f| f.call_site));
+
+        let mut backtrace = String::new();
+        for (index, address) in addresses.enumerate() {
+            let line = format!(
+                "#{:<3} x{:04X} {}",
+                index,
+                address,
+                symbols.label(address).unwrap_or_default()
+            );
+            writeln!(backtrace, "{}", line.trim_end()).unwrap();
+        }
+        backtrace
+    }
+}
+
+/**
+A stack frame found by following R5 under the LC-3 C calling convention: the frame pointer points at the first
+local, with the caller's frame pointer at `R5 + 1` and the return address at `R5 + 2`.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackFrame {
+    pub frame_pointer: u16,
+    pub return_address: u16,
+}
+
+/**
+Walks the frame pointer chain starting at `r5`. The stack grows downwards, so the walk stops at the first
+dynamic link that does not point to a higher address.
+*/
+pub fn walk_frame_pointers(memory: &[u16], r5: u16) -> Vec<StackFrame> {
+    let word = |address: u16| memory.get(address as usize).copied();
+
+    let mut frames = Vec::new();
+    let mut frame_pointer = r5;
+    while frame_pointer != 0 && frames.len() < MAX_DEPTH {
+        let (Some(link), Some(return_address)) = (
+            word(frame_pointer.wrapping_add(1)),
+            word(frame_pointer.wrapping_add(2)),
+        ) else {
+            break;
+        };
+        frames.push(StackFrame {
+            frame_pointer,
+            return_address,
+        });
+        if link <= frame_pointer {
+            break;
+        }
+        frame_pointer = link;
+    }
+    frames
+}
+
+/**
+Formats frames found by `walk_frame_pointers`, one per line.
+*/
+pub fn format_frames(frames: &[StackFrame], symbols: &SymbolTable) -> String {
+    let mut output = String::new();
+    for (index, frame) in frames.iter().enumerate() {
+        let line = format!(
+            "#{:<3} R5=x{:04X} returns to x{:04X} {}",
+            index,
+            frame.frame_pointer,
+            frame.return_address,
+            symbols.label(frame.return_address).unwrap_or_default()
+        );
+        writeln!(output, "{}", line.trim_end()).unwrap();
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_shadow_stack_follows_calls_and_returns() {
+        let mut stack = CallStack::new();
+        let jsr = 0b0100_1_00000001111;
+
+        // x3000 calls x3010, which calls x3020, which returns straight to x3001
+        assert_eq!(stack.record(0x3000, jsr, 0x3010), CallChange::Entered);
+        assert_eq!(stack.record(0x3010, jsr, 0x3020), CallChange::Entered);
+
+        let mut symbols = SymbolTable::empty();
+        symbols.insert("MAIN", 0x3000);
+        symbols.insert("OUTER", 0x3010);
+        let backtrace = stack.backtrace(0x3022, &symbols);
+        assert_eq!(
+            backtrace,
+            "#0   x3022 OUTER+18\n#1   x3010 OUTER\n#2   x3000 MAIN\n"
+        );
+
+        let change = stack.record(0x3022, RET, 0x3001);
+        assert_eq!(change, CallChange::Returned(stack_frames()));
+        assert!(stack.frames().is_empty());
+
+        stack.undo(change);
+        assert_eq!(stack.frames(), stack_frames().as_slice());
+        assert_eq!(stack.record(0x3022, RET, 0x4000), CallChange::None);
+    }
+
+    fn stack_frames() -> Vec<CallFrame> {
+        vec![
+            CallFrame {
+                call_site: 0x3000,
+                entry: 0x3010,
+            },
+            CallFrame {
+                call_site: 0x3010,
+                entry: 0x3020,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_walk_frame_pointers() {
+        let mut memory = vec![0; 0x10000];
+        // inner frame at xEFF0 links to the outer frame at xEFF8, which links to nothing above it
+        memory[0xEFF1] = 0xEFF8;
+        memory[0xEFF2] = 0x3042;
+        memory[0xEFF9] = 0x0000;
+        memory[0xEFFA] = 0x3005;
+
+        let frames = walk_frame_pointers(&memory, 0xEFF0);
+        assert_eq!(
+            frames,
+            vec![
+                StackFrame {
+                    frame_pointer: 0xEFF0,
+                    return_address: 0x3042
+                },
+                StackFrame {
+                    frame_pointer: 0xEFF8,
+                    return_address: 0x3005
+                }
+            ]
+        );
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use crate::hardware::instructions::Instructions;
+
+use super::listing::Listing;
+
+/**
+How often a conditional branch went each way.
+*/
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BranchCounts {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+/**
+Records which addresses were executed and which way every `BR` went.
+*/
+pub struct Coverage {
+    hits: Vec<u64>,
+    branches: BTreeMap<u16, BranchCounts>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Coverage::new()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage {
+            hits: vec![0; 1 << 16],
+            branches: BTreeMap::new(),
+        }
+    }
+
+    /**
+    Accounts one executed instruction. `cond` is the COND register when it ran, which a `BR` does not change.
+    */
+    pub fn record(&mut self, pc: u16, instruction: u16, cond: u16) {
+        self.hits[pc as usize] += 1;
+
+        if let Ok(Instructions::BR) = Instructions::try_from(instruction >> 12) {
+            let counts = self.branches.entry(pc).or_default();
+            if (instruction >> 9) & 0x7 & cond != 0 {
+                counts.taken += 1;
+            } else {
+                counts.not_taken += 1;
+            }
+        }
+    }
+
+    pub fn hits(&self, address: u16) -> u64 {
+        self.hits[address as usize]
+    }
+
+    pub fn branch(&self, address: u16) -> Option<BranchCounts> {
+        self.branches.get(&address).copied()
+    }
+
+    /**
+    Writes the coverage of `image` (address and word of everything that was loaded) in lcov's tracefile format.
+
+    Addresses the listing knows are reported against their assembly source line. The rest are reported against
+    `object`, using the address itself as the line number. Only `BR` instructions that can go either way count as
+    branches: `BRnzp` is always taken and a `BR` without condition codes is usually data.
+    */
+    pub fn lcov(&self, image: &[(u16, u16)], listing: &Listing, object: &str) -> String {
+        // file -> line -> (hits, branch outcomes if the line holds a conditional branch)
+        let mut files: BTreeMap<PathBuf, BTreeMap<usize, (u64, Option<BranchCounts>)>> =
+            BTreeMap::new();
+
+        for &(address, word) in image {
+            let (file, line) = match listing.line_at(address) {
+                Some(line) => (listing.file(line).to_path_buf(), line.line),
+                None => (PathBuf::from(object), address as usize),
+            };
+            let entry = files.entry(file).or_default().entry(line).or_default();
+            // a line spanning several words, e.g. a .STRINGZ, counts as executed if any of them was
+            entry.0 = entry.0.max(self.hits(address));
+
+            if is_conditional_branch(word) {
+                let counts = self.branch(address).unwrap_or_default();
+                let branch = entry.1.get_or_insert_with(BranchCounts::default);
+                branch.taken += counts.taken;
+                branch.not_taken += counts.not_taken;
+            }
+        }
+
+        let mut report = String::new();
+        for (file, lines) in files {
+            writeln!(report, "TN:").unwrap();
+            writeln!(report, "SF:{}", file.display()).unwrap();
+
+            let (mut branches_found, mut branches_hit) = (0, 0);
+            for (&line, &(hits, branch)) in &lines {
+                if let Some(branch) = branch {
+                    let outcome = |count: u64| match hits {
+                        0 => String::from("-"),
+                        _ => count.to_string(),
+                    };
+                    writeln!(report, "BRDA:{},0,0,{}", line, outcome(branch.taken)).unwrap();
+                    writeln!(report, "BRDA:{},0,1,{}", line, outcome(branch.not_taken)).unwrap();
+                    branches_found += 2;
+                    branches_hit += (branch.taken > 0) as u32 + (branch.not_taken > 0) as u32;
+                }
+            }
+            writeln!(report, "BRF:{}", branches_found).unwrap();
+            writeln!(report, "BRH:{}", branches_hit).unwrap();
+
+            for (&line, &(hits, _)) in &lines {
+                writeln!(report, "DA:{},{}", line, hits).unwrap();
+            }
+            writeln!(report, "LF:{}", lines.len()).unwrap();
+            writeln!(
+                report,
+                "LH:{}",
+                lines.values().filter(|(hits, _)| *hits > 0).count()
+            )
+            .unwrap();
+            writeln!(report, "end_of_record").unwrap();
+        }
+        report
+    }
+}
+
+fn is_conditional_branch(word: u16) -> bool {
+    let conditions = (word >> 9) & 0x7;
+    word >> 12 == 0 && conditions != 0 && conditions != 0x7
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn test_branch_outcomes() {
+        let mut coverage = Coverage::new();
+        let brz = 0b0000_010_000000010;
+
+        // cond is P, then Z
+        coverage.record(0x3000, brz, 0b001);
+        coverage.record(0x3000, brz, 0b010);
+        coverage.record(0x3000, brz, 0b010);
+
+        assert_eq!(coverage.hits(0x3000), 3);
+        assert_eq!(
+            coverage.branch(0x3000),
+            Some(BranchCounts {
+                taken: 2,
+                not_taken: 1
+            })
+        );
+        assert_eq!(coverage.branch(0x3001), None);
+    }
+
+    #[test]
+    fn test_lcov_maps_to_source_lines() {
+        let file = "  (0000) 3000  0011000000000000 (   1)                 .ORIG x3000\n\
+                    \x20 (3000) 0402  0000010000000010 (   2)                 BRz DONE\n\
+                    \x20 (3001) F021  1111000000100001 (   3)                 OUT\n\
+                    \x20 (3002) F025  1111000000100101 (   4) DONE            HALT\n";
+        let listing = Listing::parse(file.as_bytes(), Path::new("done.asm")).unwrap();
+        let image = [
+            (0x3000, 0x0402),
+            (0x3001, 0xF021),
+            (0x3002, 0xF025),
+            (0x3003, 0),
+        ];
+
+        let mut coverage = Coverage::new();
+        coverage.record(0x3000, 0x0402, 0b010);
+        coverage.record(0x3002, 0xF025, 0b010);
+
+        let report = coverage.lcov(&image, &listing, "done.obj");
+        assert!(report.contains("SF:done.asm\n"));
+        assert!(report.contains("BRDA:2,0,0,1\nBRDA:2,0,1,0\nBRF:2\nBRH:1\n"));
+        assert!(report.contains("DA:2,1\nDA:3,0\nDA:4,1\nLF:3\nLH:2\n"));
+        // not in the listing, so reported by address
+        assert!(report.contains("SF:done.obj\n"));
+        assert!(report.contains("DA:12291,0\n"));
+    }
+}
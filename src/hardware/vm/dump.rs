@@ -0,0 +1,201 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use serde::{Deserialize, Serialize};
+
+use super::callstack::CallFrame;
+use super::snapshot::{Snapshot, SnapshotError};
+use super::trace::TraceRecord;
+
+const MAGIC: &[u8; 4] = b"LC3D";
+const VERSION: u16 = 1;
+
+/**
+Everything known about a run when it stopped, for post-mortem inspection.
+
+Layout (all values big-endian):
+
+|field|size|
+|---|---|
+| magic `LC3D` | 4 bytes |
+| version | u16 |
+| snapshot length | u32 |
+| machine state, see `Snapshot` | length bytes |
+| info length | u32 |
+| `DumpInfo` as JSON | length bytes |
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrashDump {
+    pub snapshot: Snapshot,
+    pub info: DumpInfo,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DumpInfo {
+    /// The object files that were loaded.
+    pub programs: Vec<String>,
+    pub reason: String,
+    /// Address of the instruction that stopped the machine.
+    pub address: u16,
+    /// The VM has no supervisor mode or interrupt priorities, so this is user mode, priority 0 and the condition codes.
+    pub psr: u16,
+    /// The shadow call stack, outermost call first.
+    pub call_stack: Vec<CallFrame>,
+    /// The last executed instructions, oldest first.
+    pub trace: Vec<TraceRecord>,
+}
+
+#[derive(Debug)]
+pub enum DumpError {
+    Io(io::Error),
+    NotADump,
+    UnsupportedVersion(u16),
+    Snapshot(SnapshotError),
+    Info(serde_json::Error),
+}
+
+impl fmt::Display for DumpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DumpError::Io(error) => write!(f, "failed to access dump: {}", error),
+            DumpError::NotADump => write!(f, "not a crash dump"),
+            DumpError::UnsupportedVersion(version) => {
+                write!(f, "unsupported crash dump version {}", version)
+            }
+            DumpError::Snapshot(error) => write!(f, "crash dump is damaged: {}", error),
+            DumpError::Info(error) => write!(f, "crash dump is damaged: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for DumpError {}
+
+impl From<io::Error> for DumpError {
+    fn from(error: io::Error) -> Self {
+        if error.kind() == io::ErrorKind::UnexpectedEof {
+            DumpError::NotADump
+        } else {
+            DumpError::Io(error)
+        }
+    }
+}
+
+impl CrashDump {
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut snapshot = Vec::new();
+        self.snapshot.write_to(&mut snapshot)?;
+        let info = serde_json::to_vec(&self.info).expect("dump info always serializes");
+
+        writer.write_all(MAGIC)?;
+        writer.write_u16::<BigEndian>(VERSION)?;
+        writer.write_u32::<BigEndian>(snapshot.len() as u32)?;
+        writer.write_all(&snapshot)?;
+        writer.write_u32::<BigEndian>(info.len() as u32)?;
+        writer.write_all(&info)
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<CrashDump, DumpError> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(DumpError::NotADump);
+        }
+        let version = reader.read_u16::<BigEndian>()?;
+        if version != VERSION {
+            return Err(DumpError::UnsupportedVersion(version));
+        }
+
+        let snapshot = read_section(reader)?;
+        let snapshot =
+            Snapshot::read_from(&mut snapshot.as_slice()).map_err(DumpError::Snapshot)?;
+
+        let info = read_section(reader)?;
+        let info = serde_json::from_slice(&info).map_err(DumpError::Info)?;
+
+        Ok(CrashDump { snapshot, info })
+    }
+}
+
+/**
+Reads a length and a section of that many bytes. The buffer only grows with what the reader delivers, so a damaged
+length cannot allocate more than the file holds.
+*/
+fn read_section<R: Read>(reader: &mut R) -> Result<Vec<u8>, DumpError> {
+    let length = reader.read_u32::<BigEndian>()? as usize;
+    let mut section = Vec::new();
+    reader.take(length as u64).read_to_end(&mut section)?;
+    if section.len() != length {
+        return Err(DumpError::NotADump);
+    }
+    Ok(section)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::hardware::vm::{StopReason, VirtualMachine};
+
+    #[test]
+    fn test_dump_round_trip() {
+        let mut vm = VirtualMachine::create();
+        vm.keep_recent_steps(2);
+        // JSR #1 ; HALT ; ADD R1, R1, #1 ; RES
+        vm.memory.write(0x3000, 0b0100_1_00000000001);
+        vm.memory.write(0x3001, 0xF025);
+        vm.memory.write(0x3002, 0b0001_001_001_1_00001);
+        vm.memory.write(0x3003, 0xD000);
+        let reason = vm.execute_program();
+
+        let dump = vm.crash_dump(reason);
+        assert_eq!(dump.info.address, 0x3003);
+        assert_eq!(dump.info.call_stack.len(), 1);
+        assert_eq!(dump.info.trace.len(), 2);
+        assert_eq!(dump.info.trace[1].opcode, "RES");
+
+        let mut file = Vec::new();
+        dump.write_to(&mut file).unwrap();
+        assert_eq!(CrashDump::read_from(&mut file.as_slice()).unwrap(), dump);
+
+        file[0] = b'X';
+        assert!(matches!(
+            CrashDump::read_from(&mut file.as_slice()),
+            Err(DumpError::NotADump)
+        ));
+    }
+
+    #[test]
+    fn test_truncated_and_oversized_dumps_are_rejected() {
+        let vm = VirtualMachine::create();
+        let mut file = Vec::new();
+        vm.crash_dump(StopReason::Halted)
+            .write_to(&mut file)
+            .unwrap();
+
+        for length in [4, 10, 100, file.len() - 1] {
+            assert!(
+                matches!(
+                    CrashDump::read_from(&mut &file[..length]),
+                    Err(DumpError::NotADump)
+                ),
+                "{}",
+                length
+            );
+        }
+
+        // a section length far beyond the end of the file
+        let mut oversized = b"LC3D".to_vec();
+        oversized.extend_from_slice(&VERSION.to_be_bytes());
+        oversized.extend_from_slice(&[0xFF; 4]);
+        assert!(matches!(
+            CrashDump::read_from(&mut oversized.as_slice()),
+            Err(DumpError::NotADump)
+        ));
+        file[6..10].copy_from_slice(&[0xFF; 4]);
+        assert!(matches!(
+            CrashDump::read_from(&mut file.as_slice()),
+            Err(DumpError::NotADump)
+        ));
+    }
+}
@@ -0,0 +1,49 @@
+use std::fmt;
+use std::str::FromStr;
+
+/**
+How the VM executes instructions. All engines have the same observable behaviour and only differ in speed.
+
+- `Interpreter`: decodes every instruction as it runs.
+- `Cached`: keeps decoded instructions per address and decodes again only after the address is written.
+- `Compiled`: translates the reachable code into closures before running it, see `Translation`, and interprets
+  the rest.
+*/
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Engine {
+    #[default]
+    Interpreter,
+    Cached,
+    Compiled,
+}
+
+impl Engine {
+    pub const ALL: [Engine; 3] = [Engine::Interpreter, Engine::Cached, Engine::Compiled];
+}
+
+impl fmt::Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Engine::Interpreter => "interpreter",
+            Engine::Cached => "cached",
+            Engine::Compiled => "compiled",
+        };
+        f.pad(name)
+    }
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Engine, String> {
+        Engine::ALL
+            .into_iter()
+            .find(|engine| engine.to_string() == name)
+            .ok_or_else(|| {
+                format!(
+                    "unknown engine '{}', expected interpreter, cached or compiled",
+                    name
+                )
+            })
+    }
+}
@@ -0,0 +1,256 @@
+use std::fmt;
+use std::path::Path;
+
+use super::loader::{LoadError, ObjectFile};
+
+/**
+Header of an lc3tools object file, followed by two version bytes.
+*/
+const LC3TOOLS_MAGIC: &[u8] = &[0x1C, 0x30, 0x15, 0xC0, 0x01];
+const LC3TOOLS_VERSION: &[u8] = &[0x01, 0x01];
+
+/**
+The image formats the loader understands.
+
+- `Raw`: the origin followed by the words, all big-endian, as written by `lc3as` to `.obj` files.
+- `Hex`: ASCII, one word per line as hex digits, the origin first (`lc3as` `.hex` files).
+- `Bin`: ASCII, one word per line as 16 binary digits, the origin first (`lc3as` `.bin` files).
+- `Lc3Tools`: the lc3tools object format. After the header, every entry is a little-endian u16 value, a byte that
+  is 1 when the value is an origin, and the assembly source line as a little-endian u32 length and the bytes.
+  One file may hold several sections, each starting at an origin entry.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectFormat {
+    Raw,
+    Hex,
+    Bin,
+    Lc3Tools,
+}
+
+impl fmt::Display for ObjectFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ObjectFormat::Raw => "raw",
+            ObjectFormat::Hex => "hex",
+            ObjectFormat::Bin => "bin",
+            ObjectFormat::Lc3Tools => "lc3tools",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl ObjectFormat {
+    pub fn from_name(name: &str) -> Option<ObjectFormat> {
+        match name {
+            "raw" | "obj" => Some(ObjectFormat::Raw),
+            "hex" => Some(ObjectFormat::Hex),
+            "bin" => Some(ObjectFormat::Bin),
+            "lc3tools" => Some(ObjectFormat::Lc3Tools),
+            _ => None,
+        }
+    }
+
+    /**
+    Works out the format of a file from its header, its extension and, failing those, whether it is text.
+    */
+    pub fn detect(path: &Path, bytes: &[u8]) -> ObjectFormat {
+        if bytes.starts_with(LC3TOOLS_MAGIC) {
+            return ObjectFormat::Lc3Tools;
+        }
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("hex") => return ObjectFormat::Hex,
+            Some("bin") => return ObjectFormat::Bin,
+            Some("obj") => return ObjectFormat::Raw,
+            _ => {}
+        }
+
+        let Ok(text) = std::str::from_utf8(bytes) else {
+            return ObjectFormat::Raw;
+        };
+        let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+        if lines.clone().next().is_none() {
+            ObjectFormat::Raw
+        } else if lines
+            .clone()
+            .all(|line| line.len() == 16 && line.chars().all(|c| c == '0' || c == '1'))
+        {
+            ObjectFormat::Bin
+        } else if lines.all(|line| line.len() <= 4 && line.chars().all(|c| c.is_ascii_hexdigit())) {
+            ObjectFormat::Hex
+        } else {
+            ObjectFormat::Raw
+        }
+    }
+
+    /**
+    The sections of an image in this format. Only lc3tools files can hold more than one.
+    */
+    pub fn decode(self, bytes: &[u8]) -> Result<Vec<ObjectFile>, LoadError> {
+        match self {
+            ObjectFormat::Raw => Ok(vec![ObjectFile::parse(bytes)?]),
+            ObjectFormat::Hex => decode_text(bytes, 16),
+            ObjectFormat::Bin => decode_text(bytes, 2),
+            ObjectFormat::Lc3Tools => decode_lc3tools(bytes),
+        }
+    }
+
+    pub fn encode(self, objects: &[ObjectFile]) -> Result<Vec<u8>, LoadError> {
+        if self != ObjectFormat::Lc3Tools && objects.len() != 1 {
+            return Err(LoadError::SectionCount {
+                format: self,
+                sections: objects.len(),
+            });
+        }
+
+        let mut bytes = Vec::new();
+        match self {
+            ObjectFormat::Raw => {
+                for word in objects[0].all_words() {
+                    bytes.extend(word.to_be_bytes());
+                }
+            }
+            ObjectFormat::Hex => {
+                for word in objects[0].all_words() {
+                    bytes.extend(format!("{:04X}\n", word).bytes());
+                }
+            }
+            ObjectFormat::Bin => {
+                for word in objects[0].all_words() {
+                    bytes.extend(format!("{:016b}\n", word).bytes());
+                }
+            }
+            ObjectFormat::Lc3Tools => {
+                bytes.extend(LC3TOOLS_MAGIC);
+                bytes.extend(LC3TOOLS_VERSION);
+                for object in objects {
+                    for (index, word) in object.all_words().enumerate() {
+                        bytes.extend(word.to_le_bytes());
+                        bytes.push((index == 0) as u8);
+                        bytes.extend(0u32.to_le_bytes());
+                    }
+                }
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+impl ObjectFile {
+    fn all_words(&self) -> impl Iterator<Item = u16> + '_ {
+        std::iter::once(self.origin).chain(self.words.iter().copied())
+    }
+}
+
+fn decode_text(bytes: &[u8], radix: u32) -> Result<Vec<ObjectFile>, LoadError> {
+    let text = String::from_utf8_lossy(bytes);
+    let mut words = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        match u16::from_str_radix(line, radix) {
+            Ok(word) => words.push(word),
+            Err(_) => {
+                return Err(LoadError::InvalidLine {
+                    line: index + 1,
+                    text: line.to_string(),
+                })
+            }
+        }
+    }
+
+    match words.split_first() {
+        Some((&origin, words)) => Ok(vec![ObjectFile {
+            origin,
+            words: words.to_vec(),
+        }]),
+        None => Err(LoadError::MissingOrigin),
+    }
+}
+
+fn decode_lc3tools(bytes: &[u8]) -> Result<Vec<ObjectFile>, LoadError> {
+    let header = LC3TOOLS_MAGIC.len() + LC3TOOLS_VERSION.len();
+    let mut rest = bytes.get(header..).ok_or(LoadError::Truncated)?;
+
+    let mut objects: Vec<ObjectFile> = Vec::new();
+    while !rest.is_empty() {
+        let entry = rest.get(..7).ok_or(LoadError::Truncated)?;
+        let value = u16::from_le_bytes([entry[0], entry[1]]);
+        let is_origin = entry[2] != 0;
+        let line_length = u32::from_le_bytes([entry[3], entry[4], entry[5], entry[6]]) as usize;
+        rest = rest.get(7 + line_length..).ok_or(LoadError::Truncated)?;
+
+        match objects.last_mut() {
+            _ if is_origin => objects.push(ObjectFile {
+                origin: value,
+                words: Vec::new(),
+            }),
+            Some(object) => object.words.push(value),
+            None => return Err(LoadError::MissingOrigin),
+        }
+    }
+
+    if objects.is_empty() {
+        return Err(LoadError::MissingOrigin);
+    }
+    Ok(objects)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn object() -> ObjectFile {
+        ObjectFile {
+            origin: 0x3000,
+            words: vec![0xF025, 0x1234],
+        }
+    }
+
+    #[test]
+    fn test_formats_round_trip() {
+        for format in [
+            ObjectFormat::Raw,
+            ObjectFormat::Hex,
+            ObjectFormat::Bin,
+            ObjectFormat::Lc3Tools,
+        ] {
+            let bytes = format.encode(&[object()]).unwrap();
+            let detected = ObjectFormat::detect(Path::new("image"), &bytes);
+
+            assert_eq!(detected, format);
+            assert_eq!(detected.decode(&bytes).unwrap(), vec![object()]);
+        }
+
+        let hex = ObjectFormat::Hex.encode(&[object()]).unwrap();
+        assert_eq!(hex, b"3000\nF025\n1234\n");
+    }
+
+    #[test]
+    fn test_lc3tools_sections() {
+        let second = ObjectFile {
+            origin: 0x4000,
+            words: vec![0xBEEF],
+        };
+        let objects = vec![object(), second];
+        let bytes = ObjectFormat::Lc3Tools.encode(&objects).unwrap();
+        assert_eq!(ObjectFormat::Lc3Tools.decode(&bytes).unwrap(), objects);
+
+        assert!(matches!(
+            ObjectFormat::Hex.encode(&objects),
+            Err(LoadError::SectionCount { sections: 2, .. })
+        ));
+        assert!(matches!(
+            ObjectFormat::Lc3Tools.decode(&bytes[..bytes.len() - 1]),
+            Err(LoadError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn test_invalid_text_line() {
+        let error = ObjectFormat::Hex.decode(b"3000\nF025\nxyz\n").unwrap_err();
+        assert!(matches!(error, LoadError::InvalidLine { line: 3, .. }));
+    }
+}
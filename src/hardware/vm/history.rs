@@ -0,0 +1,136 @@
+use std::collections::VecDeque;
+
+use crate::hardware::memory::MemoryAccess;
+use crate::hardware::registers::Registers;
+
+use super::callstack::CallChange;
+use super::Step;
+
+/**
+What it takes to undo one executed instruction: the registers before it ran, the previous value of every memory word it overwrote
+and how it changed the call stack.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UndoEntry {
+    pub number: u64,
+    pub registers: Registers,
+    pub overwritten: Vec<(u16, u16)>,
+    pub call: CallChange,
+}
+
+impl UndoEntry {
+    pub fn from_step(step: &Step) -> UndoEntry {
+        let overwritten = step
+            .accesses
+            .iter()
+            .filter_map(|access| match *access {
+                MemoryAccess::Write {
+                    address, previous, ..
+                } => Some((address, previous)),
+                MemoryAccess::Read { .. } => None,
+            })
+            .collect();
+
+        UndoEntry {
+            number: step.number,
+            registers: step.before,
+            overwritten,
+            call: step.call.clone(),
+        }
+    }
+
+    /**
+    Whether undoing this entry changes `address`.
+    */
+    pub fn writes(&self, address: u16) -> bool {
+        self.overwritten
+            .iter()
+            .any(|&(written, _)| written == address)
+    }
+}
+
+/**
+A bounded ring buffer of undo entries. Once full, the oldest step is forgotten.
+*/
+pub struct History {
+    capacity: usize,
+    entries: VecDeque<UndoEntry>,
+}
+
+impl History {
+    pub fn with_capacity(capacity: usize) -> History {
+        History {
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn record(&mut self, step: &Step) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(UndoEntry::from_step(step));
+    }
+
+    pub fn pop(&mut self) -> Option<UndoEntry> {
+        self.entries.pop_back()
+    }
+
+    /**
+    The oldest step number that can still be returned to.
+    */
+    pub fn earliest(&self) -> Option<u64> {
+        self.entries.front().map(|entry| entry.number)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn step(number: u64) -> Step {
+        Step {
+            number,
+            pc: 0x3000,
+            instruction: 0,
+            before: Registers::initial(),
+            after: Registers::initial(),
+            accesses: vec![MemoryAccess::Write {
+                address: 0x4000,
+                previous: number as u16,
+                value: 0,
+            }],
+            call: CallChange::None,
+            stop: None,
+        }
+    }
+
+    #[test]
+    fn test_history_drops_oldest_entries() {
+        let mut history = History::with_capacity(2);
+
+        history.record(&step(0));
+        history.record(&step(1));
+        history.record(&step(2));
+
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.earliest(), Some(1));
+        assert_eq!(history.pop().unwrap().overwritten, vec![(0x4000, 2)]);
+    }
+}
@@ -0,0 +1,19 @@
+use std::time::Duration;
+
+/**
+Resource limits for a run, e.g. of an untrusted program. `None` means unlimited.
+*/
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Total executed instructions, counted from the start of the machine (including snapshot history).
+    pub instructions: Option<u64>,
+    pub wall_time: Option<Duration>,
+    pub output_bytes: Option<u64>,
+    pub input_reads: Option<u64>,
+}
+
+impl Limits {
+    pub fn unlimited() -> Limits {
+        Limits::default()
+    }
+}
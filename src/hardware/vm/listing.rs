@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+/**
+Maps addresses to lines of assembly source, read from the listing the LC-3 assembler writes next to the object file:
+
+```text
+  (0000) 3000  0011000000000000 (   1)                 .ORIG x3000
+  (3000) E002  1110000000000010 (   2)                 LEA   R0 HELLO
+  (3001) F022  1111000000100010 (   3)                 PUTS
+```
+
+The source is assumed to be the `.asm` file with the same name as the listing.
+*/
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Listing {
+    files: Vec<PathBuf>,
+    lines: BTreeMap<u16, SourceLine>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceLine {
+    file: usize,
+    pub line: usize,
+}
+
+impl Listing {
+    pub fn empty() -> Listing {
+        Listing::default()
+    }
+
+    pub fn parse<R: BufRead>(reader: R, source: &Path) -> io::Result<Listing> {
+        let mut listing = Listing::empty();
+        listing.files.push(source.to_path_buf());
+
+        let mut previous = None;
+        for line in reader.lines() {
+            let line = line?;
+            if let Some((address, number, source)) = parse_line(&line) {
+                // .ORIG directives start a section but do not occupy memory
+                if is_orig(source) {
+                    previous = number;
+                    continue;
+                }
+                // words of a .STRINGZ or .BLKW after the first have no line number of their own
+                let Some(number) = number.or(previous) else {
+                    continue;
+                };
+                previous = Some(number);
+                listing.lines.insert(
+                    address,
+                    SourceLine {
+                        file: 0,
+                        line: number,
+                    },
+                );
+            }
+        }
+        Ok(listing)
+    }
+
+    /**
+    Loads the `.lst` file that belongs to `program`, if there is one.
+    */
+    pub fn for_program(program: &str) -> io::Result<Option<Listing>> {
+        let path = Path::new(program);
+        match File::open(path.with_extension("lst")) {
+            Ok(file) => Listing::parse(BufReader::new(file), &path.with_extension("asm")).map(Some),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    pub fn extend(&mut self, other: Listing) {
+        let offset = self.files.len();
+        self.files.extend(other.files);
+        for (address, line) in other.lines {
+            self.lines.insert(
+                address,
+                SourceLine {
+                    file: line.file + offset,
+                    line: line.line,
+                },
+            );
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    pub fn line_at(&self, address: u16) -> Option<SourceLine> {
+        self.lines.get(&address).copied()
+    }
+
+    pub fn file(&self, line: SourceLine) -> &Path {
+        &self.files[line.file]
+    }
+}
+
+/**
+Reads `(3000) E002  1110000000000010 (   2) ...` into the address, the source line number and the source text, if
+the line has them.
+*/
+fn parse_line(line: &str) -> Option<(u16, Option<usize>, &str)> {
+    let rest = line.trim_start().strip_prefix('(')?;
+    let (address, rest) = rest.split_once(')')?;
+    let address = u16::from_str_radix(address.trim(), 16).ok()?;
+    let Some((number, source)) = rest
+        .split_once('(')
+        .and_then(|(_, rest)| rest.split_once(')'))
+    else {
+        return Some((address, None, ""));
+    };
+    Some((address, number.trim().parse().ok(), source))
+}
+
+/**
+Whether the source is a `.ORIG` directive, possibly after a label.
+*/
+fn is_orig(source: &str) -> bool {
+    let code = source.split(';').next().unwrap_or_default();
+    code.split_whitespace()
+        .take(2)
+        .any(|word| word.eq_ignore_ascii_case(".ORIG"))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_listing() {
+        let file = "  (0000) 3000  0011000000000000 (   1)                 .ORIG x3000\n\
+                    \x20 (3000) E002  1110000000000010 (   2)                 LEA   R0 HELLO\n\
+                    \x20 (3001) F022  1111000000100010 (   3)                 PUTS\n\
+                    \x20 (3002) F025  1111000000100101 (   4)                 HALT\n\
+                    \x20 (3003) 0048  0000000001001000 (   5) HELLO           .STRINGZ \"Hi\"\n\
+                    \x20 (3004) 0069  0000000001101001\n";
+
+        let listing = Listing::parse(file.as_bytes(), Path::new("hello.asm")).unwrap();
+
+        assert_eq!(listing.line_at(0x3000).unwrap().line, 2);
+        assert_eq!(listing.line_at(0x3003).unwrap().line, 5);
+        assert_eq!(listing.line_at(0x3004).unwrap().line, 5);
+        assert_eq!(listing.line_at(0x3005), None);
+        let line = listing.line_at(0x3001).unwrap();
+        assert_eq!(listing.file(line), Path::new("hello.asm"));
+    }
+
+    #[test]
+    fn test_every_orig_section_is_skipped() {
+        let file = "  (0000) 3000  0011000000000000 (   1)                 .ORIG x3000
+                      (3000) F025  1111000000100101 (   2)                 HALT
+                      (0000) 4000  0100000000000000 (   4)                 .orig x4000
+                      (4000) 0007  0000000000000111 (   5) DATA            .FILL 7
+";
+
+        let listing = Listing::parse(file.as_bytes(), Path::new("two.asm")).unwrap();
+
+        assert_eq!(listing.line_at(0x0000), None);
+        assert_eq!(listing.line_at(0x3000).unwrap().line, 2);
+        assert_eq!(listing.line_at(0x4000).unwrap().line, 5);
+
+        let unnumbered = "  (3000) 3000  0011000000000000
+                            (3000) F025  1111000000100101 (   2)                 HALT
+";
+        let listing = Listing::parse(unnumbered.as_bytes(), Path::new("a.asm")).unwrap();
+        assert_eq!(listing.line_at(0x3000).unwrap().line, 2);
+    }
+}
@@ -0,0 +1,238 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use super::formats::ObjectFormat;
+
+/**
+The first address of the memory mapped device registers. Programs may not be loaded at or above it.
+*/
+pub const DEVICE_PAGE: u16 = 0xFE00;
+
+/**
+An LC-3 object file: the origin followed by the words to place there, all big-endian.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectFile {
+    pub origin: u16,
+    pub words: Vec<u16>,
+}
+
+/**
+Where an object file landed in memory.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadedImage {
+    pub origin: u16,
+    pub length: usize,
+}
+
+impl LoadedImage {
+    /**
+    The last address of the image. Only meaningful for images that are not empty.
+    */
+    pub fn end(&self) -> u16 {
+        self.origin.wrapping_add(self.length as u16).wrapping_sub(1)
+    }
+
+    pub fn contains(&self, address: u16) -> bool {
+        address >= self.origin && ((address - self.origin) as usize) < self.length
+    }
+
+    pub fn overlaps(&self, other: &LoadedImage) -> bool {
+        self.length > 0
+            && other.length > 0
+            && self.origin <= other.end()
+            && other.origin <= self.end()
+    }
+}
+
+impl fmt::Display for LoadedImage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.length == 0 {
+            write!(f, "nothing at x{:04X}", self.origin)
+        } else {
+            write!(
+                f,
+                "x{:04X}-x{:04X} ({} words)",
+                self.origin,
+                self.end(),
+                self.length
+            )
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    /// The file is shorter than the two byte origin.
+    MissingOrigin,
+    /// Object files hold whole 16 bit words.
+    OddLength {
+        bytes: usize,
+    },
+    /// A line of a text image that is not a word.
+    InvalidLine {
+        line: usize,
+        text: String,
+    },
+    /// An lc3tools object file that ends in the middle of an entry.
+    Truncated,
+    /// Only lc3tools object files can hold more than one section.
+    SectionCount {
+        format: ObjectFormat,
+        sections: usize,
+    },
+    /// The image runs past xFFFF.
+    WrapsAddressSpace {
+        origin: u16,
+        length: usize,
+    },
+    /// The image would overwrite the device registers at xFE00 and above.
+    DevicePage {
+        image: LoadedImage,
+    },
+    /// The image would overwrite a previously loaded one.
+    Overlap {
+        image: LoadedImage,
+        existing: LoadedImage,
+    },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io { path, error } => {
+                write!(f, "failed to read {}: {}", path.display(), error)
+            }
+            LoadError::MissingOrigin => write!(f, "object file is too short to hold an origin"),
+            LoadError::OddLength { bytes } => write!(
+                f,
+                "object file has {} bytes, which is not a whole number of words",
+                bytes
+            ),
+            LoadError::InvalidLine { line, text } => {
+                write!(f, "line {} is not a word: {}", line, text)
+            }
+            LoadError::Truncated => write!(f, "object file ends in the middle of an entry"),
+            LoadError::SectionCount { format, sections } => write!(
+                f,
+                "a {} image holds exactly one section, not {}",
+                format, sections
+            ),
+            LoadError::WrapsAddressSpace { origin, length } => write!(
+                f,
+                "{} words loaded at x{:04X} run past the end of memory",
+                length, origin
+            ),
+            LoadError::DevicePage { image } => write!(
+                f,
+                "image {} overlaps the device registers at x{:04X} and above",
+                image, DEVICE_PAGE
+            ),
+            LoadError::Overlap { image, existing } => write!(
+                f,
+                "image {} overlaps the already loaded image {}",
+                image, existing
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl ObjectFile {
+    pub fn parse(bytes: &[u8]) -> Result<ObjectFile, LoadError> {
+        if bytes.len() < 2 {
+            return Err(LoadError::MissingOrigin);
+        }
+        if !bytes.len().is_multiple_of(2) {
+            return Err(LoadError::OddLength { bytes: bytes.len() });
+        }
+
+        let mut words = bytes
+            .chunks_exact(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]));
+        let origin = words.next().expect("checked above");
+        Ok(ObjectFile {
+            origin,
+            words: words.collect(),
+        })
+    }
+
+    /**
+    Where the words will land, checked against the end of memory and the device page.
+    */
+    pub fn image(&self) -> Result<LoadedImage, LoadError> {
+        let image = LoadedImage {
+            origin: self.origin,
+            length: self.words.len(),
+        };
+        if self.origin as usize + self.words.len() > 1 << 16 {
+            return Err(LoadError::WrapsAddressSpace {
+                origin: image.origin,
+                length: image.length,
+            });
+        }
+        if image.length > 0 && image.end() >= DEVICE_PAGE {
+            return Err(LoadError::DevicePage { image });
+        }
+        Ok(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_object_file() {
+        let object = ObjectFile::parse(&[0x30, 0x00, 0xF0, 0x25, 0x12, 0x34]).unwrap();
+        assert_eq!(object.origin, 0x3000);
+        assert_eq!(object.words, vec![0xF025, 0x1234]);
+        assert_eq!(
+            object.image().unwrap(),
+            LoadedImage {
+                origin: 0x3000,
+                length: 2
+            }
+        );
+
+        assert!(matches!(
+            ObjectFile::parse(&[0x30]),
+            Err(LoadError::MissingOrigin)
+        ));
+        assert!(matches!(
+            ObjectFile::parse(&[0x30, 0x00, 0xF0]),
+            Err(LoadError::OddLength { bytes: 3 })
+        ));
+    }
+
+    #[test]
+    fn test_image_bounds() {
+        let object = |origin: u16, length: usize| ObjectFile {
+            origin,
+            words: vec![0; length],
+        };
+
+        assert!(matches!(
+            object(0xFFFF, 2).image(),
+            Err(LoadError::WrapsAddressSpace { .. })
+        ));
+        assert!(matches!(
+            object(0xFDFF, 2).image(),
+            Err(LoadError::DevicePage { .. })
+        ));
+        assert_eq!(object(0xFDFE, 2).image().unwrap().end(), 0xFDFF);
+
+        let first = object(0x3000, 0x10).image().unwrap();
+        assert!(first.overlaps(&object(0x300F, 1).image().unwrap()));
+        assert!(!first.overlaps(&object(0x3010, 1).image().unwrap()));
+        assert!(!first.overlaps(&object(0x3000, 0).image().unwrap()));
+    }
+}
@@ -0,0 +1,305 @@
+use std::fmt::{self, Write};
+use std::time::Instant;
+
+use crate::hardware::memory::MemoryAccess;
+use crate::hardware::registers::{Registers, COND, PC};
+
+use super::engine::Engine;
+use super::limits::Limits;
+use super::{StopReason, VirtualMachine, BATCH};
+
+/**
+Two machines, usually with different engines, that execute the same program one instruction at a time each and
+must agree after every instruction.
+
+Both machines need the same memory, registers and input beforehand, e.g. from loading the same files and feeding
+the keyboards the same bytes. Only the left machine's output is worth showing; compared are the registers (the PC
+and COND included), the memory writes of each instruction, the number of bytes written to the display and why a
+machine stopped.
+*/
+pub struct Lockstep {
+    pub left: VirtualMachine,
+    pub right: VirtualMachine,
+}
+
+/**
+How a lockstep run ended.
+*/
+#[derive(Debug)]
+pub enum LockstepOutcome {
+    /// Both machines stopped for the same reason after the same instructions.
+    Agreed(StopReason),
+    Diverged(Box<Divergence>),
+}
+
+/**
+The first instruction after which the two machines disagreed.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Number of instructions both machines executed before this one.
+    pub step: u64,
+    pub pc: u16,
+    pub instruction: u16,
+    pub left: Side,
+    pub right: Side,
+}
+
+/**
+One machine's state after the diverging instruction.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Side {
+    pub engine: Engine,
+    pub registers: Registers,
+    /// Address and value of every write, in order.
+    pub writes: Vec<(u16, u16)>,
+    pub output: u64,
+    pub stop: Option<StopReason>,
+}
+
+impl Side {
+    fn agrees_with(&self, other: &Side) -> bool {
+        self.registers == other.registers
+            && self.writes == other.writes
+            && self.output == other.output
+            && self.stop == other.stop
+    }
+
+    fn after_step(vm: &mut VirtualMachine) -> Side {
+        vm.memory.start_journal();
+        let stop = vm.step();
+        let writes = vm
+            .memory
+            .take_journal()
+            .into_iter()
+            .filter_map(|access| match access {
+                MemoryAccess::Write { address, value, .. } => Some((address, value)),
+                MemoryAccess::Read { .. } => None,
+            })
+            .collect();
+        Side {
+            engine: vm.engine(),
+            registers: vm.registers,
+            writes,
+            output: vm.memory.display.written,
+            stop,
+        }
+    }
+}
+
+impl Lockstep {
+    pub fn new(left: VirtualMachine, right: VirtualMachine) -> Lockstep {
+        Lockstep { left, right }
+    }
+
+    /**
+    Executes one instruction on both machines. Returns why they stopped, if they did, or how they disagreed.
+    */
+    pub fn step(&mut self) -> Result<Option<StopReason>, Box<Divergence>> {
+        let step = self.left.steps;
+        let pc = self.left.registers.read_program_counter();
+        let instruction = self.left.peek_memory(pc);
+
+        let left = Side::after_step(&mut self.left);
+        let right = Side::after_step(&mut self.right);
+        if left.agrees_with(&right) {
+            return Ok(left.stop);
+        }
+        Err(Box::new(Divergence {
+            step,
+            pc,
+            instruction,
+            left,
+            right,
+        }))
+    }
+
+    /**
+    Runs both machines until they stop, disagree or reach a limit. The output and input limits apply to each
+    machine, the instruction and time limits to the run.
+    */
+    pub fn run(&mut self, limits: Limits) -> LockstepOutcome {
+        self.left.set_limits(limits);
+        self.right.set_limits(limits);
+        let started = Instant::now();
+
+        loop {
+            if let Some(limit) = limits.instructions {
+                if self.left.steps >= limit {
+                    return LockstepOutcome::Agreed(StopReason::InstructionLimit(limit));
+                }
+            }
+            if let Some(limit) = limits.wall_time {
+                if self.left.steps.is_multiple_of(BATCH) && started.elapsed() >= limit {
+                    return LockstepOutcome::Agreed(StopReason::TimeLimit(limit));
+                }
+            }
+            match self.step() {
+                Ok(None) => {}
+                Ok(Some(stop)) => return LockstepOutcome::Agreed(stop),
+                Err(divergence) => return LockstepOutcome::Diverged(divergence),
+            }
+        }
+    }
+}
+
+/**
+A table of everything that differs: one row per register, then the writes, the output and the stop.
+*/
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "engines diverged at step {}, x{:04X}: x{:04X}",
+            self.step, self.pc, self.instruction
+        )?;
+        let mut rows = vec![(
+            "".to_string(),
+            self.left.engine.to_string(),
+            self.right.engine.to_string(),
+        )];
+
+        for register in 0..10 {
+            let (left, right) = (
+                self.left.registers.read(register),
+                self.right.registers.read(register),
+            );
+            if left != right {
+                let name = match register {
+                    PC => "PC".to_string(),
+                    COND => "COND".to_string(),
+                    _ => format!("R{}", register),
+                };
+                rows.push((name, format!("x{:04X}", left), format!("x{:04X}", right)));
+            }
+        }
+        if self.left.writes != self.right.writes {
+            rows.push((
+                "writes".to_string(),
+                format_writes(&self.left.writes),
+                format_writes(&self.right.writes),
+            ));
+        }
+        if self.left.output != self.right.output {
+            rows.push((
+                "output bytes".to_string(),
+                self.left.output.to_string(),
+                self.right.output.to_string(),
+            ));
+        }
+        if self.left.stop != self.right.stop {
+            rows.push((
+                "stop".to_string(),
+                format_stop(self.left.stop),
+                format_stop(self.right.stop),
+            ));
+        }
+
+        let width = rows.iter().map(|row| row.1.len()).max().unwrap_or(0);
+        for (name, left, right) in rows {
+            writeln!(
+                f,
+                "  {:<12} {:<width$}  {}",
+                name,
+                left,
+                right,
+                width = width
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn format_writes(writes: &[(u16, u16)]) -> String {
+    if writes.is_empty() {
+        return "none".to_string();
+    }
+    let mut text = String::new();
+    for (address, value) in writes {
+        if !text.is_empty() {
+            text.push_str(", ");
+        }
+        write!(text, "x{:04X}=x{:04X}", address, value).unwrap();
+    }
+    text
+}
+
+fn format_stop(stop: Option<StopReason>) -> String {
+    stop.map_or("running".to_string(), |stop| stop.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io;
+
+    use super::*;
+    use crate::hardware::console::{Display, Keyboard};
+
+    fn machine(engine: Engine, program: &[u16]) -> VirtualMachine {
+        let mut vm = VirtualMachine::create();
+        vm.set_engine(engine);
+        vm.memory.display = Display::to_writer(Box::new(io::sink()));
+        for (address, &word) in (0x3000..).zip(program) {
+            vm.memory.write(address, word);
+        }
+        vm
+    }
+
+    #[test]
+    fn test_engines_agree_on_a_bundled_program() {
+        for engine in Engine::ALL {
+            let game = |engine| {
+                let mut vm = machine(engine, &[]);
+                vm.load_program("2048.obj").unwrap();
+                vm.memory.keyboard =
+                    Keyboard::from_bytes(&[&b"n"[..], &b"wasd".repeat(20)].concat());
+                vm
+            };
+            let mut lockstep = Lockstep::new(game(Engine::Interpreter), game(engine));
+
+            let limits = Limits {
+                instructions: Some(200_000),
+                ..Limits::unlimited()
+            };
+            match lockstep.run(limits) {
+                LockstepOutcome::Agreed(stop) => {
+                    assert_eq!(stop, StopReason::InstructionLimit(200_000), "{}", engine)
+                }
+                LockstepOutcome::Diverged(divergence) => panic!("{}", divergence),
+            }
+        }
+    }
+
+    #[test]
+    fn test_first_divergence_is_reported() {
+        // ADD R1, R1, #1 ; LD R0, DATA ; ST R0, DATA ; HALT ; DATA
+        let program = |data| {
+            [
+                0b0001_001_001_1_00001,
+                0b0010_000_000000010,
+                0b0011_000_000000001,
+                0xF025,
+                data,
+            ]
+        };
+        let mut lockstep = Lockstep::new(
+            machine(Engine::Interpreter, &program(5)),
+            machine(Engine::Compiled, &program(6)),
+        );
+
+        let LockstepOutcome::Diverged(divergence) = lockstep.run(Limits::unlimited()) else {
+            panic!("the machines hold different data");
+        };
+        assert_eq!((divergence.step, divergence.pc), (1, 0x3001));
+        assert_eq!(divergence.left.registers.read(0), 5);
+        assert_eq!(divergence.right.registers.read(0), 6);
+
+        let report = divergence.to_string();
+        assert!(report.starts_with("engines diverged at step 1, x3001: x2002\n"));
+        assert!(report.contains("R0           x0005        x0006\n"));
+        assert!(!report.contains("R1"));
+        assert!(!report.contains("writes"));
+    }
+}
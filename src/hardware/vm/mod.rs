@@ -1,15 +1,169 @@
-use std::fs::File;
-use std::io::BufReader;
-
-use byteorder::{BigEndian, ReadBytesExt};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
 
+use super::console::InputFailure;
 use super::instructions::execute_instruction;
-use super::memory::Memory;
+use super::memory::{Memory, MemoryAccess};
 use super::registers::Registers;
 
+pub mod callstack;
+pub mod coverage;
+pub mod dump;
+pub mod engine;
+pub mod formats;
+pub mod history;
+pub mod limits;
+pub mod listing;
+pub mod loader;
+pub mod lockstep;
+pub mod profiler;
+pub mod setup;
+pub mod snapshot;
+pub mod symbols;
+pub mod trace;
+pub mod translator;
+
+use callstack::{CallChange, CallStack};
+use coverage::Coverage;
+use dump::{CrashDump, DumpInfo};
+use engine::Engine;
+use formats::ObjectFormat;
+use history::{History, UndoEntry};
+use limits::Limits;
+use listing::Listing;
+use loader::{LoadError, LoadedImage, ObjectFile};
+use profiler::Profiler;
+use snapshot::{Snapshot, SnapshotError};
+use symbols::SymbolTable;
+use trace::{TraceRecord, TraceWriter};
+use translator::Translation;
+
 pub struct VirtualMachine {
     pub memory: Memory,
     pub registers: Registers,
+    pub steps: u64,
+    pub symbols: SymbolTable,
+    pub listing: Listing,
+    /// Every loaded section, with the object file it came from and where it landed.
+    pub programs: Vec<(String, LoadedImage)>,
+    pub call_stack: CallStack,
+    trace: Option<TraceWriter>,
+    history: Option<History>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    recent: VecDeque<Step>,
+    recent_capacity: usize,
+    limits: Limits,
+    engine: Engine,
+    /// Built when the compiled engine first runs.
+    translation: Option<Translation>,
+}
+
+/// Instructions executed between checks of the time limit.
+const BATCH: u64 = 1024;
+
+/**
+Why the machine stopped executing.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Halted,
+    EndOfInput,
+    InstructionLimit(u64),
+    TimeLimit(Duration),
+    OutputLimit(u64),
+    InputLimit(u64),
+    /// The program read input at `step`, but the replayed input log has the next byte at `recorded`.
+    ReplayDiverged {
+        step: u64,
+        recorded: u64,
+    },
+    Fault(Fault),
+}
+
+/**
+An instruction the machine cannot execute. `address` is where the instruction is.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// RES, or RTI, which is only legal in supervisor mode and this VM runs everything in user mode.
+    IllegalOpcode {
+        address: u16,
+        instruction: u16,
+    },
+    UnknownTrap {
+        address: u16,
+        vector: u8,
+    },
+}
+
+impl Fault {
+    pub fn address(&self) -> u16 {
+        match *self {
+            Fault::IllegalOpcode { address, .. } | Fault::UnknownTrap { address, .. } => address,
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::IllegalOpcode {
+                address,
+                instruction,
+            } => write!(
+                f,
+                "illegal instruction x{:04X} at x{:04X}",
+                instruction, address
+            ),
+            Fault::UnknownTrap { address, vector } => {
+                write!(f, "unknown trap vector x{:02X} at x{:04X}", vector, address)
+            }
+        }
+    }
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopReason::Halted => write!(f, "halted"),
+            StopReason::EndOfInput => write!(f, "program waited for input after the input ended"),
+            StopReason::InstructionLimit(limit) => {
+                write!(f, "instruction limit of {} reached", limit)
+            }
+            StopReason::TimeLimit(limit) => {
+                write!(f, "time limit of {:.3}s reached", limit.as_secs_f64())
+            }
+            StopReason::OutputLimit(limit) => write!(f, "output limit of {} bytes reached", limit),
+            StopReason::InputLimit(limit) => write!(f, "input limit of {} reads reached", limit),
+            StopReason::ReplayDiverged { step, recorded } => write!(
+                f,
+                "replay diverged: input was read at step {} but recorded at step {}",
+                step, recorded
+            ),
+            StopReason::Fault(fault) => write!(f, "faulted: {}", fault),
+        }
+    }
+}
+
+/**
+Everything that happened while executing a single instruction: the registers before and after
+and every memory access the instruction made (the instruction fetch itself is not included).
+*/
+#[derive(Debug, Clone)]
+pub struct Step {
+    pub number: u64,
+    pub pc: u16,
+    pub instruction: u16,
+    pub before: Registers,
+    pub after: Registers,
+    pub accesses: Vec<MemoryAccess>,
+    pub call: CallChange,
+    pub stop: Option<StopReason>,
 }
 
 impl VirtualMachine {
@@ -17,6 +171,237 @@ impl VirtualMachine {
         VirtualMachine {
             memory: Memory::empty(),
             registers: Registers::initial(),
+            steps: 0,
+            symbols: SymbolTable::empty(),
+            listing: Listing::empty(),
+            programs: Vec::new(),
+            call_stack: CallStack::new(),
+            trace: None,
+            history: None,
+            profiler: None,
+            coverage: None,
+            recent: VecDeque::new(),
+            recent_capacity: 0,
+            limits: Limits::unlimited(),
+            engine: Engine::Interpreter,
+            translation: None,
+        }
+    }
+
+    /**
+    Applies resource limits to `execute_program`. Output and input limits are enforced by the devices,
+    so they also apply when stepping.
+    */
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+        self.memory.display.limit = limits.output_bytes;
+        self.memory.keyboard.limit = limits.input_reads;
+    }
+
+    /**
+    Selects how instructions are executed from now on. Traced and recorded steps always use the interpreter.
+    */
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.engine = engine;
+        match engine {
+            Engine::Cached => self.memory.enable_decode_cache(),
+            Engine::Interpreter | Engine::Compiled => self.memory.disable_decode_cache(),
+        }
+        self.discard_translation();
+    }
+
+    pub fn engine(&self) -> Engine {
+        self.engine
+    }
+
+    /**
+    The compiled engine's translation, once it has run.
+    */
+    pub fn translation(&self) -> Option<&Translation> {
+        self.translation.as_ref()
+    }
+
+    /**
+    Translates the code reachable from the PC and the origins of the loaded object files.
+    */
+    fn translate(&mut self) {
+        let mut entries = vec![self.registers.read_program_counter()];
+        entries.extend(self.programs.iter().map(|(_, image)| image.origin));
+        self.translation = Some(Translation::translate(&mut self.memory, &entries));
+    }
+
+    /**
+    Makes the compiled engine translate again the next time it runs, after memory changed wholesale.
+    */
+    fn discard_translation(&mut self) {
+        self.translation = None;
+        self.memory.unwatch_writes();
+    }
+
+    /**
+    Emits a JSON Lines record for every instruction executed from now on.
+    */
+    pub fn enable_trace(&mut self, trace: TraceWriter) {
+        self.trace = Some(trace);
+    }
+
+    /**
+    Counts executed instructions per address and per subroutine from now on.
+    */
+    pub fn enable_profiler(&mut self) {
+        self.profiler = Some(Profiler::new());
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /**
+    The profiler's report, with routines named from the loaded symbols.
+    */
+    pub fn profile_report(&self) -> Option<String> {
+        self.profiler
+            .as_ref()
+            .map(|profiler| profiler.report(&self.symbols))
+    }
+
+    /**
+    Records executed addresses and branch outcomes from now on.
+    */
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(Coverage::new());
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /**
+    Coverage of the loaded object files in lcov format. Addresses without a listing are reported against the
+    object file they were loaded from.
+    */
+    pub fn coverage_lcov(&self) -> Option<String> {
+        let coverage = self.coverage.as_ref()?;
+        let mut report = String::new();
+        for (object, image) in &self.programs {
+            let words: Vec<(u16, u16)> = (image.origin..)
+                .take(image.length)
+                .map(|address| (address, self.memory.peek(address)))
+                .collect();
+            report += &coverage.lcov(&words, &self.listing, object);
+        }
+        Some(report)
+    }
+
+    /**
+    Remembers the last `capacity` executed instructions for crash dumps.
+    */
+    pub fn keep_recent_steps(&mut self, capacity: usize) {
+        self.recent_capacity = capacity;
+        self.recent = VecDeque::with_capacity(capacity);
+    }
+
+    /**
+    The machine state after it stopped for `reason`, with the recent steps and the call stack.
+    */
+    pub fn crash_dump(&self, reason: StopReason) -> CrashDump {
+        let address = match reason {
+            StopReason::Fault(fault) => fault.address(),
+            _ => self.registers.read_program_counter(),
+        };
+        CrashDump {
+            snapshot: Snapshot::capture(self),
+            info: DumpInfo {
+                programs: self.program_names(),
+                reason: reason.to_string(),
+                address,
+                psr: 0x8000 | self.registers.read_cond(),
+                call_stack: self.call_stack.frames().to_vec(),
+                trace: self.recent.iter().map(TraceRecord::from_step).collect(),
+            },
+        }
+    }
+
+    /**
+    The object files loaded so far, once each, in load order.
+    */
+    fn program_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for (name, _) in &self.programs {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+        names
+    }
+
+    pub fn write_crash_dump(&self, path: &str, reason: StopReason) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        self.crash_dump(reason).write_to(&mut file)?;
+        file.flush()
+    }
+
+    /**
+    Keeps undo information for the last `capacity` executed instructions so they can be stepped back over.
+    */
+    pub fn enable_history(&mut self, capacity: usize) {
+        self.history = Some(History::with_capacity(capacity));
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    /**
+    Undoes the most recently executed instruction, restoring the registers and any memory it overwrote.
+    Returns `None` once the recorded history is exhausted.
+
+    Input consumed by the undone instruction is not given back: stepping forward again reads new input.
+    */
+    pub fn step_back(&mut self) -> Option<UndoEntry> {
+        let entry = self.history.as_mut()?.pop()?;
+
+        for &(address, previous) in entry.overwritten.iter().rev() {
+            self.memory.write(address, previous);
+        }
+        self.registers = entry.registers;
+        self.steps = entry.number;
+        self.call_stack.undo(entry.call.clone());
+
+        Some(entry)
+    }
+
+    /**
+    Writes the complete machine state to `path`, see `Snapshot` for the format.
+    */
+    pub fn save_snapshot(&self, path: &str) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        Snapshot::capture(self).write_to(&mut file)?;
+        file.flush()
+    }
+
+    pub fn load_snapshot(&mut self, path: &str) -> Result<(), SnapshotError> {
+        let mut file = BufReader::new(File::open(path).map_err(SnapshotError::Io)?);
+        let snapshot = Snapshot::read_from(&mut file)?;
+        self.restore_snapshot(&snapshot);
+        Ok(())
+    }
+
+    /**
+    Puts the machine back into the captured state. Undo history from before the restore is dropped.
+    */
+    pub fn restore_snapshot(&mut self, snapshot: &Snapshot) {
+        self.memory.restore(&snapshot.memory);
+        self.discard_translation();
+        self.registers = snapshot.registers;
+        self.steps = snapshot.steps;
+        self.memory.keyboard.pending = snapshot.pending_input.iter().copied().collect();
+        self.memory.display.written = snapshot.output_written;
+        self.memory.keyboard.reads = snapshot.input_reads;
+        // the snapshot does not know which calls are active
+        self.call_stack.clear();
+        if let Some(history) = &mut self.history {
+            history.clear();
         }
     }
 
@@ -24,48 +409,749 @@ impl VirtualMachine {
         self.memory.read(address)
     }
 
+    /**
+    Reads memory for inspection, without polling devices. See `Memory::peek`.
+    */
+    pub fn peek_memory(&self, address: u16) -> u16 {
+        self.memory.peek(address)
+    }
+
     pub fn read_register(&self, register_index: u16) -> u16 {
         self.registers.read(register_index)
     }
 
-    pub fn execute_program(&mut self) {
-        while (self.registers.read_program_counter() as usize) < (self.memory.memory_max) {
-            let next_instruction = self.read_memory(self.registers.read_program_counter());
+    /**
+    Runs until the program halts or a limit is hit.
+    */
+    pub fn execute_program(&mut self) -> StopReason {
+        let reason = self.run();
+        self.memory.display.flush();
+        reason
+    }
 
-            self.registers.increment_program_counter();
+    fn run(&mut self) -> StopReason {
+        let started = Instant::now();
+        let instruction_limit = self.limits.instructions.unwrap_or(u64::MAX);
+        // the uninstrumented loop is the one long runs spend their time in, so it is chosen once per batch
+        let plain = !self.instrumented();
 
-            let registers = &mut self.registers;
-            let memory = &mut self.memory;
+        loop {
+            if self.steps >= instruction_limit {
+                return StopReason::InstructionLimit(instruction_limit);
+            }
+            let batch = (instruction_limit - self.steps).min(BATCH);
+            let stop = if plain && self.engine == Engine::Compiled {
+                self.run_compiled(batch)
+            } else if plain {
+                (0..batch).find_map(|_| self.step_instruction())
+            } else {
+                (0..batch).find_map(|_| self.step())
+            };
+            if let Some(reason) = stop {
+                return reason;
+            }
 
-            execute_instruction(next_instruction, registers, memory);
+            // reading the clock on every instruction would dominate the run time
+            if let Some(limit) = self.limits.wall_time {
+                if started.elapsed() >= limit {
+                    return StopReason::TimeLimit(limit);
+                }
+            }
         }
     }
 
-    pub fn load_program(&mut self, path: &str) {
-        let f = File::open(path).unwrap();
-        let mut file_buffer = BufReader::new(f);
+    /**
+    Whether anything watches individual instructions: a trace, the undo history, the crash dump trace, the
+    profiler or coverage.
+    */
+    fn instrumented(&self) -> bool {
+        self.trace.is_some()
+            || self.history.is_some()
+            || self.recent_capacity > 0
+            || self.profiler.is_some()
+            || self.coverage.is_some()
+    }
+
+    /**
+    Executes a single instruction. Output may stay buffered until the display is flushed.
+    */
+    pub fn step(&mut self) -> Option<StopReason> {
+        if self.trace.is_some() || self.history.is_some() || self.recent_capacity > 0 {
+            return self.step_recorded().stop;
+        }
+        if !self.instrumented() {
+            return self.step_plain();
+        }
 
-        let base_address = file_buffer
-            .read_u16::<BigEndian>()
-            .expect("Error reading file");
+        let pc = self.registers.read_program_counter();
+        let cond = self.registers.read_cond();
+        let instruction = self.memory.peek(pc);
+        let stop = self.step_plain();
 
-        let mut address = base_address;
+        let next_pc = self.registers.read_program_counter();
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(pc, instruction, next_pc);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(pc, instruction, cond);
+        }
+        stop
+    }
 
-        loop {
-            match file_buffer.read_u16::<BigEndian>() {
-                Ok(instruction) => {
-                    self.memory.write(address, instruction);
-                    address += 1;
-                }
-                Err(e) => {
-                    if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                        println!("Finished loading program")
-                    } else {
-                        println!("failed: {}", e);
-                    }
-                    break;
+    /**
+    Executes a single instruction, keeping only the shadow call stack up to date.
+    */
+    fn step_plain(&mut self) -> Option<StopReason> {
+        match self.engine {
+            Engine::Compiled => self.run_compiled(1),
+            Engine::Interpreter | Engine::Cached => self.step_instruction(),
+        }
+    }
+
+    /**
+    Runs up to `budget` instructions with the compiled engine. Addresses without a translation, because only a
+    computed jump reaches them or because they were written since, are interpreted.
+    */
+    fn run_compiled(&mut self, budget: u64) -> Option<StopReason> {
+        let mut translation = match self.translation.take() {
+            Some(translation) => translation,
+            None => {
+                self.translate();
+                self.translation.take().expect("just translated")
+            }
+        };
+        let stop = self.run_translated(&mut translation, budget);
+        self.translation = Some(translation);
+        stop
+    }
+
+    fn run_translated(&mut self, translation: &mut Translation, budget: u64) -> Option<StopReason> {
+        Self::invalidate_written(&mut self.memory, translation);
+        let mut executed = 0;
+        while executed < budget {
+            let pc = self.registers.read_program_counter();
+            let Some((block, first)) = translation.lookup(pc) else {
+                executed += 1;
+                let stop = self.step_instruction();
+                Self::invalidate_written(&mut self.memory, translation);
+                match stop {
+                    Some(stop) => return Some(stop),
+                    None => continue,
                 }
+            };
+
+            let exit = block.run(
+                &mut self.registers,
+                &mut self.memory,
+                self.steps,
+                first,
+                (budget - executed) as usize,
+            );
+            self.steps += exit.executed as u64;
+            executed += exit.executed as u64;
+            if !exit.last && !exit.interrupted {
+                continue;
+            }
+
+            // only the last instruction of a block can call or return
+            let last = block.len() - 1;
+            if exit.last && block.jumps() {
+                self.call_stack.follow(
+                    block.start.wrapping_add(last as u16),
+                    block.word(last),
+                    self.registers.read_program_counter(),
+                );
+            }
+            if let Some(stop) = exit.stop.or_else(|| self.device_stop()) {
+                return Some(stop);
+            }
+            Self::invalidate_written(&mut self.memory, translation);
+        }
+        None
+    }
+
+    /**
+    Drops the translations of the watched addresses written since the last call.
+    */
+    fn invalidate_written(memory: &mut Memory, translation: &mut Translation) {
+        if memory.watched_written() {
+            for address in memory.take_watched_writes() {
+                translation.invalidate(address);
             }
         }
     }
+
+    /**
+    Fetches and executes a single instruction with the interpreter or the decode cache, keeping only the shadow
+    call stack up to date.
+    */
+    #[inline(always)]
+    fn step_instruction(&mut self) -> Option<StopReason> {
+        self.memory.keyboard.step = self.steps;
+        let pc = self.registers.read_program_counter();
+
+        let (instruction, stop) = match self.engine {
+            Engine::Interpreter | Engine::Compiled => {
+                let instruction = self.memory.read(pc);
+                self.registers.increment_program_counter();
+                let stop = execute_instruction(instruction, &mut self.registers, &mut self.memory);
+                (instruction, stop)
+            }
+            Engine::Cached => {
+                let decoded = self.memory.fetch_decoded(pc);
+                // the word the fetch saw, which the call stack needs
+                let instruction = self.memory.peek(pc);
+                self.registers.increment_program_counter();
+                let stop = decoded.execute(&mut self.registers, &mut self.memory);
+                (instruction, stop)
+            }
+        };
+        self.steps += 1;
+
+        self.call_stack
+            .follow(pc, instruction, self.registers.read_program_counter());
+
+        stop.or_else(|| self.device_stop())
+    }
+
+    /**
+    A stop caused by the keyboard or display during the last instruction.
+    */
+    #[inline]
+    fn device_stop(&mut self) -> Option<StopReason> {
+        if self.memory.display.limit_reached {
+            self.memory.display.limit_reached = false;
+            return self.memory.display.limit.map(StopReason::OutputLimit);
+        }
+        match self.memory.keyboard.failure.take()? {
+            InputFailure::Exhausted => Some(StopReason::EndOfInput),
+            InputFailure::LimitReached => self.memory.keyboard.limit.map(StopReason::InputLimit),
+            InputFailure::Diverged { step, recorded } => {
+                Some(StopReason::ReplayDiverged { step, recorded })
+            }
+        }
+    }
+
+    /**
+    The shadow call stack as a backtrace starting at `pc`, followed by the frames found by walking R5 when
+    `frame_pointers` is set.
+    */
+    pub fn backtrace(&self, pc: u16, frame_pointers: bool) -> String {
+        let mut backtrace = self.call_stack.backtrace(pc, &self.symbols);
+        if frame_pointers {
+            let frames =
+                callstack::walk_frame_pointers(self.memory.contents(), self.registers.read(5));
+            backtrace += "frames (R5):\n";
+            backtrace += &callstack::format_frames(&frames, &self.symbols);
+        }
+        backtrace
+    }
+
+    /**
+    A short description of the machine state, for reporting why and where a run stopped.
+    */
+    pub fn summary(&self) -> String {
+        let registers = &self.registers;
+        let mut summary = format!(
+            "executed {} instructions, wrote {} bytes, read {} bytes\nPC: x{:04X}  COND: x{:04X}\n",
+            self.steps,
+            self.memory.display.written,
+            self.memory.keyboard.reads,
+            registers.read_program_counter(),
+            registers.read_cond()
+        );
+        for index in 0..8 {
+            summary += &format!("R{}: x{:04X}  ", index, registers.read(index));
+        }
+        summary.trim_end().to_string()
+    }
+
+    /**
+    Executes a single instruction like `step`, but journals its effects and returns them.
+    The step is also written to the trace and the undo history when those are enabled.
+    */
+    pub fn step_recorded(&mut self) -> Step {
+        let before = self.registers;
+        let pc = before.read_program_counter();
+        self.memory.keyboard.step = self.steps;
+        let instruction = self.read_memory(pc);
+
+        self.registers.increment_program_counter();
+
+        self.memory.start_journal();
+        let stop = execute_instruction(instruction, &mut self.registers, &mut self.memory);
+        let accesses = self.memory.take_journal();
+        let stop = stop.or_else(|| self.device_stop());
+        let call = self
+            .call_stack
+            .record(pc, instruction, self.registers.read_program_counter());
+
+        let step = Step {
+            number: self.steps,
+            pc,
+            instruction,
+            before,
+            after: self.registers,
+            accesses,
+            call,
+            stop,
+        };
+        self.steps += 1;
+
+        if let Some(trace) = &mut self.trace {
+            trace.record(&step).expect("failed to write trace");
+        }
+        if let Some(history) = &mut self.history {
+            history.record(&step);
+        }
+        if self.recent_capacity > 0 {
+            if self.recent.len() == self.recent_capacity {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(step.clone());
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(pc, instruction, step.after.read_program_counter());
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(pc, instruction, before.read_cond());
+        }
+
+        step
+    }
+
+    /**
+    Loads an object file in any of the supported formats (see `ObjectFormat`), along with the assembler's `.sym`
+    and `.lst` files next to it when there are any. Nothing is loaded when the file is invalid or does not fit.
+
+    The first image loaded sets the PC to its origin; later ones (e.g. an OS or libraries) leave it alone.
+    Returns where each section of the file landed.
+    */
+    pub fn load_program(&mut self, path: &str) -> Result<Vec<LoadedImage>, LoadError> {
+        let io_error = |path: &Path| {
+            let path = path.to_path_buf();
+            move |error| LoadError::Io { path, error }
+        };
+
+        let bytes = fs::read(path).map_err(io_error(Path::new(path)))?;
+        let objects = self.decode_images(path, &bytes)?;
+
+        let symbols = SymbolTable::for_program(path)
+            .map_err(io_error(&Path::new(path).with_extension("sym")))?;
+        let listing =
+            Listing::for_program(path).map_err(io_error(&Path::new(path).with_extension("lst")))?;
+        self.symbols.extend(symbols.unwrap_or_default());
+        self.listing.extend(listing.unwrap_or_default());
+
+        self.load_images(path, &objects)
+    }
+
+    /**
+    Loads an image that is already in memory, like `load_program` without the `.sym` and `.lst` files. `name`
+    stands in for the path, for detecting the format by extension and in the list of loaded programs.
+    */
+    pub fn load_bytes(&mut self, name: &str, bytes: &[u8]) -> Result<Vec<LoadedImage>, LoadError> {
+        let objects = self.decode_images(name, bytes)?;
+        self.load_images(name, &objects)
+    }
+
+    /**
+    The sections of an image, checked to fit next to each other and the images loaded before.
+    */
+    fn decode_images(&self, name: &str, bytes: &[u8]) -> Result<Vec<ObjectFile>, LoadError> {
+        let objects = ObjectFormat::detect(Path::new(name), bytes).decode(bytes)?;
+        let mut images: Vec<LoadedImage> = Vec::new();
+        for object in &objects {
+            let image = self.check_fits(object)?;
+            if let Some(&existing) = images.iter().find(|other| other.overlaps(&image)) {
+                return Err(LoadError::Overlap { image, existing });
+            }
+            images.push(image);
+        }
+        Ok(objects)
+    }
+
+    fn load_images(
+        &mut self,
+        name: &str,
+        objects: &[ObjectFile],
+    ) -> Result<Vec<LoadedImage>, LoadError> {
+        if self.programs.is_empty() {
+            self.registers.update_program_counter(objects[0].origin);
+        }
+        let mut images = Vec::new();
+        for object in objects {
+            images.push(self.load_object(name, object)?);
+        }
+        Ok(images)
+    }
+
+    /**
+    Copies an object file into memory, unless it runs past the end of memory, into the device page or over an
+    image loaded before. `name` is the file it came from, for coverage reports and crash dumps.
+    */
+    pub fn load_object(
+        &mut self,
+        name: &str,
+        object: &ObjectFile,
+    ) -> Result<LoadedImage, LoadError> {
+        let image = self.check_fits(object)?;
+        // words first: an empty image may start at xFFFF, where the address range cannot advance
+        for (&word, address) in object.words.iter().zip(object.origin..) {
+            self.memory.write(address, word);
+        }
+        self.programs.push((name.to_string(), image));
+        self.discard_translation();
+        Ok(image)
+    }
+
+    fn check_fits(&self, object: &ObjectFile) -> Result<LoadedImage, LoadError> {
+        let image = object.image()?;
+        match self
+            .programs
+            .iter()
+            .find(|(_, existing)| existing.overlaps(&image))
+        {
+            Some(&(_, existing)) => Err(LoadError::Overlap { image, existing }),
+            None => Ok(image),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::hardware::console::{read_input_log, Display, InputEvent, Keyboard};
+
+    #[test]
+    fn test_step_back_restores_registers_and_memory() {
+        let mut vm = VirtualMachine::create();
+        vm.enable_history(16);
+
+        // LEA R1, #2 ; STR R1, R1, #0
+        vm.memory.write(0x3000, 0b1110_001_000000010);
+        vm.memory.write(0x3001, 0b0111_001_001_000000);
+        vm.memory.write(0x3003, 0x1234);
+
+        vm.step();
+        vm.step();
+        assert_eq!(vm.read_memory(0x3003), 0x3003);
+
+        let entry = vm.step_back().unwrap();
+        assert_eq!(entry.number, 1);
+        assert_eq!(vm.read_memory(0x3003), 0x1234);
+        assert_eq!(vm.registers.read_program_counter(), 0x3001);
+        assert_eq!(vm.read_register(1), 0x3003);
+
+        vm.step_back().unwrap();
+        assert_eq!(vm.registers, Registers::initial());
+        assert_eq!(vm.steps, 0);
+        assert!(vm.step_back().is_none());
+    }
+
+    #[test]
+    fn test_limits_stop_the_machine() {
+        let mut vm = VirtualMachine::create();
+        // BRnzp #-1
+        vm.memory.write(0x3000, 0b0000_111_111111111);
+        vm.set_limits(Limits {
+            instructions: Some(1000),
+            ..Limits::unlimited()
+        });
+        assert_eq!(vm.execute_program(), StopReason::InstructionLimit(1000));
+        assert_eq!(vm.steps, 1000);
+
+        let mut vm = VirtualMachine::create();
+        vm.set_limits(Limits {
+            wall_time: Some(Duration::from_millis(10)),
+            ..Limits::unlimited()
+        });
+        vm.memory.write(0x3000, 0b0000_111_111111111);
+        assert_eq!(
+            vm.execute_program(),
+            StopReason::TimeLimit(Duration::from_millis(10))
+        );
+
+        let mut vm = VirtualMachine::create();
+        vm.memory.display = Display::to_writer(Box::new(io::sink()));
+        vm.set_limits(Limits {
+            output_bytes: Some(5),
+            ..Limits::unlimited()
+        });
+        // OUT ; BRnzp #-2
+        vm.memory.write(0x3000, 0xF021);
+        vm.memory.write(0x3001, 0b0000_111_111111110);
+        assert_eq!(vm.execute_program(), StopReason::OutputLimit(5));
+        assert_eq!(vm.memory.display.written, 5);
+
+        let mut vm = VirtualMachine::create();
+        vm.memory.keyboard = Keyboard::from_bytes(b"abc");
+        vm.set_limits(Limits {
+            input_reads: Some(2),
+            ..Limits::unlimited()
+        });
+        // GETC ; BRnzp #-2
+        vm.memory.write(0x3000, 0xF020);
+        vm.memory.write(0x3001, 0b0000_111_111111110);
+        assert_eq!(vm.execute_program(), StopReason::InputLimit(2));
+        assert_eq!(vm.read_register(0), b'b' as u16);
+    }
+
+    #[test]
+    fn test_halt_and_end_of_input_stop_the_machine() {
+        let mut vm = VirtualMachine::create();
+        vm.memory.keyboard = Keyboard::from_bytes(b"");
+        vm.memory.write(0x3000, 0xF020);
+        assert_eq!(vm.execute_program(), StopReason::EndOfInput);
+
+        let mut vm = VirtualMachine::create();
+        vm.memory.write(0x3000, 0xF025);
+        assert_eq!(vm.execute_program(), StopReason::Halted);
+        assert_eq!(vm.registers.read_program_counter(), 0x3001);
+    }
+
+    #[test]
+    fn test_program_counter_wraps_around_memory() {
+        let mut vm = VirtualMachine::create();
+        vm.memory.display = Display::to_writer(Box::new(io::sink()));
+        // LEA R0, #1 ; PUTS ; then the string "ok" running up to xFFFF, which executes as two never-taken
+        // branches, and the terminating x0000 after the wrap, also a no-op
+        vm.memory.write(0xFFFC, 0b1110_000_000000001);
+        vm.memory.write(0xFFFD, 0xF022);
+        vm.memory.write(0xFFFE, b'o' as u16);
+        vm.memory.write(0xFFFF, b'k' as u16);
+        vm.memory.write(0x0001, 0xF025);
+        vm.registers.update_program_counter(0xFFFC);
+
+        assert_eq!(vm.execute_program(), StopReason::Halted);
+        assert_eq!(vm.registers.read_program_counter(), 0x0002);
+        assert_eq!(vm.memory.display.written, 2);
+        assert_eq!(vm.peek_memory(0xFFFF), b'k' as u16);
+    }
+
+    /**
+    A loop that overwrites its first instruction, ADD R0, R0, #1, with ADD R0, R0, #8 using `store`, then runs it
+    again. R3 and x3009 both point at the patched instruction.
+    */
+    fn self_modifying(engine: Engine, store: u16) -> VirtualMachine {
+        let mut vm = VirtualMachine::create();
+        vm.set_engine(engine);
+        let program = [
+            0b0101_000_000_1_00000,
+            0b0101_001_001_1_00000,
+            0b0001_001_001_1_00010,
+            0b0001_000_000_1_00001,
+            0b0010_010_000000101,
+            store,
+            0b0001_001_001_1_11111,
+            0b0000_001_111111011,
+            0xF025,
+            0x3003,
+            0b0001_000_000_1_01000,
+        ];
+        for (address, &word) in (0x3000..).zip(&program) {
+            vm.memory.write(address, word);
+        }
+        vm.registers.update(3, 0x3003);
+        vm
+    }
+
+    #[test]
+    fn test_engines_see_self_modifying_code() {
+        // ST R2, #-3 ; STI R2, #3 ; STR R2, R3, #0
+        for store in [
+            0b0011_010_111111101,
+            0b1011_010_000000011,
+            0b0111_010_011_000000,
+        ] {
+            for engine in Engine::ALL {
+                let mut vm = self_modifying(engine, store);
+                assert_eq!(vm.execute_program(), StopReason::Halted);
+                assert_eq!(vm.read_register(0), 9, "{} with x{:04X}", engine, store);
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_cache_is_invalidated_by_outside_writes() {
+        let mut vm = VirtualMachine::create();
+        vm.set_engine(Engine::Cached);
+        // ADD R0, R0, #1 ; HALT
+        vm.memory.write(0x3000, 0b0001_000_000_1_00001);
+        vm.memory.write(0x3001, 0xF025);
+        vm.execute_program();
+        assert_eq!(vm.memory.decode_cache().unwrap().len(), 2);
+        let snapshot = Snapshot::capture(&vm);
+
+        // patched by a debugger or loader: ADD R0, R0, #2
+        vm.memory.write(0x3000, 0b0001_000_000_1_00010);
+        vm.registers.update_program_counter(0x3000);
+        vm.execute_program();
+        assert_eq!(vm.read_register(0), 3);
+
+        // patched by an image: ADD R0, R0, #4
+        vm.memory.import_raw(0x3000, &[0x10, 0x24]).unwrap();
+        vm.registers.update_program_counter(0x3000);
+        vm.execute_program();
+        assert_eq!(vm.read_register(0), 7);
+
+        // restoring a snapshot brings back ADD R0, R0, #1
+        vm.restore_snapshot(&snapshot);
+        assert!(vm.memory.decode_cache().unwrap().is_empty());
+        vm.registers.update_program_counter(0x3000);
+        vm.execute_program();
+        assert_eq!(vm.read_register(0), 2);
+    }
+
+    #[test]
+    fn test_load_object_rejects_overlap() {
+        let mut vm = VirtualMachine::create();
+        let object = |origin: u16, words: Vec<u16>| ObjectFile { origin, words };
+
+        let image = vm
+            .load_object("image.obj", &object(0x3000, vec![1, 2, 3]))
+            .unwrap();
+        assert_eq!(image.end(), 0x3002);
+
+        let error = vm
+            .load_object("image.obj", &object(0x3002, vec![9]))
+            .unwrap_err();
+        assert!(matches!(error, LoadError::Overlap { existing, .. } if existing == image));
+        assert_eq!(vm.read_memory(0x3002), 3);
+        assert_eq!(vm.programs, vec![("image.obj".to_string(), image)]);
+    }
+
+    #[test]
+    fn test_load_bytes_rejects_garbage_without_loading_anything() {
+        let mut vm = VirtualMachine::create();
+        assert!(matches!(
+            vm.load_bytes("image.obj", &[0x30]),
+            Err(LoadError::MissingOrigin)
+        ));
+        assert!(matches!(
+            vm.load_bytes("image.hex", b"3000\nnope\n"),
+            Err(LoadError::InvalidLine { line: 2, .. })
+        ));
+        assert!(matches!(
+            vm.load_bytes("image.obj", &[0xFD, 0xFF, 0x12, 0x34, 0x56, 0x78]),
+            Err(LoadError::DevicePage { .. })
+        ));
+        assert!(vm.programs.is_empty());
+        assert!(vm.memory.contents().iter().all(|&word| word == 0));
+
+        // an empty image at the very end of memory loads nothing
+        let images = vm.load_bytes("image.obj", &[0xFF, 0xFF]).unwrap();
+        assert_eq!(images[0].length, 0);
+        assert_eq!(vm.registers.read_program_counter(), 0xFFFF);
+
+        let images = vm
+            .load_bytes("image.obj", &[0x30, 0x00, 0xF0, 0x25])
+            .unwrap();
+        assert_eq!(images[0].end(), 0x3000);
+        assert_eq!(vm.peek_memory(0x3000), 0xF025);
+        assert_eq!(vm.programs.len(), 2);
+    }
+
+    #[test]
+    fn test_first_program_sets_the_entry_point() {
+        let directory = std::env::temp_dir();
+        let main = directory.join("rust-vm-test-main.obj");
+        let library = directory.join("rust-vm-test-library.obj");
+        fs::write(&main, [0x40, 0x00, 0xF0, 0x25]).unwrap();
+        fs::write(&library, [0x50, 0x00, 0x12, 0x34, 0x56, 0x78]).unwrap();
+
+        let mut vm = VirtualMachine::create();
+        vm.load_program(main.to_str().unwrap()).unwrap();
+        let images = vm.load_program(library.to_str().unwrap()).unwrap();
+
+        assert_eq!(images[0].end(), 0x5001);
+        assert_eq!(vm.registers.read_program_counter(), 0x4000);
+        assert_eq!(vm.programs.len(), 2);
+        assert_eq!(vm.read_memory(0x5001), 0x5678);
+    }
+
+    #[test]
+    fn test_faults_stop_the_machine() {
+        let mut vm = VirtualMachine::create();
+        // JSR #1 ; HALT ; TRAP x30
+        vm.memory.write(0x3000, 0b0100_1_00000000001);
+        vm.memory.write(0x3001, 0xF025);
+        vm.memory.write(0x3002, 0xF030);
+        let reason = vm.execute_program();
+        assert_eq!(
+            reason,
+            StopReason::Fault(Fault::UnknownTrap {
+                address: 0x3002,
+                vector: 0x30
+            })
+        );
+        assert_eq!(vm.backtrace(0x3002, false), "#0   x3002\n#1   x3000\n");
+
+        let mut vm = VirtualMachine::create();
+        vm.memory.write(0x3000, 0x8000);
+        assert_eq!(
+            vm.execute_program(),
+            StopReason::Fault(Fault::IllegalOpcode {
+                address: 0x3000,
+                instruction: 0x8000
+            })
+        );
+    }
+
+    #[test]
+    fn test_replayed_input_reproduces_run() {
+        let log_path = std::env::temp_dir().join("rust-vm-test-replay.log");
+
+        let program = |vm: &mut VirtualMachine| {
+            // loop: GETC ; NOT R0, R0 ; STR R0, R1, #0 ; LEA R1, #-3 ; BRnzp loop
+            vm.memory.write(0x3000, 0xF020);
+            vm.memory.write(0x3001, 0b1001_000_000_111111);
+            vm.memory.write(0x3002, 0b0111_000_001_000000);
+            vm.memory.write(0x3003, 0b1110_001_111111101);
+            vm.memory.write(0x3004, 0b0000_111_111111011);
+        };
+
+        let mut recorded = VirtualMachine::create();
+        program(&mut recorded);
+        recorded.memory.keyboard = Keyboard::from_bytes(b"hi");
+        let log = File::create(&log_path).unwrap();
+        recorded.memory.keyboard.record(Box::new(log));
+        for _ in 0..8 {
+            recorded.step();
+        }
+
+        let mut replayed = VirtualMachine::create();
+        program(&mut replayed);
+        let log = BufReader::new(File::open(&log_path).unwrap());
+        replayed
+            .memory
+            .keyboard
+            .replay(read_input_log(log).unwrap());
+        for _ in 0..8 {
+            replayed.step();
+        }
+
+        assert_eq!(replayed.registers, recorded.registers);
+        assert_eq!(replayed.memory.contents(), recorded.memory.contents());
+    }
+
+    #[test]
+    fn test_diverged_replay_stops_the_machine() {
+        let mut vm = VirtualMachine::create();
+        // ADD R0, R0, #0 ; GETC
+        vm.memory.write(0x3000, 0b0001_000_000_1_00000);
+        vm.memory.write(0x3001, 0xF020);
+        vm.memory.keyboard.replay(vec![InputEvent {
+            step: 0,
+            byte: b'x',
+        }]);
+        assert_eq!(
+            vm.execute_program(),
+            StopReason::ReplayDiverged {
+                step: 1,
+                recorded: 0
+            }
+        );
+    }
 }
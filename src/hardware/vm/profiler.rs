@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::hardware::instructions::jmp::RET;
+use crate::hardware::instructions::Instructions;
+
+use super::symbols::SymbolTable;
+
+const REPORT_ROWS: usize = 10;
+
+struct Frame {
+    entry: u16,
+    started: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RoutineStats {
+    pub calls: u64,
+    /// Instructions executed in the routine itself.
+    pub flat: u64,
+    /// Instructions executed in the routine and everything it called.
+    pub inclusive: u64,
+}
+
+/**
+Counts executed instructions per address and attributes them to subroutines.
+
+Subroutines are tracked from JSR/JSRR entries and `JMP R7` (RET) returns; whatever runs before
+the first call is attributed to the program's entry point. Taken backward branches and jumps are
+counted as loop back edges.
+*/
+pub struct Profiler {
+    total: u64,
+    hits: Vec<u64>,
+    stack: Vec<Frame>,
+    active: HashMap<u16, u32>,
+    routines: HashMap<u16, RoutineStats>,
+    back_edges: HashMap<(u16, u16), u64>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            total: 0,
+            hits: vec![0; 1 << 16],
+            stack: Vec::new(),
+            active: HashMap::new(),
+            routines: HashMap::new(),
+            back_edges: HashMap::new(),
+        }
+    }
+
+    /**
+    Accounts one executed instruction. `next_pc` is the program counter after it ran.
+    */
+    pub fn record(&mut self, pc: u16, instruction: u16, next_pc: u16) {
+        if self.stack.is_empty() {
+            self.enter(pc);
+        }
+
+        self.total += 1;
+        self.hits[pc as usize] += 1;
+        let current = self
+            .stack
+            .last()
+            .expect("profiler stack is never empty")
+            .entry;
+        self.routines.entry(current).or_default().flat += 1;
+
+        match Instructions::try_from(instruction >> 12) {
+            Ok(Instructions::JSR) => {
+                self.enter(next_pc);
+                self.routines.entry(next_pc).or_default().calls += 1;
+            }
+            Ok(Instructions::JMP) if instruction == RET => self.leave(),
+            Ok(Instructions::BR) | Ok(Instructions::JMP) if next_pc <= pc => {
+                *self.back_edges.entry((pc, next_pc)).or_default() += 1;
+            }
+            _ => {}
+        }
+    }
+
+    fn enter(&mut self, entry: u16) {
+        self.stack.push(Frame {
+            entry,
+            started: self.total,
+        });
+        *self.active.entry(entry).or_default() += 1;
+    }
+
+    fn leave(&mut self) {
+        // a RET without a matching call, the entry point's frame is kept
+        if self.stack.len() == 1 {
+            return;
+        }
+        let frame = self.stack.pop().expect("profiler stack is never empty");
+        let active = self.active.get_mut(&frame.entry).expect("frame is active");
+        *active -= 1;
+        // with recursion only the outermost activation counts, so nothing is counted twice
+        if *active == 0 {
+            self.routines.entry(frame.entry).or_default().inclusive += self.total - frame.started;
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn hits(&self, address: u16) -> u64 {
+        self.hits[address as usize]
+    }
+
+    /**
+    Per routine statistics, including the time spent in routines that have not returned yet.
+    */
+    pub fn routines(&self) -> HashMap<u16, RoutineStats> {
+        let mut routines = self.routines.clone();
+        let mut counted = Vec::new();
+        for frame in &self.stack {
+            if !counted.contains(&frame.entry) {
+                counted.push(frame.entry);
+                routines.entry(frame.entry).or_default().inclusive += self.total - frame.started;
+            }
+        }
+        routines
+    }
+
+    pub fn report(&self, symbols: &SymbolTable) -> String {
+        let mut report = String::new();
+        writeln!(report, "Profile: {} instructions", self.total).unwrap();
+
+        let mut routines: Vec<(u16, RoutineStats)> = self.routines().into_iter().collect();
+        routines.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(&b.0)));
+
+        writeln!(report, "\nRoutines:").unwrap();
+        writeln!(
+            report,
+            "{:>12} {:>12} {:>8}  routine",
+            "flat", "inclusive", "calls"
+        )
+        .unwrap();
+        for (entry, stats) in routines {
+            writeln!(
+                report,
+                "{:>12} {:>12} {:>8}  x{:04X} {}",
+                stats.flat,
+                stats.inclusive,
+                stats.calls,
+                entry,
+                symbols.label(entry).unwrap_or_default()
+            )
+            .unwrap();
+        }
+
+        let mut addresses: Vec<(u16, u64)> = (0..=u16::MAX)
+            .map(|address| (address, self.hits(address)))
+            .filter(|&(_, hits)| hits > 0)
+            .collect();
+        addresses.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        writeln!(report, "\nHottest addresses:").unwrap();
+        for (address, hits) in addresses.into_iter().take(REPORT_ROWS) {
+            writeln!(
+                report,
+                "{:>12}  x{:04X} {}",
+                hits,
+                address,
+                symbols.label(address).unwrap_or_default()
+            )
+            .unwrap();
+        }
+
+        let mut loops: Vec<(u16, u16, u64, u64)> = self
+            .back_edges
+            .iter()
+            .map(|(&(source, target), &iterations)| {
+                let body = (target..=source).map(|address| self.hits(address)).sum();
+                (target, source, iterations, body)
+            })
+            .collect();
+        loops.sort_by(|a, b| b.3.cmp(&a.3).then(a.0.cmp(&b.0)));
+
+        writeln!(report, "\nHottest loops:").unwrap();
+        writeln!(report, "{:>12} {:>12}  range", "instructions", "iterations").unwrap();
+        for (target, source, iterations, body) in loops.into_iter().take(REPORT_ROWS) {
+            writeln!(
+                report,
+                "{:>12} {:>12}  x{:04X}-x{:04X} {}",
+                body,
+                iterations,
+                target,
+                source,
+                symbols.label(target).unwrap_or_default()
+            )
+            .unwrap();
+        }
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_attributes_instructions_to_subroutines() {
+        let mut profiler = Profiler::new();
+
+        // x3000 JSR x3010 ; x3010 BR x3010 (twice) ; x3011 RET ; x3001 HALT
+        profiler.record(0x3000, 0b0100_1_00000001111, 0x3010);
+        profiler.record(0x3010, 0b0000_111_111111111, 0x3010);
+        profiler.record(0x3010, 0b0000_000_000000000, 0x3011);
+        profiler.record(0x3011, RET, 0x3001);
+        profiler.record(0x3001, 0xF025, 0x3002);
+
+        let routines = profiler.routines();
+        assert_eq!(
+            routines[&0x3000],
+            RoutineStats {
+                calls: 0,
+                flat: 2,
+                inclusive: 5
+            }
+        );
+        assert_eq!(
+            routines[&0x3010],
+            RoutineStats {
+                calls: 1,
+                flat: 3,
+                inclusive: 3
+            }
+        );
+        assert_eq!(profiler.hits(0x3010), 2);
+
+        let mut symbols = SymbolTable::empty();
+        symbols.insert("WAIT", 0x3010);
+        let report = profiler.report(&symbols);
+        assert!(report.contains("x3010 WAIT"));
+        assert!(report.contains("x3010-x3010 WAIT"));
+    }
+
+    #[test]
+    fn test_recursion_is_not_counted_twice() {
+        let mut profiler = Profiler::new();
+
+        // x3000 JSR x3010 ; x3010 JSR x3010 ; x3010 RET... unwinding both frames
+        profiler.record(0x3000, 0b0100_1_00000001111, 0x3010);
+        profiler.record(0x3010, 0b0100_1_11111111111, 0x3010);
+        profiler.record(0x3010, RET, 0x3011);
+        profiler.record(0x3011, RET, 0x3001);
+
+        assert_eq!(profiler.routines()[&0x3010].inclusive, 3);
+        assert_eq!(profiler.routines()[&0x3010].calls, 2);
+    }
+}
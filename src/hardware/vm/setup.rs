@@ -0,0 +1,511 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::hardware::instructions::ConditionalFlags;
+use crate::hardware::registers::{COND, PC};
+
+use super::symbols::parse_address;
+use super::VirtualMachine;
+
+/**
+The machine state a program starts from, read from a setup file and applied after the programs are loaded:
+
+```text
+# registers, the PC and the condition codes
+R1 = x4000            ; a pointer to the list below
+R2 = -1
+PC = START
+COND = z              ; n, z or p, or the whole PSR: PSR = x8002
+
+# memory, by address or symbol
+x4000 = 3, 1, 4, 0    ; an array from x4000 on
+PROMPT = "> "         ; a zero-terminated string
+RESULT = 'A'
+x5000-x50FF = 0       ; every cell of a range
+
+input "12\n"          ; queued keyboard input, read before anything else
+```
+
+Values are decimals (`-1`, `#10`), hex (`x10`, `0x10`), characters (`'A'`, `'\n'`) or symbols,
+resolved when the setup is applied. `#` and `;` start comments, commas separate values like spaces do.
+*/
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Setup {
+    pub entries: Vec<Entry>,
+    pub input: Vec<u8>,
+}
+
+/**
+One assignment, in file order.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub line: usize,
+    pub target: Target,
+    pub values: Vec<Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// R0 to R7 or the PC, by register index.
+    Register(u16),
+    Cond,
+    /// The processor status register. The VM only runs in user mode at priority 0, so only its condition codes
+    /// can change.
+    Psr,
+    /// Consecutive cells from an address or symbol on.
+    Memory(String),
+    /// Every cell from the first to the second address or symbol, both included.
+    Range(String, String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Word(u16),
+    Symbol(String),
+    /// Characters, one per word, followed by a zero word.
+    String(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SetupError {
+    /// Line of the setup file, 0 when the problem is not tied to one.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SetupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            0 => write!(f, "{}", self.message),
+            line => write!(f, "line {}: {}", line, self.message),
+        }
+    }
+}
+
+/**
+The index of a register named `R0` to `R7` or `PC`, in any case.
+*/
+pub fn register_index(name: &str) -> Option<u16> {
+    match name.to_ascii_uppercase().as_str() {
+        "PC" => Some(PC),
+        name => match name.strip_prefix('R')?.parse() {
+            Ok(register) if register < 8 => Some(register),
+            _ => None,
+        },
+    }
+}
+
+impl Value {
+    /**
+    A number, a character in single quotes or a symbol. `None` for text that is none of these, e.g. `12abc`.
+    */
+    pub fn parse(text: &str) -> Option<Value> {
+        if let Some(quoted) = text.strip_prefix('\'') {
+            let bytes = unescape(quoted.strip_suffix('\'')?)?;
+            return match bytes[..] {
+                [byte] => Some(Value::Word(byte as u16)),
+                _ => None,
+            };
+        }
+        let decimal = text.strip_prefix('#').unwrap_or(text);
+        if let Ok(number) = decimal.parse::<i32>() {
+            let word = u16::try_from(number)
+                .ok()
+                .or_else(|| i16::try_from(number).ok().map(|number| number as u16))?;
+            return Some(Value::Word(word));
+        }
+        if let Some(word) = parse_address(text) {
+            return Some(Value::Word(word));
+        }
+        let symbol = text
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+        match text.chars().next() {
+            Some(first) if symbol && !first.is_ascii_digit() => {
+                Some(Value::Symbol(text.to_string()))
+            }
+            _ => None,
+        }
+    }
+
+    fn words(&self, vm: &VirtualMachine) -> Result<Vec<u16>, String> {
+        match self {
+            Value::Word(word) => Ok(vec![*word]),
+            Value::Symbol(name) => vm
+                .symbols
+                .resolve(name)
+                .map(|address| vec![address])
+                .ok_or_else(|| format!("unknown symbol '{}'", name)),
+            Value::String(bytes) => Ok(bytes.iter().map(|&byte| byte as u16).chain([0]).collect()),
+        }
+    }
+}
+
+impl Setup {
+    pub fn parse(text: &str) -> Result<Setup, SetupError> {
+        let mut setup = Setup::default();
+        for (index, text) in text.lines().enumerate() {
+            let line = index + 1;
+            let error = |message: String| SetupError { line, message };
+            let tokens = tokenize(text).map_err(error)?;
+            match &tokens[..] {
+                [] => {}
+                [Token::Word(keyword), Token::Text(bytes)] if keyword == "input" => {
+                    setup.input.extend(bytes)
+                }
+                [Token::Word(target), Token::Equals, values @ ..] => {
+                    let target = parse_target(target).map_err(error)?;
+                    let values = values
+                        .iter()
+                        .map(|token| match token {
+                            Token::Word(word) => match (&target, condition_flag(word)) {
+                                (Target::Cond, Some(flag)) => Ok(Value::Word(flag)),
+                                _ => Value::parse(word)
+                                    .ok_or_else(|| error(format!("invalid value '{}'", word))),
+                            },
+                            Token::Text(bytes) => Ok(Value::String(bytes.clone())),
+                            Token::Equals => Err(error("unexpected '='".to_string())),
+                        })
+                        .collect::<Result<Vec<Value>, SetupError>>()?;
+                    let single = !matches!(target, Target::Memory(_));
+                    if values.is_empty() || single && values.len() > 1 {
+                        return Err(error(format!(
+                            "expected {} after '='",
+                            if single { "one value" } else { "values" }
+                        )));
+                    }
+                    setup.entries.push(Entry {
+                        line,
+                        target,
+                        values,
+                    });
+                }
+                _ => {
+                    return Err(error(
+                        "expected '<target> = <values>' or 'input \"<text>\"'".to_string(),
+                    ))
+                }
+            }
+        }
+        Ok(setup)
+    }
+
+    pub fn load(path: &Path) -> Result<Setup, SetupError> {
+        let text = fs::read_to_string(path).map_err(|error| SetupError {
+            line: 0,
+            message: format!("failed to read {}: {}", path.display(), error),
+        })?;
+        Setup::parse(&text)
+    }
+
+    /**
+    Writes the registers and memory in file order and queues the input. Symbols are looked up in the programs
+    loaded so far. The input goes in front of any other pending input. Stops at the first entry that does not
+    apply, e.g. an unknown symbol.
+    */
+    pub fn apply(&self, vm: &mut VirtualMachine) -> Result<(), SetupError> {
+        for entry in &self.entries {
+            entry.apply(vm).map_err(|message| SetupError {
+                line: entry.line,
+                message,
+            })?;
+        }
+        for &byte in self.input.iter().rev() {
+            vm.memory.keyboard.pending.push_front(byte);
+        }
+        Ok(())
+    }
+}
+
+impl Entry {
+    fn apply(&self, vm: &mut VirtualMachine) -> Result<(), String> {
+        let mut words = Vec::new();
+        for value in &self.values {
+            words.extend(value.words(vm)?);
+        }
+        let address = |name: &str| {
+            vm.symbols
+                .resolve(name)
+                .ok_or_else(|| format!("unknown address '{}'", name))
+        };
+
+        match &self.target {
+            Target::Register(register) => vm.registers.update(*register, single(&words)?),
+            Target::Cond => {
+                let cond = single(&words)?;
+                vm.registers.update(COND, condition_codes(cond)?);
+            }
+            Target::Psr => {
+                let psr = single(&words)?;
+                if psr & !0b111 != 0x8000 {
+                    return Err(format!(
+                        "PSR x{:04X} is not user mode at priority 0, the only mode the VM runs in",
+                        psr
+                    ));
+                }
+                vm.registers.update(COND, condition_codes(psr & 0b111)?);
+            }
+            Target::Memory(start) => {
+                let start = address(start)?;
+                for (offset, word) in words.into_iter().enumerate() {
+                    vm.memory.write(start.wrapping_add(offset as u16), word);
+                }
+            }
+            Target::Range(start, end) => {
+                let (start, end) = (address(start)?, address(end)?);
+                if start > end {
+                    return Err(format!("range x{:04X}-x{:04X} is empty", start, end));
+                }
+                let word = single(&words)?;
+                for address in start..=end {
+                    vm.memory.write(address, word);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn single(words: &[u16]) -> Result<u16, String> {
+    match words {
+        [word] => Ok(*word),
+        _ => Err("expected a single word, not a string".to_string()),
+    }
+}
+
+/**
+Exactly one of N, Z and P: anything else would leave the machine in a state no instruction can produce.
+*/
+fn condition_codes(value: u16) -> Result<u16, String> {
+    let flags = [
+        ConditionalFlags::Negative as u16,
+        ConditionalFlags::Zero as u16,
+        ConditionalFlags::Positive as u16,
+    ];
+    if flags.contains(&value) {
+        Ok(value)
+    } else {
+        Err(format!(
+            "condition codes x{:X} must be exactly one of n, z and p",
+            value
+        ))
+    }
+}
+
+fn condition_flag(text: &str) -> Option<u16> {
+    let flag = match text.to_ascii_lowercase().as_str() {
+        "n" => ConditionalFlags::Negative,
+        "z" => ConditionalFlags::Zero,
+        "p" => ConditionalFlags::Positive,
+        _ => return None,
+    };
+    Some(flag as u16)
+}
+
+fn parse_target(text: &str) -> Result<Target, String> {
+    if let Some(register) = register_index(text) {
+        return Ok(Target::Register(register));
+    }
+    match text.to_ascii_uppercase().as_str() {
+        "COND" => return Ok(Target::Cond),
+        "PSR" => return Ok(Target::Psr),
+        _ => {}
+    }
+    match text.split_once('-') {
+        Some((start, end)) if !start.is_empty() && !end.is_empty() => {
+            Ok(Target::Range(start.to_string(), end.to_string()))
+        }
+        Some(_) => Err(format!("invalid range '{}'", text)),
+        None => Ok(Target::Memory(text.to_string())),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Text(Vec<u8>),
+    Equals,
+}
+
+/**
+Splits a line into words, quoted strings and `=`, dropping commas and the comment. `#` starts a comment unless it
+is a decimal like `#10` after the `=`.
+*/
+fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = line.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+    while let Some(c) = rest.chars().next() {
+        let decimal = rest[c.len_utf8()..].starts_with(|c: char| c.is_ascii_digit() || c == '-');
+        let length = match c {
+            ';' => break,
+            '#' if !decimal || !tokens.contains(&Token::Equals) => break,
+            '=' => {
+                tokens.push(Token::Equals);
+                1
+            }
+            '"' | '\'' => {
+                let length = quoted(rest).ok_or_else(|| "unterminated quotes".to_string())?;
+                if c == '"' {
+                    let text = &rest[1..length - 1];
+                    let bytes =
+                        unescape(text).ok_or_else(|| format!("invalid string \"{}\"", text))?;
+                    tokens.push(Token::Text(bytes));
+                } else {
+                    tokens.push(Token::Word(rest[..length].to_string()));
+                }
+                length
+            }
+            _ => {
+                let length = rest
+                    .find(|c: char| c.is_whitespace() || matches!(c, ',' | ';' | '=' | '"'))
+                    .unwrap_or(rest.len());
+                tokens.push(Token::Word(rest[..length].to_string()));
+                length
+            }
+        };
+        rest = rest[length..].trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+    }
+    Ok(tokens)
+}
+
+/**
+The length of the quoted text at the start of `text`, both quotes included.
+*/
+fn quoted(text: &str) -> Option<usize> {
+    let quote = text.chars().next()?;
+    let mut escaped = false;
+    for (index, c) in text.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            c if c == quote => return Some(index + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+/**
+The bytes of `text` with `\n`, `\t`, `\r`, `\0`, `\\`, `\"`, `\'` and `\xHH` replaced. `None` for other escapes and
+characters beyond ASCII.
+*/
+fn unescape(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if !c.is_ascii() {
+            return None;
+        }
+        if c != '\\' {
+            bytes.push(c as u8);
+            continue;
+        }
+        let byte = match chars.next()? {
+            'n' => b'\n',
+            't' => b'\t',
+            'r' => b'\r',
+            '0' => 0,
+            '\\' => b'\\',
+            '"' => b'"',
+            '\'' => b'\'',
+            'x' => {
+                let digits: String = chars.by_ref().take(2).collect();
+                u8::from_str_radix(&digits, 16).ok()?
+            }
+            _ => return None,
+        };
+        bytes.push(byte);
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const SETUP: &str = "\
+# a list and a pointer to it
+R1 = LIST
+R2 = -1 ; all ones
+PC = x3000
+COND = p
+LIST = 3, 1, #4, 0
+PROMPT = \"> \\\"\\n\"   # an escaped quote
+x4100 = 'A' '\\n' ';' 0x10
+x5000-x5002 = ','
+input \"12\\n\"
+";
+
+    #[test]
+    fn test_setup_is_applied_in_file_order() {
+        let setup = Setup::parse(SETUP).unwrap();
+        assert_eq!(setup.entries.len(), 8);
+        assert_eq!(setup.input, b"12\n");
+        assert_eq!(
+            setup.entries[5].values,
+            vec![Value::String(b"> \"\n".to_vec())]
+        );
+
+        let mut vm = VirtualMachine::create();
+        vm.symbols.insert("LIST", 0x4000);
+        vm.symbols.insert("PROMPT", 0x4010);
+        vm.registers.update_program_counter(0x4000);
+        vm.memory.keyboard.pending.extend(b"34");
+        setup.apply(&mut vm).unwrap();
+
+        assert_eq!(vm.read_register(1), 0x4000);
+        assert_eq!(vm.read_register(2), 0xFFFF);
+        assert_eq!(vm.registers.read_program_counter(), 0x3000);
+        assert_eq!(vm.registers.read_cond(), ConditionalFlags::Positive as u16);
+        assert_eq!(vm.memory.peek_range(0x4000, 0x4003), [3, 1, 4, 0]);
+        assert_eq!(vm.memory.peek_range(0x4010, 0x4014), [62, 32, 34, 10, 0]);
+        assert_eq!(vm.memory.peek_range(0x4100, 0x4103), [65, 10, 59, 16]);
+        assert_eq!(vm.memory.peek_range(0x4FFF, 0x5003), [0, 44, 44, 44, 0]);
+        assert_eq!(vm.memory.keyboard.pending, b"12\n34");
+    }
+
+    #[test]
+    fn test_setup_errors_name_the_line() {
+        let error = |text: &str| Setup::parse(text).unwrap_err().to_string();
+        assert_eq!(
+            error("R1 = 1\nR1 = 1 2"),
+            "line 2: expected one value after '='"
+        );
+        assert_eq!(
+            error("R8 2"),
+            "line 1: expected '<target> = <values>' or 'input \"<text>\"'"
+        );
+        assert_eq!(error("x3000 = 12ab"), "line 1: invalid value '12ab'");
+        assert_eq!(error("x3000 = \"open"), "line 1: unterminated quotes");
+        assert_eq!(error("x3000 = 70000"), "line 1: invalid value '70000'");
+        assert_eq!(error("x3000 = é"), "line 1: invalid value 'é'");
+
+        let mut vm = VirtualMachine::create();
+        let apply = |text: &str, vm: &mut VirtualMachine| {
+            Setup::parse(text)
+                .unwrap()
+                .apply(vm)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            apply("\nR0 = NOWHERE", &mut vm),
+            "line 2: unknown symbol 'NOWHERE'"
+        );
+        assert_eq!(
+            apply("COND = 3", &mut vm),
+            "line 1: condition codes x3 must be exactly one of n, z and p"
+        );
+        assert_eq!(
+            apply("PSR = x0002", &mut vm),
+            "line 1: PSR x0002 is not user mode at priority 0, the only mode the VM runs in"
+        );
+        assert_eq!(
+            apply("R0 = \"ab\"", &mut vm),
+            "line 1: expected a single word, not a string"
+        );
+    }
+}
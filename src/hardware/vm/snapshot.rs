@@ -0,0 +1,324 @@
+use std::fmt;
+use std::io::{self, Cursor, Read, Write};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::hardware::instructions::ConditionalFlags;
+use crate::hardware::memory::MEMORY_MAX;
+use crate::hardware::registers::{Registers, COND};
+
+use super::VirtualMachine;
+
+const MAGIC: &[u8; 4] = b"LC3S";
+const VERSION: u16 = 1;
+
+/**
+The complete state of a machine, saved to and restored from a versioned file.
+
+Layout (all values big-endian):
+
+|field|size|
+|---|---|
+| magic `LC3S` | 4 bytes |
+| version | u16 |
+| R0-R7, PC | 9 × u16 |
+| PSR | u16 |
+| executed instruction count | u64 |
+| output bytes written | u64 |
+| input bytes read | u64 |
+| memory word count | u32 |
+| memory | count × u16 |
+| pending input byte count | u32 |
+| pending input | count × u8 |
+| CRC-32 of everything above | u32 |
+
+The keyboard device registers are memory mapped, so they are saved as part of memory. The output and input
+counters are saved so the run limits keep counting from where they were. The VM only runs in user mode at
+priority 0, so the PSR must be `x8000` plus exactly one condition code and there are no saved stack pointers.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub registers: Registers,
+    pub steps: u64,
+    pub output_written: u64,
+    pub input_reads: u64,
+    pub memory: Vec<u16>,
+    pub pending_input: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    NotASnapshot,
+    UnsupportedVersion(u16),
+    InvalidPsr(u16),
+    ChecksumMismatch { stored: u32, computed: u32 },
+    MemorySize(usize),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Io(error) => write!(f, "failed to access snapshot: {}", error),
+            SnapshotError::NotASnapshot => write!(f, "not a snapshot file"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot version {}", version)
+            }
+            SnapshotError::InvalidPsr(psr) => write!(
+                f,
+                "PSR x{:04X} is not user mode at priority 0 with one condition code",
+                psr
+            ),
+            SnapshotError::ChecksumMismatch { stored, computed } => write!(
+                f,
+                "snapshot is corrupt: checksum {:08X} does not match contents {:08X}",
+                stored, computed
+            ),
+            SnapshotError::MemorySize(size) => {
+                write!(
+                    f,
+                    "snapshot holds {} memory words, expected {}",
+                    size, MEMORY_MAX
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(error: io::Error) -> Self {
+        if error.kind() == io::ErrorKind::UnexpectedEof {
+            SnapshotError::NotASnapshot
+        } else {
+            SnapshotError::Io(error)
+        }
+    }
+}
+
+impl Snapshot {
+    pub fn capture(vm: &VirtualMachine) -> Snapshot {
+        Snapshot {
+            registers: vm.registers,
+            steps: vm.steps,
+            output_written: vm.memory.display.written,
+            input_reads: vm.memory.keyboard.reads,
+            memory: vm.memory.contents().to_vec(),
+            pending_input: vm.memory.keyboard.pending.iter().copied().collect(),
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut data = Vec::with_capacity(self.memory.len() * 2 + 64);
+
+        data.write_all(MAGIC)?;
+        data.write_u16::<BigEndian>(VERSION)?;
+        for index in 0..9 {
+            data.write_u16::<BigEndian>(self.registers.read(index))?;
+        }
+        data.write_u16::<BigEndian>(0x8000 | self.registers.read_cond())?;
+        data.write_u64::<BigEndian>(self.steps)?;
+        data.write_u64::<BigEndian>(self.output_written)?;
+        data.write_u64::<BigEndian>(self.input_reads)?;
+        data.write_u32::<BigEndian>(self.memory.len() as u32)?;
+        for &word in &self.memory {
+            data.write_u16::<BigEndian>(word)?;
+        }
+        data.write_u32::<BigEndian>(self.pending_input.len() as u32)?;
+        data.write_all(&self.pending_input)?;
+
+        let checksum = crc32(&data);
+        data.write_u32::<BigEndian>(checksum)?;
+
+        writer.write_all(&data)
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Snapshot, SnapshotError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+
+        if data.len() < MAGIC.len() + 2 || &data[..MAGIC.len()] != MAGIC {
+            return Err(SnapshotError::NotASnapshot);
+        }
+        let version = u16::from_be_bytes([data[4], data[5]]);
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        if data.len() < MAGIC.len() + 2 + 4 {
+            return Err(SnapshotError::NotASnapshot);
+        }
+
+        let (contents, stored) = data.split_at(data.len() - 4);
+        let stored = u32::from_be_bytes([stored[0], stored[1], stored[2], stored[3]]);
+        let computed = crc32(contents);
+        if stored != computed {
+            return Err(SnapshotError::ChecksumMismatch { stored, computed });
+        }
+
+        let mut cursor = Cursor::new(&contents[MAGIC.len() + 2..]);
+
+        let mut registers = Registers::initial();
+        for index in 0..9 {
+            registers.update(index, cursor.read_u16::<BigEndian>()?);
+        }
+        let psr = cursor.read_u16::<BigEndian>()?;
+        let flags = [
+            ConditionalFlags::Negative as u16,
+            ConditionalFlags::Zero as u16,
+            ConditionalFlags::Positive as u16,
+        ];
+        if psr & !0b111 != 0x8000 || !flags.contains(&(psr & 0b111)) {
+            return Err(SnapshotError::InvalidPsr(psr));
+        }
+        registers.update(COND, psr & 0b111);
+        let steps = cursor.read_u64::<BigEndian>()?;
+        let output_written = cursor.read_u64::<BigEndian>()?;
+        let input_reads = cursor.read_u64::<BigEndian>()?;
+
+        let count = read_count(&mut cursor, 2)?;
+        if count != MEMORY_MAX {
+            return Err(SnapshotError::MemorySize(count));
+        }
+        let mut memory = vec![0; count];
+        cursor.read_u16_into::<BigEndian>(&mut memory)?;
+
+        let count = read_count(&mut cursor, 1)?;
+        let mut pending_input = vec![0; count];
+        cursor.read_exact(&mut pending_input)?;
+
+        Ok(Snapshot {
+            registers,
+            steps,
+            output_written,
+            input_reads,
+            memory,
+            pending_input,
+        })
+    }
+}
+
+/**
+Reads an item count and checks that the rest of the file can hold that many items of `size` bytes, before anything
+is allocated for them.
+*/
+fn read_count(cursor: &mut Cursor<&[u8]>, size: usize) -> Result<usize, SnapshotError> {
+    let count = cursor.read_u32::<BigEndian>()? as usize;
+    let remaining = cursor.get_ref().len() - cursor.position() as usize;
+    if count > remaining / size {
+        return Err(SnapshotError::NotASnapshot);
+    }
+    Ok(count)
+}
+
+/**
+CRC-32 (IEEE 802.3), as used by zip and png.
+*/
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut vm = VirtualMachine::create();
+        vm.memory.write(0x3000, 0b1110_001_000000010);
+        vm.memory.write(0x4000, 0xBEEF);
+        vm.memory.keyboard.pending.extend(b"wasd");
+        vm.step();
+        vm.memory.display.written = 12;
+        vm.memory.keyboard.reads = 3;
+
+        let mut file = Vec::new();
+        Snapshot::capture(&vm).write_to(&mut file).unwrap();
+
+        let mut restored = VirtualMachine::create();
+        restored.restore_snapshot(&Snapshot::read_from(&mut file.as_slice()).unwrap());
+
+        assert_eq!(restored.registers, vm.registers);
+        assert_eq!(restored.steps, 1);
+        assert_eq!(restored.read_memory(0x4000), 0xBEEF);
+        assert_eq!(restored.memory.contents(), vm.memory.contents());
+        assert_eq!(restored.memory.display.written, 12);
+        assert_eq!(restored.memory.keyboard.reads, 3);
+        assert_eq!(restored.memory.keyboard.read_byte(), Some(b'w'));
+    }
+
+    #[test]
+    fn test_snapshot_detects_corruption() {
+        let vm = VirtualMachine::create();
+        let mut file = Vec::new();
+        Snapshot::capture(&vm).write_to(&mut file).unwrap();
+
+        file[100] ^= 0x1;
+        assert!(matches!(
+            Snapshot::read_from(&mut file.as_slice()),
+            Err(SnapshotError::ChecksumMismatch { .. })
+        ));
+
+        file[5] = 99;
+        assert!(matches!(
+            Snapshot::read_from(&mut file.as_slice()),
+            Err(SnapshotError::UnsupportedVersion(99))
+        ));
+
+        assert!(matches!(
+            Snapshot::read_from(&mut "hello".as_bytes()),
+            Err(SnapshotError::NotASnapshot)
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_rejects_invalid_psr_and_oversized_counts() {
+        let file = |edit: &dyn Fn(&mut Vec<u8>)| {
+            let mut file = Vec::new();
+            Snapshot::capture(&VirtualMachine::create())
+                .write_to(&mut file)
+                .unwrap();
+            file.truncate(file.len() - 4);
+            edit(&mut file);
+            file.extend(crc32(&file).to_be_bytes());
+            Snapshot::read_from(&mut file.as_slice())
+        };
+        // magic, version and R0-R7, PC come before the PSR, the counters after it
+        let psr = 4 + 2 + 9 * 2;
+        let memory_count = psr + 2 + 3 * 8;
+
+        assert!(matches!(
+            file(&|file| file[psr + 1] = 3),
+            Err(SnapshotError::InvalidPsr(0x8003))
+        ));
+        assert!(matches!(
+            file(&|file| file[psr] = 0),
+            Err(SnapshotError::InvalidPsr(0x0002))
+        ));
+        assert!(matches!(
+            file(&|file| file[memory_count..memory_count + 4].copy_from_slice(&[0xFF; 4])),
+            Err(SnapshotError::NotASnapshot)
+        ));
+        assert!(matches!(
+            file(&|file| {
+                let end = file.len();
+                file[end - 4..].copy_from_slice(&[0xFF; 4]);
+            }),
+            Err(SnapshotError::NotASnapshot)
+        ));
+    }
+}
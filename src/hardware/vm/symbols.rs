@@ -0,0 +1,163 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+/**
+Labels and their addresses, as written by the LC-3 assembler to a `.sym` file next to the object file:
+
+```text
+// Symbol table
+// Scope level 0:
+//  Symbol Name       Page Address
+//  ----------------  ------------
+//  START             3000
+//  LOOP              3004
+```
+
+Lines of the form `LOOP x3004` are accepted as well.
+*/
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    by_address: BTreeMap<u16, String>,
+    by_name: HashMap<String, u16>,
+}
+
+impl SymbolTable {
+    pub fn empty() -> SymbolTable {
+        SymbolTable::default()
+    }
+
+    pub fn parse<R: BufRead>(reader: R) -> io::Result<SymbolTable> {
+        let mut table = SymbolTable::empty();
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim_start_matches("//");
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if let [name, address] = fields[..] {
+                if let Some(address) = parse_hex(address) {
+                    table.insert(name, address);
+                }
+            }
+        }
+        Ok(table)
+    }
+
+    /**
+    Loads the `.sym` file that belongs to `program`, if there is one.
+    */
+    pub fn for_program(program: &str) -> io::Result<Option<SymbolTable>> {
+        let path = Path::new(program).with_extension("sym");
+        match File::open(path) {
+            Ok(file) => SymbolTable::parse(BufReader::new(file)).map(Some),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    pub fn insert(&mut self, name: &str, address: u16) {
+        self.by_address.insert(address, name.to_string());
+        self.by_name.insert(name.to_string(), address);
+    }
+
+    pub fn extend(&mut self, other: SymbolTable) {
+        for (address, name) in other.by_address {
+            self.insert(&name, address);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_address.is_empty()
+    }
+
+    pub fn name_at(&self, address: u16) -> Option<&str> {
+        self.by_address.get(&address).map(String::as_str)
+    }
+
+    pub fn address_of(&self, name: &str) -> Option<u16> {
+        self.by_name.get(name).copied()
+    }
+
+    /**
+    Resolves a symbol name or an address written as `x3000`, `0x3000` or decimal.
+    */
+    pub fn resolve(&self, text: &str) -> Option<u16> {
+        self.address_of(text).or_else(|| parse_address(text))
+    }
+
+    /**
+    Names an address relative to the closest symbol at or before it, e.g. `LOOP+2`.
+    */
+    pub fn label(&self, address: u16) -> Option<String> {
+        match self.by_address.range(..=address).next_back() {
+            Some((&base, name)) if base == address => Some(name.clone()),
+            Some((&base, name)) => Some(format!("{}+{}", name, address - base)),
+            None => None,
+        }
+    }
+
+    /**
+    Like `label`, but falls back to the address itself, e.g. `x3006`.
+    */
+    pub fn describe(&self, address: u16) -> String {
+        self.label(address)
+            .unwrap_or_else(|| format!("x{:04X}", address))
+    }
+}
+
+/**
+Parses an address written as `x3000`, `0x3000` or decimal.
+*/
+pub fn parse_address(text: &str) -> Option<u16> {
+    let hex = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix('x'))
+        .or_else(|| text.strip_prefix('X'));
+    match hex {
+        Some(digits) => u16::from_str_radix(digits, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn parse_hex(text: &str) -> Option<u16> {
+    let digits = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix('x'))
+        .unwrap_or(text);
+    u16::from_str_radix(digits, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_assembler_symbol_file() {
+        let file = "// Symbol table\n\
+                    // Scope level 0:\n\
+                    //\tSymbol Name       Page Address\n\
+                    //\t----------------  ------------\n\
+                    //\tSTART             3000\n\
+                    //\tLOOP              3004\n\
+                    DATA x4000\n";
+
+        let table = SymbolTable::parse(file.as_bytes()).unwrap();
+
+        assert_eq!(table.address_of("LOOP"), Some(0x3004));
+        assert_eq!(table.name_at(0x3000), Some("START"));
+        assert_eq!(table.describe(0x4000), "DATA");
+        assert_eq!(table.describe(0x3006), "LOOP+2");
+        assert_eq!(table.describe(0x2000), "x2000");
+        assert_eq!(table.resolve("LOOP"), Some(0x3004));
+        assert_eq!(table.resolve("x3005"), Some(0x3005));
+    }
+
+    #[test]
+    fn test_parse_address() {
+        assert_eq!(parse_address("x3000"), Some(0x3000));
+        assert_eq!(parse_address("0xFE00"), Some(0xFE00));
+        assert_eq!(parse_address("12288"), Some(0x3000));
+        assert_eq!(parse_address("x10000"), None);
+    }
+}
@@ -0,0 +1,350 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::hardware::instructions::{sign_extended, Instructions};
+use crate::hardware::memory::MemoryAccess;
+
+use super::{Step, StopReason};
+
+/**
+One executed instruction, as written to a JSON Lines trace.
+
+Each record is a single JSON object on its own line:
+
+```text
+{"step":0,"pc":12288,"instruction":4640,"opcode":"ADD","operands":{"dr":1,"imm5":0,"sr1":0},...}
+```
+
+`registers` lists the general purpose registers the instruction wrote with their new values, also when a value did
+not change, e.g. for `AND R0, R0, #0`. `cond` is the COND register after the step.
+*/
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceRecord {
+    pub step: u64,
+    pub pc: u16,
+    pub instruction: u16,
+    pub opcode: String,
+    pub operands: BTreeMap<String, i32>,
+    pub registers: Vec<RegisterWrite>,
+    pub memory_reads: Vec<MemoryValue>,
+    pub memory_writes: Vec<MemoryValue>,
+    pub cond: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegisterWrite {
+    pub register: u16,
+    pub value: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryValue {
+    pub address: u16,
+    pub value: u16,
+}
+
+#[derive(Debug)]
+pub enum TraceError {
+    Io(io::Error),
+    Parse {
+        line: usize,
+        error: serde_json::Error,
+    },
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TraceError::Io(error) => write!(f, "failed to read trace: {}", error),
+            TraceError::Parse { line, error } => {
+                write!(f, "invalid trace record on line {}: {}", line, error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+impl TraceRecord {
+    pub fn from_step(step: &Step) -> TraceRecord {
+        let registers = written_registers(step)
+            .into_iter()
+            .map(|index| RegisterWrite {
+                register: index,
+                value: step.after.read(index),
+            })
+            .collect();
+
+        let mut memory_reads = Vec::new();
+        let mut memory_writes = Vec::new();
+        for access in &step.accesses {
+            match *access {
+                MemoryAccess::Read { address, value } => {
+                    memory_reads.push(MemoryValue { address, value })
+                }
+                MemoryAccess::Write { address, value, .. } => {
+                    memory_writes.push(MemoryValue { address, value })
+                }
+            }
+        }
+
+        TraceRecord {
+            step: step.number,
+            pc: step.pc,
+            instruction: step.instruction,
+            opcode: opcode_name(step.instruction),
+            operands: decode_operands(step.instruction),
+            registers,
+            memory_reads,
+            memory_writes,
+            cond: step.after.read_cond(),
+        }
+    }
+
+    pub fn to_json_line(&self) -> String {
+        serde_json::to_string(self).expect("trace records always serialize")
+    }
+
+    pub fn parse(line: &str) -> Result<TraceRecord, serde_json::Error> {
+        serde_json::from_str(line)
+    }
+}
+
+/**
+Writes one trace record per executed instruction to the wrapped writer.
+*/
+pub struct TraceWriter {
+    writer: Box<dyn Write>,
+}
+
+impl TraceWriter {
+    pub fn new(writer: Box<dyn Write>) -> TraceWriter {
+        TraceWriter { writer }
+    }
+
+    pub fn record(&mut self, step: &Step) -> io::Result<()> {
+        writeln!(
+            self.writer,
+            "{}",
+            TraceRecord::from_step(step).to_json_line()
+        )
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/**
+Loads a JSON Lines trace back into records, skipping blank lines.
+*/
+pub fn read_trace<R: BufRead>(reader: R) -> Result<Vec<TraceRecord>, TraceError> {
+    let mut records = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line.map_err(TraceError::Io)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = TraceRecord::parse(&line).map_err(|error| TraceError::Parse {
+            line: index + 1,
+            error,
+        })?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/**
+The registers the step's instruction wrote, in index order. GETC and IN leave R0 alone when they get no input.
+*/
+fn written_registers(step: &Step) -> Vec<u16> {
+    let destination = (step.instruction >> 9) & 0x7;
+    match Instructions::try_from(step.instruction >> 12) {
+        Ok(Instructions::ADD)
+        | Ok(Instructions::AND)
+        | Ok(Instructions::NOT)
+        | Ok(Instructions::LD)
+        | Ok(Instructions::LDI)
+        | Ok(Instructions::LDR)
+        | Ok(Instructions::LEA) => vec![destination],
+        Ok(Instructions::JSR) => vec![7],
+        Ok(Instructions::TRAP) => {
+            let reads_key = matches!(step.instruction & 0xFF, 0x20 | 0x23);
+            let no_input = matches!(
+                step.stop,
+                Some(StopReason::EndOfInput)
+                    | Some(StopReason::InputLimit(_))
+                    | Some(StopReason::ReplayDiverged { .. })
+            );
+            if reads_key && !no_input {
+                vec![0, 7]
+            } else {
+                vec![7]
+            }
+        }
+        _ => vec![],
+    }
+}
+
+fn opcode_name(instruction: u16) -> String {
+    match Instructions::try_from(instruction >> 12) {
+        Ok(kind) => format!("{:?}", kind),
+        Err(()) => String::from("UNKNOWN"),
+    }
+}
+
+/**
+Splits an instruction into its named fields. Offsets and immediates are sign extended.
+*/
+pub fn decode_operands(instruction: u16) -> BTreeMap<String, i32> {
+    let field = |shift: u16, mask: u16| ((instruction >> shift) & mask) as i32;
+    let signed = |bits: u16| sign_extended(instruction & ((1 << bits) - 1), bits) as i16 as i32;
+
+    let operands: Vec<(&str, i32)> = match Instructions::try_from(instruction >> 12) {
+        Ok(Instructions::BR) => vec![
+            ("n", field(11, 0x1)),
+            ("z", field(10, 0x1)),
+            ("p", field(9, 0x1)),
+            ("pc_offset9", signed(9)),
+        ],
+        Ok(Instructions::ADD) | Ok(Instructions::AND) => {
+            if field(5, 0x1) == 1 {
+                vec![
+                    ("dr", field(9, 0x7)),
+                    ("sr1", field(6, 0x7)),
+                    ("imm5", signed(5)),
+                ]
+            } else {
+                vec![
+                    ("dr", field(9, 0x7)),
+                    ("sr1", field(6, 0x7)),
+                    ("sr2", field(0, 0x7)),
+                ]
+            }
+        }
+        Ok(Instructions::LD) | Ok(Instructions::LDI) | Ok(Instructions::LEA) => {
+            vec![("dr", field(9, 0x7)), ("pc_offset9", signed(9))]
+        }
+        Ok(Instructions::ST) | Ok(Instructions::STI) => {
+            vec![("sr", field(9, 0x7)), ("pc_offset9", signed(9))]
+        }
+        Ok(Instructions::JSR) => {
+            if field(11, 0x1) == 1 {
+                vec![("pc_offset11", signed(11))]
+            } else {
+                vec![("base", field(6, 0x7))]
+            }
+        }
+        Ok(Instructions::LDR) => vec![
+            ("dr", field(9, 0x7)),
+            ("base", field(6, 0x7)),
+            ("offset6", signed(6)),
+        ],
+        Ok(Instructions::STR) => vec![
+            ("sr", field(9, 0x7)),
+            ("base", field(6, 0x7)),
+            ("offset6", signed(6)),
+        ],
+        Ok(Instructions::NOT) => vec![("dr", field(9, 0x7)), ("sr", field(6, 0x7))],
+        Ok(Instructions::JMP) => vec![("base", field(6, 0x7))],
+        Ok(Instructions::TRAP) => vec![("trapvect8", field(0, 0xFF))],
+        Ok(Instructions::RTI) | Ok(Instructions::RES) | Err(()) => vec![],
+    };
+
+    operands
+        .into_iter()
+        .map(|(name, value)| (String::from(name), value))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::hardware::console::Keyboard;
+    use crate::hardware::vm::VirtualMachine;
+
+    #[test]
+    fn test_decode_operands() {
+        let operands = decode_operands(0b0110_001_010_111110);
+
+        assert_eq!(operands["dr"], 1);
+        assert_eq!(operands["base"], 2);
+        assert_eq!(operands["offset6"], -2);
+    }
+
+    #[test]
+    fn test_trace_round_trip() {
+        let mut vm = VirtualMachine::create();
+
+        // LEA R1, #2 ; STR R1, R1, #0 ; LDR R2, R1, #0
+        vm.memory.write(0x3000, 0b1110_001_000000010);
+        vm.memory.write(0x3001, 0b0111_001_001_000000);
+        vm.memory.write(0x3002, 0b0110_010_001_000000);
+
+        let mut output = Vec::new();
+        for _ in 0..3 {
+            let step = vm.step_recorded();
+            output.extend(TraceRecord::from_step(&step).to_json_line().bytes());
+            output.push(b'\n');
+        }
+
+        let records = read_trace(output.as_slice()).unwrap();
+
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].opcode, "LEA");
+        assert_eq!(
+            records[0].registers,
+            vec![RegisterWrite {
+                register: 1,
+                value: 0x3003
+            }]
+        );
+        assert_eq!(
+            records[1].memory_writes,
+            vec![MemoryValue {
+                address: 0x3003,
+                value: 0x3003
+            }]
+        );
+        assert_eq!(records[2].step, 2);
+        assert_eq!(records[2].pc, 0x3002);
+        assert_eq!(
+            records[2].memory_reads,
+            vec![MemoryValue {
+                address: 0x3003,
+                value: 0x3003
+            }]
+        );
+        assert_eq!(records[2].cond, 0x1);
+    }
+
+    #[test]
+    fn test_unchanged_register_writes_are_recorded() {
+        let mut vm = VirtualMachine::create();
+        vm.memory.keyboard = Keyboard::from_bytes(b"");
+
+        // AND R0, R0, #0 ; ADD R1, R1, #0 ; BRnzp #0 ; GETC
+        vm.memory.write(0x3000, 0b0101_000_000_1_00000);
+        vm.memory.write(0x3001, 0b0001_001_001_1_00000);
+        vm.memory.write(0x3002, 0b0000_111_000000000);
+        vm.memory.write(0x3003, 0xF020);
+
+        let written: Vec<Vec<u16>> = (0..4)
+            .map(|_| {
+                let step = vm.step_recorded();
+                let record = TraceRecord::from_step(&step);
+                record
+                    .registers
+                    .iter()
+                    .map(|write| write.register)
+                    .collect()
+            })
+            .collect();
+
+        assert_eq!(written, [vec![0], vec![1], vec![], vec![7]]);
+    }
+}
@@ -0,0 +1,501 @@
+use std::collections::BTreeMap;
+
+use crate::hardware::instructions::decoded::Decoded;
+use crate::hardware::instructions::Instructions;
+use crate::hardware::memory::{Memory, MEMORY_MAX};
+use crate::hardware::registers::Registers;
+
+use super::loader::DEVICE_PAGE;
+use super::StopReason;
+
+const TRAP: u16 = Instructions::TRAP as u16;
+const HALT: u16 = 0xF025;
+
+/**
+Runs a block from an offset for at most a number of instructions, the first of them as step `steps`, and leaves
+the PC after the last one that ran.
+*/
+type Body = Box<dyn Fn(&mut Registers, &mut Memory, u64, usize, usize) -> Exit>;
+
+/**
+How far a run of a block got.
+*/
+pub struct Exit {
+    /// The number of instructions that ran.
+    pub executed: usize,
+    pub stop: Option<StopReason>,
+    /// Whether the run ended with the jump, call or trap that ends the block.
+    pub last: bool,
+    /// Whether the run stopped right after a load or store that made the keyboard fail or wrote translated code.
+    pub interrupted: bool,
+}
+
+/**
+Consecutive translated instructions starting at `start`, compiled into one closure. A taken branch leaves the block,
+unless it goes back to `start`; otherwise only the last instruction can jump or stop the machine.
+*/
+pub struct Block {
+    pub start: u16,
+    words: Vec<u16>,
+    body: Body,
+    /// Whether the last instruction is a JSR, JSRR or JMP, which the call stack follows.
+    jumps: bool,
+}
+
+impl Block {
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    /**
+    The word the instruction at `offset` was translated from.
+    */
+    #[inline]
+    pub fn word(&self, offset: usize) -> u16 {
+        self.words[offset]
+    }
+
+    /**
+    Whether the last instruction can call or return.
+    */
+    #[inline]
+    pub fn jumps(&self) -> bool {
+        self.jumps
+    }
+
+    fn contains(&self, address: u16) -> bool {
+        (self.start as usize..self.start as usize + self.len()).contains(&(address as usize))
+    }
+
+    /**
+    Runs up to `budget` instructions from `offset`, the first of them as step `steps`. A branch back to the start
+    of the block loops inside the run. The run ends at any other taken branch, at the end of the block, and after a
+    load that made the keyboard fail or a store to a translated address, since the rest of the block may be what
+    was written.
+    */
+    #[inline]
+    pub fn run(
+        &self,
+        registers: &mut Registers,
+        memory: &mut Memory,
+        steps: u64,
+        offset: usize,
+        budget: usize,
+    ) -> Exit {
+        (self.body)(registers, memory, steps, offset, budget)
+    }
+}
+
+/**
+The code reachable from a set of entry points, in blocks that each compile into one closure.
+
+Reachability follows branches, JSR and the fall-through after every instruction that returns, but not JMP, JSRR
+targets or anything in the device page. A block starts at every entry point, jump target and return address and
+runs up to the next instruction that does not fall through, so blocks can share their tails. Code only reached
+through computed jumps has no translation and is left to the interpreter, as is code written after it was
+translated: every translated address is watched in memory and `invalidate` drops the blocks a write hit.
+*/
+pub struct Translation {
+    blocks: Vec<Option<Block>>,
+    /// For every address, one more than the index of the block entered there, or zero. That is the block starting
+    /// at the address if there is one, else the last one starting before it.
+    owners: Vec<u32>,
+}
+
+impl Translation {
+    pub fn translate(memory: &mut Memory, entries: &[u16]) -> Translation {
+        let mut found: BTreeMap<u16, Decoded> = BTreeMap::new();
+        let mut leaders = vec![false; MEMORY_MAX];
+        let mut pending: Vec<u16> = entries.to_vec();
+        for &entry in entries {
+            leaders[entry as usize] = true;
+        }
+
+        while let Some(address) = pending.pop() {
+            if address >= DEVICE_PAGE || found.contains_key(&address) {
+                continue;
+            }
+            let decoded = Decoded::decode(memory.peek(address), address);
+            found.insert(address, decoded);
+            let next = address.wrapping_add(1);
+            for successor in successors(decoded, address).into_iter().flatten() {
+                // a conditional branch carries on into its fall-through in the same block
+                if ends_block(decoded) || successor != next {
+                    leaders[successor as usize] = true;
+                }
+                pending.push(successor);
+            }
+        }
+
+        let mut translation = Translation {
+            blocks: Vec::new(),
+            owners: vec![0; MEMORY_MAX],
+        };
+        for &start in found.keys() {
+            if !leaders[start as usize] {
+                continue;
+            }
+            let mut words = Vec::new();
+            let mut instructions = Vec::new();
+            for (&address, &decoded) in found.range(start..) {
+                if address as usize != start as usize + words.len() {
+                    break;
+                }
+                words.push(memory.peek(address));
+                instructions.push(decoded);
+                memory.watch_writes(address);
+                if ends_block(decoded) {
+                    break;
+                }
+            }
+            translation.push(start, words, &instructions, &found);
+        }
+        translation
+    }
+
+    fn push(
+        &mut self,
+        start: u16,
+        words: Vec<u16>,
+        instructions: &[Decoded],
+        found: &BTreeMap<u16, Decoded>,
+    ) {
+        let jumps = matches!(
+            instructions.last(),
+            Some(Decoded::Jsr { .. } | Decoded::Jsrr { .. } | Decoded::Jmp { .. })
+        );
+        let block = Block {
+            start,
+            words,
+            body: compile(start, instructions, found),
+            jumps,
+        };
+        // blocks come in order of their starts, so a later one owns what it shares with an earlier one
+        let start = start as usize;
+        self.owners[start..start + block.len()].fill(self.blocks.len() as u32 + 1);
+        self.blocks.push(Some(block));
+    }
+
+    /**
+    The block to enter at `address` and the offset of `address` in it.
+    */
+    #[inline]
+    pub fn lookup(&self, address: u16) -> Option<(&Block, usize)> {
+        let owner = self.owners[address as usize] as usize;
+        let block = self.blocks.get(owner.checked_sub(1)?)?.as_ref()?;
+        Some((block, (address - block.start) as usize))
+    }
+
+    /**
+    Drops every block containing `address`, after it was written.
+    */
+    pub fn invalidate(&mut self, address: u16) {
+        let mut dropped = Vec::new();
+        for slot in &mut self.blocks {
+            if slot.as_ref().is_some_and(|block| block.contains(address)) {
+                dropped.extend(slot.take());
+            }
+        }
+        // hand what the dropped blocks owned to the blocks that remain
+        for block in dropped {
+            let range = block.start as usize..block.start as usize + block.len();
+            self.owners[range.clone()].fill(0);
+            for (index, remaining) in self.blocks.iter().enumerate() {
+                let Some(remaining) = remaining else {
+                    continue;
+                };
+                let start = range.start.max(remaining.start as usize);
+                let end = range.end.min(remaining.start as usize + remaining.len());
+                if start < end {
+                    self.owners[start..end].fill(index as u32 + 1);
+                }
+            }
+        }
+    }
+
+    /**
+    The number of addresses with a translation.
+    */
+    pub fn len(&self) -> usize {
+        self.owners.iter().filter(|&&owner| owner != 0).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/**
+Where execution can continue after the instruction at `address`, as far as it is known without running it.
+*/
+fn successors(decoded: Decoded, address: u16) -> [Option<u16>; 2] {
+    let next = Some(address.wrapping_add(1));
+    match decoded {
+        Decoded::Br { nzp: 0, .. } => [next, None],
+        Decoded::Br { nzp: 0b111, target } => [Some(target), None],
+        Decoded::Br { target, .. } | Decoded::Jsr { target } => [Some(target), next],
+        Decoded::Jsrr { .. } => [next, None],
+        Decoded::Jmp { .. } | Decoded::Handler { instruction: HALT } => [None, None],
+        Decoded::Handler { instruction } if instruction >> 12 == TRAP => [next, None],
+        // RTI and RES fault
+        Decoded::Handler { .. } => [None, None],
+        _ => [next, None],
+    }
+}
+
+/**
+Whether the instruction never falls through to the next one, or can call or stop the machine. Conditional branches
+leave their block only when taken.
+*/
+fn ends_block(decoded: Decoded) -> bool {
+    match decoded {
+        Decoded::Br { nzp, .. } => nzp == 0b111,
+        Decoded::Jmp { .. }
+        | Decoded::Jsr { .. }
+        | Decoded::Jsrr { .. }
+        | Decoded::Handler { .. } => true,
+        _ => false,
+    }
+}
+
+/**
+Whether the instruction can make the keyboard fail or write a translated address, which ends the run of its block.
+Only loads and stores can, when they reach the device page or an address in `found`.
+*/
+fn may_exit(decoded: Decoded, found: &BTreeMap<u16, Decoded>) -> bool {
+    match decoded {
+        Decoded::Ld { address, .. } => address >= DEVICE_PAGE,
+        Decoded::St { address, .. } => found.contains_key(&address),
+        Decoded::Ldi { .. } | Decoded::Ldr { .. } | Decoded::Sti { .. } | Decoded::Str { .. } => {
+            true
+        }
+        _ => false,
+    }
+}
+
+/**
+Binds the instructions of the block at `start` into one closure. The instructions that fall through run without
+the PC, which is set once when the run ends, and the keyboard only learns the step before a load that can reach it.
+*/
+fn compile(start: u16, instructions: &[Decoded], found: &BTreeMap<u16, Decoded>) -> Body {
+    // an unconditional branch is taken like any other, possibly back to the start
+    let (straight, exit) = match instructions.split_last() {
+        Some((&last, straight)) if ends_block(last) && !matches!(last, Decoded::Br { .. }) => {
+            (straight, Some(last))
+        }
+        _ => (instructions, None),
+    };
+    let straight: Box<[(Decoded, bool)]> = straight
+        .iter()
+        .map(|&decoded| (decoded, may_exit(decoded, found)))
+        .collect();
+
+    Box::new(move |registers, memory, steps, mut offset, budget| {
+        let mut executed = 0;
+        while executed < budget {
+            let Some(&(decoded, may_exit)) = straight.get(offset) else {
+                let Some(exit) = exit else {
+                    break;
+                };
+                registers.update_program_counter(start.wrapping_add(offset as u16 + 1));
+                memory.keyboard.step = steps + executed as u64;
+                return Exit {
+                    executed: executed + 1,
+                    stop: exit.execute(registers, memory),
+                    last: true,
+                    interrupted: false,
+                };
+            };
+            offset += 1;
+            executed += 1;
+
+            if let Decoded::Br { nzp, target } = decoded {
+                if nzp & registers.read_cond() == 0 {
+                    continue;
+                }
+                if target == start {
+                    offset = 0;
+                    continue;
+                }
+                registers.update_program_counter(target);
+                return Exit {
+                    executed,
+                    stop: None,
+                    last: false,
+                    interrupted: false,
+                };
+            }
+            if may_exit {
+                memory.keyboard.step = steps + executed as u64 - 1;
+            }
+            decoded.execute(registers, memory);
+            if may_exit && (memory.keyboard.failure.is_some() || memory.watched_written()) {
+                registers.update_program_counter(start.wrapping_add(offset as u16));
+                return Exit {
+                    executed,
+                    stop: None,
+                    last: false,
+                    interrupted: true,
+                };
+            }
+        }
+
+        registers.update_program_counter(start.wrapping_add(offset as u16));
+        Exit {
+            executed,
+            stop: None,
+            last: false,
+            interrupted: false,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+
+    use crate::hardware::console::{Display, Keyboard};
+    use crate::hardware::vm::engine::Engine;
+    use crate::hardware::vm::limits::Limits;
+    use crate::hardware::vm::VirtualMachine;
+
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /**
+    Runs a machine prepared by `setup` with the interpreter and with the compiled engine, checks that both end in
+    the same state and returns the compiled one.
+    */
+    fn differential(setup: impl Fn(&mut VirtualMachine)) -> VirtualMachine {
+        let run = |engine| {
+            let output = Rc::new(RefCell::new(Vec::new()));
+            let mut vm = VirtualMachine::create();
+            vm.set_engine(engine);
+            vm.memory.display = Display::to_writer(Box::new(Output(Rc::clone(&output))));
+            setup(&mut vm);
+            let stop = vm.execute_program();
+            let output = output.take();
+            (vm, stop, output)
+        };
+        let (interpreter, expected_stop, expected_output) = run(Engine::Interpreter);
+        let (compiled, stop, output) = run(Engine::Compiled);
+
+        assert_eq!(stop, expected_stop);
+        assert_eq!(compiled.steps, interpreter.steps);
+        assert_eq!(compiled.registers, interpreter.registers);
+        assert!(compiled.memory.contents() == interpreter.memory.contents());
+        assert_eq!(
+            compiled.call_stack.frames(),
+            interpreter.call_stack.frames()
+        );
+        assert_eq!(output, expected_output);
+        compiled
+    }
+
+    fn write_program(vm: &mut VirtualMachine, program: &[u16]) {
+        for (address, &word) in (0x3000..).zip(program) {
+            vm.memory.write(address, word);
+        }
+    }
+
+    #[test]
+    fn test_bundled_programs_match_the_interpreter() {
+        for (path, answer) in [("2048.obj", b"n"), ("rogue.obj", b" ")] {
+            // an odd limit stops in the middle of a block
+            for instructions in [1_000_003, 20_000] {
+                let vm = differential(|vm| {
+                    vm.load_program(path).unwrap();
+                    let input = [&answer[..], &b"wasd".repeat(50)].concat();
+                    vm.memory.keyboard = Keyboard::from_bytes(&input);
+                    vm.set_limits(Limits {
+                        instructions: Some(instructions),
+                        ..Limits::unlimited()
+                    });
+                });
+                assert!(!vm.translation().unwrap().is_empty(), "{}", path);
+            }
+        }
+    }
+
+    #[test]
+    fn test_a_loop_within_one_block_stops_at_the_instruction_limit() {
+        // AND R0, R0, #0 ; LOOP ADD R0, R0, #1 ; ADD R1, R1, #2 ; BRnzp LOOP
+        for instructions in [1, 2, 1000, 1001, 1002] {
+            let vm = differential(|vm| {
+                write_program(
+                    vm,
+                    &[
+                        0b0101_000_000_1_00000,
+                        0b0001_000_000_1_00001,
+                        0b0001_001_001_1_00010,
+                        0b0000_111_111111101,
+                    ],
+                );
+                vm.set_limits(Limits {
+                    instructions: Some(instructions),
+                    ..Limits::unlimited()
+                });
+            });
+            assert_eq!(vm.steps, instructions);
+        }
+    }
+
+    #[test]
+    fn test_computed_jumps_are_interpreted() {
+        // LEA R0, TARGET ; JMP R0 ; HALT ; TARGET ADD R1, R1, #5 ; HALT
+        let vm = differential(|vm| {
+            write_program(
+                vm,
+                &[
+                    0b1110_000_000000010,
+                    0b1100_000_000_000000,
+                    0xF025,
+                    0b0001_001_001_1_00101,
+                    0xF025,
+                ],
+            )
+        });
+        assert_eq!(vm.read_register(1), 5);
+
+        let translation = vm.translation().unwrap();
+        assert_eq!(translation.len(), 2);
+        assert!(translation.lookup(0x3001).is_some());
+        assert!(translation.lookup(0x3003).is_none());
+    }
+
+    #[test]
+    fn test_a_block_that_patches_itself_stops_using_its_translation() {
+        // LD R1, NEW ; ST R1, PATCH ; PATCH ADD R0, R0, #1 ; HALT ; NEW ADD R0, R0, #7
+        let vm = differential(|vm| {
+            write_program(
+                vm,
+                &[
+                    0b0010_001_000000011,
+                    0b0011_001_000000000,
+                    0b0001_000_000_1_00001,
+                    0xF025,
+                    0b0001_000_000_1_00111,
+                ],
+            )
+        });
+        assert_eq!(vm.read_register(0), 7);
+        assert!(vm.translation().unwrap().is_empty());
+    }
+}
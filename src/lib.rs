@@ -0,0 +1,5 @@
+#![allow(clippy::unusual_byte_groupings)]
+
+pub mod debugger;
+pub mod hardware;
+pub mod testing;
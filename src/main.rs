@@ -1,9 +1,707 @@
-pub mod hardware;
+use std::fs::{self, File};
+use std::io::{self, BufReader, LineWriter};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::Duration;
 
-use hardware::vm::VirtualMachine;
+use structopt::StructOpt;
+
+use rust_vm::debugger::{self, Debugger};
+use rust_vm::hardware::console::{read_input_log, Display, InputEvent, Keyboard};
+use rust_vm::hardware::vm::dump::{CrashDump, DumpError};
+use rust_vm::hardware::vm::engine::Engine;
+use rust_vm::hardware::vm::formats::ObjectFormat;
+use rust_vm::hardware::vm::limits::Limits;
+use rust_vm::hardware::vm::loader::LoadError;
+use rust_vm::hardware::vm::lockstep::{Lockstep, LockstepOutcome};
+use rust_vm::hardware::vm::setup::Setup;
+use rust_vm::hardware::vm::trace::TraceWriter;
+use rust_vm::hardware::vm::{StopReason, VirtualMachine};
+use rust_vm::testing::grader::{self, GradingSpec};
+use rust_vm::testing::{self, junit};
+
+#[derive(StructOpt)]
+#[structopt(name = "rust-vm", about = "A virtual machine for the LC-3")]
+enum Command {
+    /// Load an object file and run it
+    Run {
+        /// Object files to load, e.g. an OS, libraries and the main program
+        #[structopt(parse(from_os_str), default_value = "rogue.obj")]
+        programs: Vec<PathBuf>,
+
+        #[structopt(flatten)]
+        entry: EntryOptions,
+
+        #[structopt(flatten)]
+        images: ImageOptions,
+
+        #[structopt(flatten)]
+        setup: SetupOptions,
+
+        /// Save a memory range when the program stops, as <file>@<start>-<end>, e.g. out.ihx@x3000-x30FF.
+        /// Intel HEX for .ihx and .ihex files, raw big-endian words otherwise
+        #[structopt(long)]
+        save_image: Option<String>,
+
+        /// Write a JSON Lines record of every executed instruction to this file
+        #[structopt(long, parse(from_os_str))]
+        trace: Option<PathBuf>,
+
+        /// Write an execution profile to this file when the program stops
+        #[structopt(long, parse(from_os_str))]
+        profile: Option<PathBuf>,
+
+        /// Write lcov coverage of the loaded program to this file when the program stops
+        #[structopt(long, parse(from_os_str))]
+        coverage: Option<PathBuf>,
+
+        /// Also follow R5 frame pointers (LC-3 C calling convention) in fault backtraces
+        #[structopt(long)]
+        frame_pointers: bool,
+
+        /// How to execute instructions: interpreter, cached to keep decoded instructions per address, or compiled
+        /// to translate the program into closures first
+        #[structopt(long, default_value = "interpreter")]
+        engine: Engine,
+
+        /// Write a crash dump to this file when the program faults
+        #[structopt(long, parse(from_os_str))]
+        crash_dump: Option<PathBuf>,
+
+        /// Number of executed instructions kept for the crash dump
+        #[structopt(long, default_value = "100")]
+        dump_trace: usize,
+
+        #[structopt(flatten)]
+        input: InputOptions,
+
+        #[structopt(flatten)]
+        limits: LimitOptions,
+    },
+    /// Continue running from a snapshot saved in the debugger
+    Resume {
+        /// Snapshot file to restore
+        #[structopt(parse(from_os_str))]
+        snapshot: PathBuf,
+
+        /// Also follow R5 frame pointers (LC-3 C calling convention) in fault backtraces
+        #[structopt(long)]
+        frame_pointers: bool,
+
+        /// How to execute instructions: interpreter, cached to keep decoded instructions per address, or compiled
+        /// to translate the program into closures first
+        #[structopt(long, default_value = "interpreter")]
+        engine: Engine,
+
+        #[structopt(flatten)]
+        limits: LimitOptions,
+    },
+    /// Load an object file and step through it in the debugger
+    Debug {
+        /// Object files to load, e.g. an OS, libraries and the main program
+        #[structopt(parse(from_os_str), required = true)]
+        programs: Vec<PathBuf>,
+
+        #[structopt(flatten)]
+        entry: EntryOptions,
+
+        #[structopt(flatten)]
+        images: ImageOptions,
+
+        #[structopt(flatten)]
+        setup: SetupOptions,
+
+        /// Number of executed instructions that can be stepped back over
+        #[structopt(long, default_value = "100000")]
+        history: usize,
+
+        #[structopt(flatten)]
+        input: InputOptions,
+    },
+    /// Run a program on two engines in lockstep and stop at the first instruction where they disagree
+    Lockstep {
+        /// Object files to load, e.g. an OS, libraries and the main program
+        #[structopt(parse(from_os_str), required = true)]
+        programs: Vec<PathBuf>,
+
+        #[structopt(flatten)]
+        entry: EntryOptions,
+
+        #[structopt(flatten)]
+        images: ImageOptions,
+
+        #[structopt(flatten)]
+        setup: SetupOptions,
+
+        /// Engine whose output is shown
+        #[structopt(long, default_value = "interpreter")]
+        left: Engine,
+
+        /// Engine checked against the left one
+        #[structopt(long, default_value = "compiled")]
+        right: Engine,
+
+        /// Keyboard input for both machines, read from this file. Without it the program gets no input
+        #[structopt(long, parse(from_os_str))]
+        input: Option<PathBuf>,
+
+        /// Feed both keyboards from a log written by --record-input
+        #[structopt(long, parse(from_os_str), conflicts_with = "input")]
+        replay_input: Option<PathBuf>,
+
+        #[structopt(flatten)]
+        limits: LimitOptions,
+    },
+    /// Run a directory of test cases (program, in.txt, out.txt, expect.txt) and report which pass
+    Test {
+        /// Directory holding one subdirectory per case, or a single case
+        #[structopt(parse(from_os_str))]
+        directory: PathBuf,
+
+        /// Number of cases run at the same time, by default one per CPU
+        #[structopt(long)]
+        jobs: Option<usize>,
+
+        /// Write a JUnit XML report to this file
+        #[structopt(long, parse(from_os_str))]
+        junit: Option<PathBuf>,
+
+        /// How to execute instructions: interpreter, cached or compiled
+        #[structopt(long, default_value = "interpreter")]
+        engine: Engine,
+    },
+    /// Grade a submission against a JSON spec of weighted test cases and print the score report
+    Grade {
+        /// Grading spec, see rust_vm::testing::grader
+        #[structopt(parse(from_os_str))]
+        spec: PathBuf,
+
+        /// The submitted object file
+        #[structopt(parse(from_os_str))]
+        submission: PathBuf,
+
+        /// Also show why hidden cases failed
+        #[structopt(long)]
+        show_hidden: bool,
+
+        /// Write the report as JSON to this file
+        #[structopt(long, parse(from_os_str))]
+        json: Option<PathBuf>,
+
+        /// Number of cases run at the same time, by default one per CPU
+        #[structopt(long)]
+        jobs: Option<usize>,
+
+        /// How to execute instructions: interpreter, cached or compiled
+        #[structopt(long, default_value = "interpreter")]
+        engine: Engine,
+    },
+    /// Convert an image between the raw, hex, bin and lc3tools formats
+    Convert {
+        /// Image to read, in any supported format
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+
+        /// File to write
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+
+        /// Output format (raw, hex, bin or lc3tools), by default taken from the output file's extension
+        #[structopt(long)]
+        to: Option<String>,
+    },
+    /// Open a crash dump in the debugger, read-only
+    Inspect {
+        /// Crash dump written by --crash-dump
+        #[structopt(parse(from_os_str))]
+        dump: PathBuf,
+    },
+}
+
+#[derive(StructOpt)]
+struct EntryOptions {
+    /// Where to start: a symbol, an address or one of the object files (its origin).
+    /// Defaults to the origin of the first object file
+    #[structopt(long)]
+    entry: Option<String>,
+}
+
+impl EntryOptions {
+    /**
+    Loads every program in order and sets the PC to the chosen entry point.
+    Exits with 1 when a program cannot be loaded or the entry point is unknown.
+    */
+    fn load(&self, vm: &mut VirtualMachine, programs: &[PathBuf]) {
+        for program in programs {
+            let program = program.to_str().expect("Invalid program path");
+            if let Err(error) = vm.load_program(program) {
+                eprintln!("Failed to load {}: {}", program, error);
+                process::exit(1);
+            }
+        }
+
+        let Some(entry) = &self.entry else {
+            return;
+        };
+        let image = vm
+            .programs
+            .iter()
+            .find(|(path, _)| path == entry)
+            .map(|(_, image)| image.origin);
+        match image.or_else(|| vm.symbols.resolve(entry)) {
+            Some(address) => vm.registers.update_program_counter(address),
+            None => {
+                eprintln!("Unknown entry point {}", entry);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+#[derive(StructOpt)]
+struct ImageOptions {
+    /// Load a memory image after the object files: a raw image of big-endian words as <file>@<base>,
+    /// e.g. rom.bin@x4000, or an Intel HEX file given without a base
+    #[structopt(long = "image", number_of_values = 1)]
+    images: Vec<String>,
+}
+
+impl ImageOptions {
+    fn apply(&self, vm: &mut VirtualMachine) {
+        for image in &self.images {
+            let (path, base) = match image.rsplit_once('@') {
+                Some((path, base)) => (path, Some(base)),
+                None => (image.as_str(), None),
+            };
+            let bytes = fs::read(path).unwrap_or_else(|error| {
+                eprintln!("Failed to read {}: {}", path, error);
+                process::exit(1);
+            });
+
+            let loaded = match base {
+                Some(base) => {
+                    let Some(base) = vm.symbols.resolve(base) else {
+                        eprintln!("Unknown base address {}", base);
+                        process::exit(1);
+                    };
+                    vm.memory.import_raw(base, &bytes)
+                }
+                None if bytes.starts_with(b":") => {
+                    let text = String::from_utf8_lossy(&bytes);
+                    vm.memory.import_intel_hex(&text)
+                }
+                None => {
+                    eprintln!(
+                        "Raw image {} needs a base address, e.g. {}@x3000",
+                        path, path
+                    );
+                    process::exit(1);
+                }
+            };
+            if let Err(error) = loaded {
+                eprintln!("Failed to load {}: {}", path, error);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+#[derive(StructOpt)]
+struct SetupOptions {
+    /// Set registers, memory and keyboard input from a setup file before the program starts, e.g. R1 = x4000
+    #[structopt(long, parse(from_os_str))]
+    setup: Option<PathBuf>,
+}
+
+impl SetupOptions {
+    fn apply(&self, vm: &mut VirtualMachine) {
+        let Some(path) = &self.setup else {
+            return;
+        };
+        if let Err(error) = Setup::load(path).and_then(|setup| setup.apply(vm)) {
+            eprintln!("Invalid setup {}: {}", path.display(), error);
+            process::exit(1);
+        }
+    }
+}
+
+/**
+Writes the memory range given as <file>@<start>-<end>.
+*/
+fn save_image(vm: &VirtualMachine, spec: &str) -> Result<(), String> {
+    let (path, range) = spec
+        .rsplit_once('@')
+        .ok_or("expected <file>@<start>-<end>")?;
+    let (start, end) = range
+        .split_once('-')
+        .and_then(|(start, end)| Some((vm.symbols.resolve(start)?, vm.symbols.resolve(end)?)))
+        .ok_or("expected a range like x3000-x30FF")?;
+
+    let intel_hex = matches!(
+        Path::new(path)
+            .extension()
+            .and_then(|extension| extension.to_str()),
+        Some("ihx" | "ihex")
+    );
+    let bytes = if intel_hex {
+        vm.memory
+            .export_intel_hex(start, end)
+            .map(String::into_bytes)
+    } else {
+        vm.memory.export_raw(start, end)
+    };
+    let bytes = bytes.map_err(|error| error.to_string())?;
+    fs::write(path, bytes).map_err(|error| error.to_string())
+}
+
+#[derive(StructOpt)]
+struct InputOptions {
+    /// Log every keyboard byte the program consumes, with the instruction count, to this file
+    #[structopt(long, parse(from_os_str))]
+    record_input: Option<PathBuf>,
+
+    /// Feed the keyboard from a log written by --record-input instead of stdin
+    #[structopt(long, parse(from_os_str), conflicts_with = "record-input")]
+    replay_input: Option<PathBuf>,
+}
+
+impl InputOptions {
+    fn apply(&self, vm: &mut VirtualMachine) {
+        if let Some(path) = &self.record_input {
+            let file = File::create(path).unwrap_or_else(|error| {
+                eprintln!("Failed to create {}: {}", path.display(), error);
+                process::exit(1);
+            });
+            vm.memory.keyboard.record(Box::new(LineWriter::new(file)));
+        }
+        if let Some(path) = &self.replay_input {
+            vm.memory.keyboard.replay(load_input_log(path));
+        }
+    }
+}
+
+/**
+Reads an input log for `--replay-input`, exiting with an error if it is missing or malformed.
+*/
+fn load_input_log(path: &Path) -> Vec<InputEvent> {
+    File::open(path)
+        .and_then(|file| read_input_log(BufReader::new(file)))
+        .unwrap_or_else(|error| {
+            eprintln!("Failed to read {}: {}", path.display(), error);
+            process::exit(1);
+        })
+}
+
+#[derive(StructOpt)]
+struct LimitOptions {
+    /// Stop after this many executed instructions
+    #[structopt(long)]
+    max_instructions: Option<u64>,
+
+    /// Stop after this many seconds of wall-clock time
+    #[structopt(long)]
+    time_limit: Option<f64>,
+
+    /// Stop once the program has written this many bytes
+    #[structopt(long)]
+    max_output: Option<u64>,
+
+    /// Stop when the program tries to read more than this many bytes of input
+    #[structopt(long)]
+    max_input: Option<u64>,
+}
+
+impl LimitOptions {
+    fn limits(&self) -> Limits {
+        Limits {
+            instructions: self.max_instructions,
+            wall_time: self.time_limit.map(Duration::from_secs_f64),
+            output_bytes: self.max_output,
+            input_reads: self.max_input,
+        }
+    }
+}
+
+fn jobs_or_cpus(jobs: Option<usize>) -> usize {
+    jobs.unwrap_or_else(|| thread::available_parallelism().map_or(1, |count| count.get()))
+}
+
+/**
+Exits with 0 when the program halted, otherwise prints why and where it stopped and exits with 2.
+Faults come with a backtrace.
+*/
+fn finish(vm: &VirtualMachine, reason: StopReason, frame_pointers: bool) -> ! {
+    if reason == StopReason::Halted {
+        println!("HALT detected");
+        process::exit(0);
+    }
+    eprintln!("Stopped: {}\n{}", reason, vm.summary());
+    if let StopReason::Fault(fault) = reason {
+        eprint!(
+            "Backtrace:\n{}",
+            vm.backtrace(fault.address(), frame_pointers)
+        );
+    }
+    process::exit(2);
+}
 
 fn main() {
-    let mut vm = VirtualMachine::create();
-    vm.load_program("rogue.obj");
-    vm.execute_program();
+    match Command::from_args() {
+        Command::Run {
+            programs,
+            entry,
+            images,
+            setup,
+            save_image: save,
+            trace,
+            profile,
+            coverage,
+            frame_pointers,
+            engine,
+            crash_dump,
+            dump_trace,
+            input,
+            limits,
+        } => {
+            let mut vm = VirtualMachine::create();
+            vm.set_engine(engine);
+            input.apply(&mut vm);
+            vm.set_limits(limits.limits());
+
+            if let Some(path) = trace {
+                let file = File::create(&path).expect("Error creating trace file");
+                // line buffered so the trace is complete even if the VM panics
+                vm.enable_trace(TraceWriter::new(Box::new(LineWriter::new(file))));
+            }
+
+            if profile.is_some() {
+                vm.enable_profiler();
+            }
+            if coverage.is_some() {
+                vm.enable_coverage();
+            }
+            if crash_dump.is_some() {
+                vm.keep_recent_steps(dump_trace);
+            }
+
+            entry.load(&mut vm, &programs);
+            images.apply(&mut vm);
+            setup.apply(&mut vm);
+            let reason = vm.execute_program();
+
+            if let Some(spec) = save {
+                if let Err(error) = save_image(&vm, &spec) {
+                    eprintln!("Failed to save {}: {}", spec, error);
+                    process::exit(1);
+                }
+            }
+            if let (Some(path), Some(report)) = (profile, vm.profile_report()) {
+                fs::write(path, report).expect("Error writing profile");
+            }
+            if let (Some(path), Some(report)) = (coverage, vm.coverage_lcov()) {
+                fs::write(path, report).expect("Error writing coverage");
+            }
+            if let (Some(path), StopReason::Fault(_)) = (crash_dump, reason) {
+                let path = path.to_str().expect("Invalid crash dump path");
+                match vm.write_crash_dump(path, reason) {
+                    Ok(()) => eprintln!("Crash dump written to {}", path),
+                    Err(error) => eprintln!("Failed to write crash dump: {}", error),
+                }
+            }
+            finish(&vm, reason, frame_pointers);
+        }
+        Command::Resume {
+            snapshot,
+            frame_pointers,
+            engine,
+            limits,
+        } => {
+            let mut vm = VirtualMachine::create();
+            vm.set_engine(engine);
+            if let Err(error) = vm.load_snapshot(snapshot.to_str().expect("Invalid snapshot path"))
+            {
+                eprintln!("{}", error);
+                process::exit(1);
+            }
+            vm.set_limits(limits.limits());
+            let reason = vm.execute_program();
+            finish(&vm, reason, frame_pointers);
+        }
+        Command::Debug {
+            programs,
+            entry,
+            images,
+            setup,
+            history,
+            input,
+        } => {
+            let mut vm = VirtualMachine::create();
+            vm.enable_history(history);
+            input.apply(&mut vm);
+            entry.load(&mut vm, &programs);
+            images.apply(&mut vm);
+            setup.apply(&mut vm);
+
+            let mut debugger = Debugger::new(vm);
+            debugger
+                .run(debugger::stdin_lines(), &mut io::stdout())
+                .expect("Error running debugger");
+        }
+        Command::Lockstep {
+            programs,
+            entry,
+            images,
+            setup,
+            left,
+            right,
+            input,
+            replay_input,
+            limits,
+        } => {
+            let bytes = input.map_or_else(Vec::new, |path| {
+                fs::read(&path).unwrap_or_else(|error| {
+                    eprintln!("Failed to read {}: {}", path.display(), error);
+                    process::exit(1);
+                })
+            });
+            let events = replay_input.map(|path| load_input_log(&path));
+            let machine = |engine, display| {
+                let mut vm = VirtualMachine::create();
+                vm.set_engine(engine);
+                vm.memory.display = display;
+                vm.memory.keyboard = Keyboard::from_bytes(&bytes);
+                if let Some(events) = &events {
+                    vm.memory.keyboard.replay(events.clone());
+                }
+                entry.load(&mut vm, &programs);
+                images.apply(&mut vm);
+                setup.apply(&mut vm);
+                vm
+            };
+
+            let mut lockstep = Lockstep::new(
+                machine(left, Display::stdout()),
+                machine(right, Display::to_writer(Box::new(io::sink()))),
+            );
+            let outcome = lockstep.run(limits.limits());
+            lockstep.left.memory.display.flush();
+            match outcome {
+                LockstepOutcome::Agreed(reason) => {
+                    eprintln!(
+                        "{} and {} agreed on {} instructions",
+                        left, right, lockstep.left.steps
+                    );
+                    finish(&lockstep.left, reason, false);
+                }
+                LockstepOutcome::Diverged(divergence) => {
+                    eprint!("{}", divergence);
+                    process::exit(3);
+                }
+            }
+        }
+        Command::Test {
+            directory,
+            jobs,
+            junit,
+            engine,
+        } => {
+            let cases = testing::discover(&directory).unwrap_or_else(|error| {
+                eprintln!("Failed to read {}: {}", directory.display(), error);
+                process::exit(1);
+            });
+            let results = testing::run_all(&cases, jobs_or_cpus(jobs), engine);
+
+            for result in &results {
+                if result.passed() {
+                    println!("PASS {} ({} instructions)", result.name, result.steps);
+                    continue;
+                }
+                println!("FAIL {}", result.name);
+                for failure in result.error.iter().chain(&result.failures) {
+                    for line in failure.lines() {
+                        println!("    {}", line);
+                    }
+                }
+            }
+            let passed = results.iter().filter(|result| result.passed()).count();
+            println!("{} passed, {} failed", passed, results.len() - passed);
+
+            if let Some(path) = junit {
+                let suite = directory.file_name().map_or_else(
+                    || "lc3".to_string(),
+                    |name| name.to_string_lossy().into_owned(),
+                );
+                fs::write(&path, junit::report(&suite, &results)).unwrap_or_else(|error| {
+                    eprintln!("Failed to write {}: {}", path.display(), error);
+                    process::exit(1);
+                });
+            }
+            if passed < results.len() {
+                process::exit(1);
+            }
+        }
+        Command::Grade {
+            spec,
+            submission,
+            show_hidden,
+            json,
+            jobs,
+            engine,
+        } => {
+            let spec = GradingSpec::load(&spec).unwrap_or_else(|error| {
+                eprintln!("{}", error);
+                process::exit(1);
+            });
+            let report = grader::grade(&spec, &submission, jobs_or_cpus(jobs), engine);
+            print!("{}", report.text(show_hidden));
+            if let Some(path) = json {
+                let text = serde_json::to_string_pretty(&report.json(show_hidden))
+                    .expect("reports always serialize");
+                fs::write(&path, text).unwrap_or_else(|error| {
+                    eprintln!("Failed to write {}: {}", path.display(), error);
+                    process::exit(1);
+                });
+            }
+        }
+        Command::Convert { input, output, to } => {
+            let name = to.or_else(|| {
+                let extension = output.extension()?.to_str()?;
+                Some(extension.to_string())
+            });
+            let Some(format) = name.as_deref().and_then(ObjectFormat::from_name) else {
+                eprintln!("Unknown output format, use --to raw, hex, bin or lc3tools");
+                process::exit(1);
+            };
+
+            let converted = fs::read(&input)
+                .map_err(|error| LoadError::Io {
+                    path: input.clone(),
+                    error,
+                })
+                .and_then(|bytes| ObjectFormat::detect(&input, &bytes).decode(&bytes))
+                .and_then(|objects| format.encode(&objects));
+            match converted {
+                Ok(bytes) => fs::write(&output, bytes).expect("Error writing image"),
+                Err(error) => {
+                    eprintln!("Failed to convert {}: {}", input.display(), error);
+                    process::exit(1);
+                }
+            }
+        }
+        Command::Inspect { dump } => {
+            let dump = File::open(dump)
+                .map_err(DumpError::Io)
+                .and_then(|file| CrashDump::read_from(&mut BufReader::new(file)));
+            match dump {
+                Ok(dump) => Debugger::inspect(dump)
+                    .run(debugger::stdin_lines(), &mut io::stdout())
+                    .expect("Error running debugger"),
+                Err(error) => {
+                    eprintln!("{}", error);
+                    process::exit(1);
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,165 @@
+/**
+Line counts above which the diff falls back to showing the first differing line, to keep the table small.
+*/
+const MAX_TABLE: usize = 4_000_000;
+
+/**
+Lines of unchanged output shown around every change.
+*/
+const CONTEXT: usize = 2;
+
+/**
+Diff lines shown at most, and characters shown of each, so garbage output cannot flood a report.
+*/
+const MAX_LINES: usize = 40;
+const MAX_WIDTH: usize = 160;
+
+/**
+A line diff of `expected` and `actual`, with `-` for expected lines that are missing, `+` for unexpected lines and
+a little unchanged context around them. Empty when both are equal.
+*/
+pub fn diff(expected: &[u8], actual: &[u8]) -> String {
+    if expected == actual {
+        return String::new();
+    }
+    let expected = lines(expected);
+    let actual = lines(actual);
+    if expected.len() * actual.len() > MAX_TABLE {
+        return first_difference(&expected, &actual);
+    }
+
+    // lengths of the longest common subsequences of the suffixes
+    let width = actual.len() + 1;
+    let mut table = vec![0u32; (expected.len() + 1) * width];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            table[i * width + j] = if expected[i] == actual[j] {
+                table[(i + 1) * width + j + 1] + 1
+            } else {
+                table[(i + 1) * width + j].max(table[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut edits = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            edits.push((' ', &expected[i]));
+            i += 1;
+            j += 1;
+        } else if i < expected.len()
+            && (j == actual.len() || table[(i + 1) * width + j] >= table[i * width + j + 1])
+        {
+            edits.push(('-', &expected[i]));
+            i += 1;
+        } else {
+            edits.push(('+', &actual[j]));
+            j += 1;
+        }
+    }
+
+    let changed: Vec<usize> = (0..edits.len()).filter(|&k| edits[k].0 != ' ').collect();
+    let mut text = String::new();
+    let mut shown = 0;
+    let mut shown_until = 0;
+    for (k, (sign, line)) in edits.iter().enumerate() {
+        let near_change = changed
+            .iter()
+            .any(|&change| k + CONTEXT >= change && k <= change + CONTEXT);
+        if !near_change {
+            continue;
+        }
+        if shown == MAX_LINES {
+            let remaining = changed.iter().filter(|&&change| change >= k).count();
+            text.push_str(&format!("  ... and {} more changed lines\n", remaining));
+            return text;
+        }
+        if k > shown_until {
+            text.push_str("  ...\n");
+        }
+        text.push(*sign);
+        text.push(' ');
+        text.push_str(&shorten(line));
+        text.push('\n');
+        shown += 1;
+        shown_until = k + 1;
+    }
+    if shown_until < edits.len() {
+        text.push_str("  ...\n");
+    }
+    text
+}
+
+fn shorten(line: &str) -> String {
+    match line.char_indices().nth(MAX_WIDTH) {
+        Some((end, _)) => format!("{}...", &line[..end]),
+        None => line.to_string(),
+    }
+}
+
+fn first_difference(expected: &[String], actual: &[String]) -> String {
+    let line = expected
+        .iter()
+        .zip(actual)
+        .position(|(expected, actual)| expected != actual)
+        .unwrap_or(expected.len().min(actual.len()));
+    let missing = "<end of output>".to_string();
+    format!(
+        "  first difference on line {}\n- {}\n+ {}\n",
+        line + 1,
+        shorten(expected.get(line).unwrap_or(&missing)),
+        shorten(actual.get(line).unwrap_or(&missing))
+    )
+}
+
+/**
+The output split into lines, with control characters other than tabs escaped so they show up in the diff. A
+missing newline at the end is marked, since it makes the last line differ.
+*/
+fn lines(output: &[u8]) -> Vec<String> {
+    let text = String::from_utf8_lossy(output);
+    let mut lines: Vec<String> = text.split('\n').map(escape).collect();
+    match lines.last_mut() {
+        Some(last) if last.is_empty() => {
+            lines.pop();
+        }
+        Some(last) => last.push_str("\\ (no newline at end)"),
+        None => {}
+    }
+    lines
+}
+
+fn escape(line: &str) -> String {
+    line.chars()
+        .map(|c| match c {
+            '\t' => c.to_string(),
+            c if c.is_control() => c.escape_default().to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_diff_shows_changed_lines_with_context() {
+        let expected = b"1\n2\n3\n4\n5\n6\n7\n8\n";
+        let actual = b"1\n2\n3\n4\nfive\n6\n7\n8\n9";
+
+        assert_eq!(diff(expected, expected), "");
+        assert_eq!(
+            diff(expected, actual),
+            "  ...\n  3\n  4\n- 5\n+ five\n  6\n  7\n  8\n+ 9\\ (no newline at end)\n"
+        );
+        assert_eq!(diff(b"a\r\n", b"a\n"), "- a\\r\n+ a\n");
+
+        let garbage = "x".repeat(1000) + "\n";
+        let report = diff(b"", garbage.repeat(100).as_bytes());
+        assert_eq!(report.lines().count(), MAX_LINES + 1);
+        assert!(report.ends_with("...\n  ... and 60 more changed lines\n"));
+    }
+}
@@ -0,0 +1,453 @@
+/*!
+Grading a submission against a spec of test cases. The spec is JSON, paths are relative to it:
+
+```json
+{
+  "programs": ["os.obj"],
+  "max_instructions": 100000,
+  "cases": [
+    {
+      "name": "sums a list",
+      "weight": 2,
+      "input": "3\n",
+      "registers": { "R1": "x4000" },
+      "memory": { "x4000": [1, 2, 3, 0], "PROMPT": { "string": "> " } },
+      "output": "6\n",
+      "expect": { "R0": 6, "RESULT": -1 },
+      "max_instructions": 5000,
+      "hidden": true
+    }
+  ]
+}
+```
+
+`programs` are loaded before the submission, which is loaded last. Values are numbers, or strings holding a symbol,
+an address, a decimal or a character like `'A'`. A memory entry writes one value, an array of values from its
+address on, or a zero-terminated string. Only `name` is required; a case passes when the program halts within its
+instruction limit with the expected output and every expected value, and earns its weight (1 by default).
+
+Every case runs in a fresh machine with instruction, output and time limits, and a case whose run panics is an
+error, so loops, faults and garbage output only cost the case they happen in. Hidden cases report whether they
+passed but not why.
+*/
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use serde_json::json;
+
+use super::{parallel, CaseResult, Expectation, TestCase};
+use crate::hardware::vm::engine::Engine;
+use crate::hardware::vm::setup::{self, register_index, Entry, Setup, Target};
+use crate::hardware::vm::VirtualMachine;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GradingSpec {
+    #[serde(default)]
+    pub programs: Vec<PathBuf>,
+    /// The instruction limit of cases without their own.
+    #[serde(default = "default_instructions")]
+    pub max_instructions: u64,
+    pub cases: Vec<CaseSpec>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CaseSpec {
+    pub name: String,
+    #[serde(default = "default_weight")]
+    pub weight: f64,
+    #[serde(default)]
+    pub hidden: bool,
+    #[serde(default)]
+    pub input: String,
+    #[serde(default)]
+    pub registers: BTreeMap<String, Value>,
+    #[serde(default)]
+    pub memory: BTreeMap<String, Cells>,
+    pub output: Option<String>,
+    #[serde(default)]
+    pub expect: BTreeMap<String, Value>,
+    pub max_instructions: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum Value {
+    Number(i32),
+    Text(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum Cells {
+    Word(Value),
+    Words(Vec<Value>),
+    String { string: String },
+}
+
+fn default_instructions() -> u64 {
+    super::DEFAULT_INSTRUCTIONS
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+impl Value {
+    fn text(&self) -> String {
+        match self {
+            Value::Number(number) => number.to_string(),
+            Value::Text(text) => text.clone(),
+        }
+    }
+
+    fn parse(&self) -> Result<setup::Value, String> {
+        setup::Value::parse(&self.text()).ok_or_else(|| format!("invalid value '{}'", self.text()))
+    }
+}
+
+impl GradingSpec {
+    pub fn load(path: &Path) -> Result<GradingSpec, String> {
+        let bytes = fs::read(path)
+            .map_err(|error| format!("failed to read {}: {}", path.display(), error))?;
+        let mut spec: GradingSpec = serde_json::from_slice(&bytes)
+            .map_err(|error| format!("invalid spec {}: {}", path.display(), error))?;
+
+        let directory = path.parent().unwrap_or(Path::new(""));
+        for program in &mut spec.programs {
+            *program = directory.join(&program);
+        }
+        if let Some(case) = spec
+            .cases
+            .iter()
+            .find(|case| !case.weight.is_finite() || case.weight < 0.0)
+        {
+            return Err(format!("case '{}' has an invalid weight", case.name));
+        }
+        Ok(spec)
+    }
+}
+
+impl CaseSpec {
+    fn test_case(&self, spec: &GradingSpec, submission: &Path) -> TestCase {
+        let mut programs = spec.programs.clone();
+        programs.push(submission.to_path_buf());
+        TestCase {
+            name: self.name.clone(),
+            programs,
+            input: self.input.clone().into_bytes(),
+            setup: Setup::default(),
+            expected_output: self.output.clone().map(String::into_bytes),
+            max_instructions: self.max_instructions.unwrap_or(spec.max_instructions),
+            expectations: self
+                .expect
+                .iter()
+                .map(|(location, value)| Expectation {
+                    source: format!("case '{}'", self.name),
+                    location: location.clone(),
+                    value: value.text(),
+                })
+                .collect(),
+        }
+    }
+
+    /**
+    The initial registers and memory as a setup, applied after the programs are loaded.
+    */
+    fn setup(&self) -> Result<Setup, String> {
+        let mut setup = Setup::default();
+        let mut assign = |target, values| {
+            setup.entries.push(Entry {
+                line: 0,
+                target,
+                values,
+            })
+        };
+        for (name, value) in &self.registers {
+            let register =
+                register_index(name).ok_or_else(|| format!("unknown register '{}'", name))?;
+            assign(Target::Register(register), vec![value.parse()?]);
+        }
+        for (location, cells) in &self.memory {
+            let values = match cells {
+                Cells::Word(value) => vec![value.parse()?],
+                Cells::Words(values) => {
+                    values.iter().map(Value::parse).collect::<Result<_, _>>()?
+                }
+                Cells::String { string } => vec![setup::Value::String(string.clone().into_bytes())],
+            };
+            assign(Target::Memory(location.clone()), values);
+        }
+        Ok(setup)
+    }
+
+    fn prepare(&self, vm: &mut VirtualMachine) -> Result<(), String> {
+        self.setup()?.apply(vm).map_err(|error| error.to_string())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GradedCase {
+    pub weight: f64,
+    pub hidden: bool,
+    pub result: CaseResult,
+}
+
+impl GradedCase {
+    pub fn points(&self) -> f64 {
+        if self.result.passed() {
+            self.weight
+        } else {
+            0.0
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GradeReport {
+    pub cases: Vec<GradedCase>,
+}
+
+/**
+Runs every case of `spec` against `submission` on `jobs` threads.
+*/
+pub fn grade(spec: &GradingSpec, submission: &Path, jobs: usize, engine: Engine) -> GradeReport {
+    let cases = parallel(&spec.cases, jobs, |case| GradedCase {
+        weight: case.weight,
+        hidden: case.hidden,
+        result: case
+            .test_case(spec, submission)
+            .run_with(engine, |vm| case.prepare(vm)),
+    });
+    GradeReport { cases }
+}
+
+impl GradeReport {
+    pub fn score(&self) -> f64 {
+        self.cases.iter().map(GradedCase::points).sum()
+    }
+
+    pub fn total(&self) -> f64 {
+        self.cases.iter().map(|case| case.weight).sum()
+    }
+
+    /**
+    The report as text, one line per case followed by what went wrong. With `show_hidden` false, hidden cases
+    only show whether they passed.
+    */
+    pub fn text(&self, show_hidden: bool) -> String {
+        let total = self.total();
+        let percent = if total > 0.0 {
+            100.0 * self.score() / total
+        } else {
+            100.0
+        };
+        let mut text = format!("Score: {}/{} ({:.1}%)\n\n", self.score(), total, percent);
+
+        let width = self
+            .cases
+            .iter()
+            .map(|case| case.result.name.chars().count())
+            .max()
+            .unwrap_or(0);
+        for case in &self.cases {
+            let status = if case.result.passed() { "PASS" } else { "FAIL" };
+            write!(
+                text,
+                "  {}  {:<width$}  {}/{}",
+                status,
+                case.result.name,
+                case.points(),
+                case.weight,
+                width = width
+            )
+            .unwrap();
+            if case.hidden && !show_hidden {
+                text.push_str(if case.result.passed() {
+                    "\n"
+                } else {
+                    "  (hidden)\n"
+                });
+                continue;
+            }
+            text.push('\n');
+            for problem in case.result.error.iter().chain(&case.result.failures) {
+                for line in problem.lines() {
+                    writeln!(text, "        {}", line).unwrap();
+                }
+            }
+        }
+        text
+    }
+
+    /**
+    The report as JSON, for gradebooks. Hidden cases leave out their problems unless `show_hidden` is set.
+    */
+    pub fn json(&self, show_hidden: bool) -> serde_json::Value {
+        let cases: Vec<serde_json::Value> = self
+            .cases
+            .iter()
+            .map(|case| {
+                let mut entry = json!({
+                    "name": case.result.name,
+                    "passed": case.result.passed(),
+                    "points": case.points(),
+                    "weight": case.weight,
+                    "hidden": case.hidden,
+                });
+                if !case.hidden || show_hidden {
+                    entry["steps"] = json!(case.result.steps);
+                    entry["stop"] = json!(case.result.stop.map(|stop| stop.to_string()));
+                    entry["error"] = json!(case.result.error);
+                    entry["failures"] = json!(case.result.failures);
+                }
+                entry
+            })
+            .collect();
+        json!({
+            "score": self.score(),
+            "total": self.total(),
+            "cases": cases,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::hardware::vm::StopReason;
+
+    const SPEC: &str = r#"{
+        "max_instructions": 1000,
+        "cases": [
+            {
+                "name": "sums a list",
+                "weight": 2,
+                "registers": { "R1": "x4000" },
+                "memory": { "x4000": [1, 2, 3, 0] },
+                "expect": { "R0": 6, "x4003": "0" }
+            },
+            { "name": "empty list", "registers": { "R1": 16384 }, "expect": { "R0": 0 } },
+            {
+                "name": "characters",
+                "weight": 3,
+                "hidden": true,
+                "registers": { "R1": "x5000" },
+                "memory": { "x5000": { "string": "AB" } },
+                "expect": { "R0": 131 },
+                "max_instructions": 100
+            },
+            {
+                "name": "wrong",
+                "weight": 4,
+                "hidden": true,
+                "registers": { "R1": "x4000" },
+                "memory": { "x4000": -1 },
+                "output": "x",
+                "expect": { "R0": 7 }
+            }
+        ]
+    }"#;
+
+    /**
+    Sums the words from R1 on into R0, up to a zero.
+    AND R0, R0, #0 ; LOOP LDR R2, R1, #0 ; BRz DONE ; ADD R0, R0, R2 ; ADD R1, R1, #1 ; BR LOOP ; DONE HALT
+    */
+    const SUM: &[u16] = &[
+        0x3000, 0x5020, 0x6440, 0x0403, 0x1002, 0x1261, 0x0FFB, 0xF025,
+    ];
+
+    fn submission(name: &str, words: &[u16]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "rust-vm-grader-{}-{}.obj",
+            name,
+            std::process::id()
+        ));
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_be_bytes()).collect();
+        fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    fn spec() -> GradingSpec {
+        let path = std::env::temp_dir().join(format!("rust-vm-grader-{}.json", std::process::id()));
+        fs::write(&path, SPEC).unwrap();
+        let spec = GradingSpec::load(&path).unwrap();
+        fs::remove_file(path).unwrap();
+        spec
+    }
+
+    #[test]
+    fn test_submission_is_scored_per_case() {
+        let path = submission("sum", SUM);
+        let report = grade(&spec(), &path, 2, Engine::Interpreter);
+        fs::remove_file(path).unwrap();
+
+        let passed: Vec<bool> = report
+            .cases
+            .iter()
+            .map(|case| case.result.passed())
+            .collect();
+        assert_eq!(passed, [true, true, true, false]);
+        assert_eq!((report.score(), report.total()), (6.0, 10.0));
+
+        let text = report.text(false);
+        assert!(text.starts_with("Score: 6/10 (60.0%)\n\n  PASS  sums a list  2/2\n"));
+        assert!(text.ends_with("  FAIL  wrong        0/4  (hidden)\n"));
+        assert!(!text.contains("expected R0"));
+        assert!(report
+            .text(true)
+            .contains("expected R0 = x0007 (7), found xFFFF (-1)"));
+
+        let json = report.json(false);
+        assert_eq!(json["score"], 6.0);
+        assert_eq!(json["cases"][0]["steps"], 19);
+        assert!(json["cases"][3].get("failures").is_none());
+    }
+
+    #[test]
+    fn test_broken_submissions_fail_without_stopping_the_grader() {
+        // BR to itself; RES; OUT in a loop
+        let submissions = [
+            (
+                "loop",
+                vec![0x3000, 0x0FFF],
+                StopReason::InstructionLimit(1000),
+            ),
+            (
+                "fault",
+                vec![0x3000, 0xD000],
+                StopReason::Fault(crate::hardware::vm::Fault::IllegalOpcode {
+                    address: 0x3000,
+                    instruction: 0xD000,
+                }),
+            ),
+            (
+                "noise",
+                vec![0x3000, 0xF021, 0x0FFE],
+                StopReason::InstructionLimit(1000),
+            ),
+        ];
+        for (name, words, stop) in submissions {
+            let path = submission(name, &words);
+            let report = grade(&spec(), &path, 1, Engine::Compiled);
+            fs::remove_file(path).unwrap();
+
+            assert_eq!(report.score(), 0.0, "{}", name);
+            assert_eq!(report.cases[0].result.stop, Some(stop), "{}", name);
+        }
+
+        let report = grade(&spec(), Path::new("missing.obj"), 1, Engine::Interpreter);
+        assert!(report.cases[0]
+            .result
+            .error
+            .as_ref()
+            .unwrap()
+            .contains("missing.obj"));
+    }
+}
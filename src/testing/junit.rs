@@ -0,0 +1,129 @@
+use std::fmt::Write;
+
+use super::CaseResult;
+
+/**
+A JUnit XML report of a test run, one `testcase` per case. Cases that could not run are reported as errors, cases
+whose expectations failed as failures, with every failed expectation in the body.
+*/
+pub fn report(suite: &str, results: &[CaseResult]) -> String {
+    let failures = results
+        .iter()
+        .filter(|result| result.error.is_none() && !result.failures.is_empty())
+        .count();
+    let errors = results
+        .iter()
+        .filter(|result| result.error.is_some())
+        .count();
+    let time: f64 = results.iter().map(|result| result.time.as_secs_f64()).sum();
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    writeln!(
+        xml,
+        "<testsuites tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">",
+        results.len(),
+        failures,
+        errors,
+        time
+    )
+    .unwrap();
+    writeln!(
+        xml,
+        "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">",
+        escape(suite),
+        results.len(),
+        failures,
+        errors,
+        time
+    )
+    .unwrap();
+    for result in results {
+        write!(
+            xml,
+            "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+            escape(&result.name),
+            escape(suite),
+            result.time.as_secs_f64()
+        )
+        .unwrap();
+        if let Some(error) = &result.error {
+            writeln!(
+                xml,
+                ">\n      <error message=\"{}\"/>\n    </testcase>",
+                escape(error)
+            )
+            .unwrap();
+        } else if let Some(first) = result.failures.first() {
+            let message = first.lines().next().unwrap_or_default();
+            writeln!(
+                xml,
+                ">\n      <failure message=\"{}\">{}</failure>\n    </testcase>",
+                escape(message),
+                escape(&result.failures.join("\n"))
+            )
+            .unwrap();
+        } else {
+            writeln!(xml, "/>").unwrap();
+        }
+    }
+    xml.push_str("  </testsuite>\n</testsuites>\n");
+    xml
+}
+
+/**
+Escapes text for attributes and element content. Control characters other than tabs and newlines are not allowed
+in XML 1.0 at all, so they are replaced.
+*/
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' => escaped.push(c),
+            c if c.is_control() => escaped.push(char::REPLACEMENT_CHARACTER),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_report_lists_passes_failures_and_errors() {
+        let result = |name: &str, failures: Vec<&str>, error: Option<&str>| CaseResult {
+            name: name.to_string(),
+            time: Duration::from_millis(250),
+            steps: 10,
+            stop: None,
+            failures: failures.into_iter().map(String::from).collect(),
+            error: error.map(String::from),
+        };
+        let xml = report(
+            "cases",
+            &[
+                result("ok", vec![], None),
+                result("wrong", vec!["output differs\n- 1\n+ <2>"], None),
+                result("broken", vec![], Some("cannot read \"prog.obj\"")),
+            ],
+        );
+
+        assert!(xml.contains(
+            "<testsuite name=\"cases\" tests=\"3\" failures=\"1\" errors=\"1\" time=\"0.750\">"
+        ));
+        assert!(xml.contains("<testcase name=\"ok\" classname=\"cases\" time=\"0.250\"/>\n"));
+        assert!(xml.contains(
+            "<failure message=\"output differs\">output differs\n- 1\n+ &lt;2&gt;</failure>"
+        ));
+        assert!(xml.contains("<error message=\"cannot read &quot;prog.obj&quot;\"/>"));
+    }
+}
@@ -0,0 +1,493 @@
+/*!
+Headless tests of LC-3 programs. A case is a directory holding the program and up to four files:
+
+- `in.txt`: the keyboard input. Without it the program gets none.
+- `setup.txt`: the initial registers and memory, applied after the programs are loaded, see `Setup`.
+- `out.txt`: the expected output, compared byte for byte.
+- `expect.txt`: further expectations, one per line, `#` starts a comment:
+
+```text
+program os.obj        # object files to load in order, prog.obj when there is no program line
+halt within 5000      # the program must HALT within this many instructions
+R0 = 5                # a register (R0 to R7, PC) or memory cell, by symbol or address
+RESULT = -1           # values are symbols, addresses (x3000, 0x3000), decimals or characters, e.g. 'A'
+```
+
+Every case runs in its own machine, so cases can run in parallel.
+*/
+
+pub mod diff;
+pub mod grader;
+pub mod junit;
+
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::hardware::console::{Display, Keyboard};
+use crate::hardware::vm::engine::Engine;
+use crate::hardware::vm::limits::Limits;
+use crate::hardware::vm::setup::{register_index, Setup, Value};
+use crate::hardware::vm::symbols::SymbolTable;
+use crate::hardware::vm::{StopReason, VirtualMachine};
+
+/**
+The instruction budget of a case without a `halt within` line.
+*/
+pub const DEFAULT_INSTRUCTIONS: u64 = 10_000_000;
+
+/**
+Output beyond this is dropped and stops the program, so a runaway loop cannot fill the memory of the runner.
+*/
+pub const MAX_OUTPUT: u64 = 1 << 20;
+
+/**
+A wall-clock backstop for each case, on top of the instruction budget.
+*/
+pub const TIME_LIMIT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestCase {
+    pub name: String,
+    pub programs: Vec<PathBuf>,
+    pub input: Vec<u8>,
+    pub setup: Setup,
+    pub expected_output: Option<Vec<u8>>,
+    pub max_instructions: u64,
+    pub expectations: Vec<Expectation>,
+}
+
+/**
+A register or memory cell that must hold a value when the program stops. Both sides are resolved against the
+program's symbols when the case runs.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expectation {
+    /// Where the expectation was written, e.g. `expect.txt line 3`, for error messages.
+    pub source: String,
+    pub location: String,
+    pub value: String,
+}
+
+/**
+How a case went. A case passes when it has neither an error nor failures.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaseResult {
+    pub name: String,
+    pub time: Duration,
+    pub steps: u64,
+    pub stop: Option<StopReason>,
+    /// Every expectation that did not hold, the first line a summary.
+    pub failures: Vec<String>,
+    /// Why the case could not run, e.g. a missing program.
+    pub error: Option<String>,
+}
+
+impl CaseResult {
+    pub fn passed(&self) -> bool {
+        self.error.is_none() && self.failures.is_empty()
+    }
+}
+
+impl TestCase {
+    /**
+    Reads the case in `directory`. Programs are resolved relative to it.
+    */
+    pub fn load(directory: &Path) -> Result<TestCase, String> {
+        let mut case = TestCase {
+            name: case_name(directory),
+            programs: Vec::new(),
+            input: read_optional(&directory.join("in.txt"))?.unwrap_or_default(),
+            setup: Setup::default(),
+            expected_output: read_optional(&directory.join("out.txt"))?,
+            max_instructions: DEFAULT_INSTRUCTIONS,
+            expectations: Vec::new(),
+        };
+
+        if let Some(bytes) = read_optional(&directory.join("setup.txt"))? {
+            case.setup = Setup::parse(&String::from_utf8_lossy(&bytes))
+                .map_err(|error| format!("setup.txt {}", error))?;
+        }
+        if let Some(bytes) = read_optional(&directory.join("expect.txt"))? {
+            let text = String::from_utf8_lossy(&bytes);
+            for (index, line) in text.lines().enumerate() {
+                case.parse_line(directory, index + 1, line)
+                    .map_err(|error| format!("expect.txt line {}: {}", index + 1, error))?;
+            }
+        }
+        if case.programs.is_empty() {
+            case.programs.push(directory.join("prog.obj"));
+        }
+        Ok(case)
+    }
+
+    fn parse_line(&mut self, directory: &Path, line: usize, text: &str) -> Result<(), String> {
+        let text = text.split('#').next().unwrap_or_default().trim();
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        match fields[..] {
+            [] => {}
+            ["program", program] => self.programs.push(directory.join(program)),
+            ["halt", "within", count] => {
+                self.max_instructions = count
+                    .parse()
+                    .map_err(|_| format!("invalid instruction count '{}'", count))?;
+            }
+            [location, "=", value] => self.expectations.push(Expectation {
+                source: format!("expect.txt line {}", line),
+                location: location.to_string(),
+                value: value.to_string(),
+            }),
+            _ => return Err(format!("cannot parse '{}'", text)),
+        }
+        Ok(())
+    }
+
+    /**
+    Runs the case in a fresh machine and checks every expectation.
+    */
+    pub fn run(&self, engine: Engine) -> CaseResult {
+        self.run_with(engine, |_| Ok(()))
+    }
+
+    /**
+    Like `run`, with `prepare` setting up the machine after the programs are loaded and the setup is applied. An
+    error from `prepare`, or a panic anywhere in the run, is reported as the case's error.
+    */
+    pub fn run_with<F>(&self, engine: Engine, prepare: F) -> CaseResult
+    where
+        F: FnOnce(&mut VirtualMachine) -> Result<(), String>,
+    {
+        let started = Instant::now();
+        let run = panic::catch_unwind(AssertUnwindSafe(|| self.execute(engine, prepare)));
+        let mut result = run.unwrap_or_else(|panic| {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            self.error(format!("the VM panicked: {}", message))
+        });
+        result.time = started.elapsed();
+        result
+    }
+
+    fn error(&self, error: String) -> CaseResult {
+        CaseResult {
+            name: self.name.clone(),
+            time: Duration::ZERO,
+            steps: 0,
+            stop: None,
+            failures: Vec::new(),
+            error: Some(error),
+        }
+    }
+
+    fn execute<F>(&self, engine: Engine, prepare: F) -> CaseResult
+    where
+        F: FnOnce(&mut VirtualMachine) -> Result<(), String>,
+    {
+        let mut result = CaseResult {
+            name: self.name.clone(),
+            time: Duration::ZERO,
+            steps: 0,
+            stop: None,
+            failures: Vec::new(),
+            error: None,
+        };
+
+        let mut vm = VirtualMachine::create();
+        vm.set_engine(engine);
+        let output = Captured::default();
+        vm.memory.display = Display::to_writer(Box::new(output.clone()));
+        vm.memory.keyboard = Keyboard::from_bytes(&self.input);
+        for program in &self.programs {
+            if let Err(error) = vm.load_program(&program.to_string_lossy()) {
+                return self.error(format!("failed to load {}: {}", program.display(), error));
+            }
+        }
+        if let Err(error) = self.setup.apply(&mut vm) {
+            return self.error(format!("setup.txt {}", error));
+        }
+        if let Err(error) = prepare(&mut vm) {
+            return self.error(error);
+        }
+        vm.set_limits(Limits {
+            instructions: Some(self.max_instructions),
+            wall_time: Some(TIME_LIMIT),
+            output_bytes: Some(MAX_OUTPUT),
+            input_reads: None,
+        });
+
+        let stop = vm.execute_program();
+        result.stop = Some(stop);
+        result.steps = vm.steps;
+        if stop != StopReason::Halted {
+            result.failures.push(format!(
+                "expected HALT within {} instructions, stopped after {}: {}",
+                self.max_instructions, vm.steps, stop
+            ));
+        }
+
+        let output = output.take();
+        if let Some(expected) = &self.expected_output {
+            if output != *expected {
+                result.failures.push(format!(
+                    "output differs from the expected output\n{}",
+                    diff::diff(expected, &output)
+                ));
+            }
+        }
+        for expectation in &self.expectations {
+            if let Err(failure) = expectation.check(&vm) {
+                result.failures.push(failure);
+            }
+        }
+        result
+    }
+}
+
+impl Expectation {
+    fn check(&self, vm: &VirtualMachine) -> Result<(), String> {
+        let unknown = |text: &str| format!("{}: unknown value '{}'", self.source, text);
+        let expected =
+            resolve_value(&vm.symbols, &self.value).ok_or_else(|| unknown(&self.value))?;
+        let actual = match register_index(&self.location) {
+            Some(register) => vm.read_register(register),
+            None => {
+                let address = vm
+                    .symbols
+                    .resolve(&self.location)
+                    .ok_or_else(|| unknown(&self.location))?;
+                vm.peek_memory(address)
+            }
+        };
+
+        if actual == expected {
+            return Ok(());
+        }
+        Err(format!(
+            "expected {} = x{:04X} ({}), found x{:04X} ({})",
+            self.location, expected, expected as i16, actual, actual as i16
+        ))
+    }
+}
+
+/**
+A symbol, an address, a decimal number, negative ones as their two's complement, or a character, see `Value`.
+*/
+pub fn resolve_value(symbols: &SymbolTable, text: &str) -> Option<u16> {
+    match Value::parse(text)? {
+        Value::Word(word) => Some(word),
+        Value::Symbol(name) => symbols.address_of(&name),
+        Value::String(_) => None,
+    }
+}
+
+fn case_name(directory: &Path) -> String {
+    match directory.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => directory.display().to_string(),
+    }
+}
+
+fn read_optional(path: &Path) -> Result<Option<Vec<u8>>, String> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(format!("failed to read {}: {}", path.display(), error)),
+    }
+}
+
+/**
+The case directories under `directory`, sorted by name. A directory without subdirectories is a single case.
+*/
+pub fn discover(directory: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut cases = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            cases.push(path);
+        }
+    }
+    cases.sort();
+    if cases.is_empty() {
+        cases.push(directory.to_path_buf());
+    }
+    Ok(cases)
+}
+
+/**
+Loads and runs every case on `jobs` threads. Results come back in the order of `directories`.
+*/
+pub fn run_all(directories: &[PathBuf], jobs: usize, engine: Engine) -> Vec<CaseResult> {
+    parallel(directories, jobs, |directory| {
+        match TestCase::load(directory) {
+            Ok(case) => case.run(engine),
+            Err(error) => CaseResult {
+                name: case_name(directory),
+                time: Duration::ZERO,
+                steps: 0,
+                stop: None,
+                failures: Vec::new(),
+                error: Some(error),
+            },
+        }
+    })
+}
+
+/**
+Applies `run` to every item on `jobs` threads, returning the results in the order of `items`.
+*/
+pub fn parallel<T, R, F>(items: &[T], jobs: usize, run: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new((0..items.len()).map(|_| None).collect::<Vec<Option<R>>>());
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, items.len().max(1)) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(item) = items.get(index) else {
+                    break;
+                };
+                let result = run(item);
+                results.lock().unwrap()[index] = Some(result);
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|result| result.expect("every item ran"))
+        .collect()
+}
+
+/**
+Collects what the program writes, to compare it once the program stopped.
+*/
+#[derive(Clone, Default)]
+pub struct Captured(Rc<RefCell<Vec<u8>>>);
+
+impl Captured {
+    pub fn take(&self) -> Vec<u8> {
+        self.0.take()
+    }
+}
+
+impl Write for Captured {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /**
+    LEA R0, MSG ; PUTS ; GETC ; ADD R1, R0, #1 ; AND R0, R0, #0 ; ADD R0, R0, #5 ; ST R0, RESULT ; HALT ;
+    RESULT .FILL 0 ; MSG .STRINGZ "hi\n"
+    */
+    const PROGRAM: [u16; 14] = [
+        0x3000, 0xE008, 0xF022, 0xF020, 0x1221, 0x5020, 0x1025, 0x3001, 0xF025, 0x0000, 0x0068,
+        0x0069, 0x000A, 0x0000,
+    ];
+
+    fn case(directory: &Path, files: &[(&str, &[u8])]) -> PathBuf {
+        fs::create_dir_all(directory).unwrap();
+        let program: Vec<u8> = PROGRAM.iter().flat_map(|word| word.to_be_bytes()).collect();
+        fs::write(directory.join("prog.obj"), program).unwrap();
+        for (name, contents) in files {
+            fs::write(directory.join(name), contents).unwrap();
+        }
+        directory.to_path_buf()
+    }
+
+    #[test]
+    fn test_cases_pass_and_fail_on_their_expectations() {
+        let root = std::env::temp_dir().join(format!("rust-vm-test-cases-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        case(
+            &root.join("a-passes"),
+            &[
+                ("in.txt", b"A"),
+                ("out.txt", b"hi\n"),
+                (
+                    "expect.txt",
+                    b"halt within 8\nR0 = 5 # a comment\nR1 = 66\nx3008 = 5\n",
+                ),
+            ],
+        );
+        case(
+            &root.join("b-fails"),
+            &[
+                ("in.txt", b"A"),
+                ("out.txt", b"hello\n"),
+                ("expect.txt", b"halt within 7\nR0 = -1\n"),
+            ],
+        );
+        fs::create_dir_all(root.join("c-missing")).unwrap();
+        case(&root.join("d-invalid"), &[("expect.txt", b"halt soon\n")]);
+        case(
+            &root.join("e-setup"),
+            &[
+                ("setup.txt", b"x3009 = \"yo\\n\"\nR1 = 1\ninput \"B\"\n"),
+                ("out.txt", b"yo\n"),
+                ("expect.txt", b"R1 = 'C'\n"),
+            ],
+        );
+        case(
+            &root.join("f-bad-setup"),
+            &[("setup.txt", b"R1 = NOWHERE\n")],
+        );
+
+        let directories = discover(&root).unwrap();
+        let results = run_all(&directories, 3, Engine::Interpreter);
+        let names: Vec<&str> = results.iter().map(|result| result.name.as_str()).collect();
+        assert_eq!(names[..2], ["a-passes", "b-fails"]);
+
+        assert!(results[0].passed(), "{:?}", results[0]);
+        assert_eq!(results[0].steps, 8);
+
+        let failures = &results[1].failures;
+        assert_eq!(results[1].stop, Some(StopReason::InstructionLimit(7)));
+        assert_eq!(failures.len(), 3, "{:?}", failures);
+        assert!(failures[0].starts_with("expected HALT within 7 instructions, stopped after 7"));
+        assert!(failures[1].ends_with("- hello\n+ hi\n"));
+        assert_eq!(failures[2], "expected R0 = xFFFF (-1), found x0005 (5)");
+
+        assert!(results[2]
+            .error
+            .as_ref()
+            .unwrap()
+            .contains("failed to load"));
+        assert_eq!(
+            results[3].error.as_deref(),
+            Some("expect.txt line 1: cannot parse 'halt soon'")
+        );
+        assert!(results[4].passed(), "{:?}", results[4]);
+        assert_eq!(
+            results[5].error.as_deref(),
+            Some("setup.txt line 1: unknown symbol 'NOWHERE'")
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+}